@@ -1,5 +1,24 @@
 use crate::sendmany::CAmount;
 
+pub const COIN: CAmount = 100000000;
+
+/** No amount larger than this (in satoshi) is valid.
+ *
+ * Note that this constant is *not* the total money supply, which in Bitcoin
+ * currently happens to be less than 21,000,000 BTC for various reasons, but
+ * rather a sanity check. As this sanity check is used by consensus-critical
+ * validation code, the exact value of the MAX_MONEY constant is consensus
+ * critical; in unusual circumstances like a(nother) overflow bug that allowed
+ * for the creation of coins out of thin air modification could lead to a fork.
+ * */
+pub const MAX_MONEY: CAmount = 21000000 * COIN;
+
+pub fn money_range(n_value: CAmount) -> bool {
+    n_value >= 0 && n_value <= MAX_MONEY
+}
+
+/** Fee rate in satoshis per kilobyte: CAmount / kB */
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord)]
 pub struct FeeRate {
     n_satoshis_per_k: CAmount,
 }
@@ -10,4 +29,52 @@ impl FeeRate {
             n_satoshis_per_k: 0,
         }
     }
+
+    pub fn from_per_k(n_satoshis_per_k: CAmount) -> Self {
+        FeeRate { n_satoshis_per_k }
+    }
+
+    //CFeeRate::CFeeRate(const CAmount& nFeePaid, size_t nSize)
+    pub fn from_fee(n_fee_paid: CAmount, n_size: usize) -> Self {
+        let n_satoshis_per_k = if n_size > 0 {
+            n_fee_paid * 1000 / n_size as CAmount
+        } else {
+            0
+        };
+        FeeRate { n_satoshis_per_k }
+    }
+
+    //CAmount CFeeRate::GetFee(size_t nSize) const
+    //The fee for n_size bytes, at least one satoshi for a nonzero rate and size.
+    pub fn get_fee(&self, n_size: usize) -> CAmount {
+        let n_fee = self.n_satoshis_per_k * n_size as CAmount / 1000;
+        if n_fee == 0 && n_size != 0 {
+            self.n_satoshis_per_k.signum()
+        } else {
+            n_fee
+        }
+    }
+
+    pub fn get_fee_per_k(&self) -> CAmount {
+        self.get_fee(1000)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fee_rate_is_per_kilobyte() {
+        let rate = FeeRate::from_fee(500, 250);
+        assert_eq!(rate.get_fee_per_k(), 2000);
+        assert_eq!(rate.get_fee(250), 500);
+        assert_eq!(rate.get_fee(1500), 3000);
+        assert_eq!(FeeRate::from_per_k(1000).get_fee(0), 0);
+        assert_eq!(FeeRate::from_per_k(1000).get_fee(1), 1);
+        assert_eq!(FeeRate::from_per_k(1).get_fee(999), 1);
+        assert_eq!(FeeRate::new().get_fee(250), 0);
+        assert_eq!(FeeRate::from_fee(500, 0), FeeRate::new());
+        assert!(FeeRate::from_fee(100, 100) > FeeRate::from_fee(100, 200));
+    }
 }
@@ -1,37 +1,244 @@
+use crate::incremental_tree::merkle_tree::Hashable;
+use crate::incremental_tree::serialize::Vector;
+use crate::incremental_tree::tree::SaplingMerkleTree;
 use crate::key::key_management::FrHash;
 use crate::transaction::{Transaction, TxOut};
-use std::collections::hash_map::HashMap;
+use blake2_rfc::blake2b::Blake2b;
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use std::cell::{Cell, RefCell};
+use std::collections::HashMap;
+use std::io::{self, Read, Write};
 
 //Things that need to be intergated
 use ethereum_types::U256;
 
+pub const CURRENT_BLOCK_VERSION: i32 = 4;
+
+const BLOCK_HASH_PERSONALIZATION: &[u8; 16] = b"ice_BlockHash___";
+const MERKLE_PERSONALIZATION: &[u8; 16] = b"ice_MerkleHash__";
+
+fn read_u256<R: Read>(mut reader: R) -> io::Result<U256> {
+    let mut bytes = [0u8; 32];
+    reader.read_exact(&mut bytes)?;
+    Ok(U256::from_little_endian(&bytes))
+}
+
+fn write_u256<W: Write>(mut writer: W, value: &U256) -> io::Result<()> {
+    let mut bytes = [0u8; 32];
+    value.to_little_endian(&mut bytes);
+    writer.write_all(&bytes)
+}
+
+#[derive(Clone)]
+pub struct BlockHeader {
+    pub n_version: i32,
+    pub hash_prev_block: U256,
+    pub hash_merkle_root: U256,
+    pub hash_final_sapling_root: FrHash,
+    pub n_time: u32,
+    pub n_bits: u32,
+    pub n_nonce: U256,
+}
+
+impl BlockHeader {
+    pub fn new() -> Self {
+        BlockHeader {
+            n_version: CURRENT_BLOCK_VERSION,
+            hash_prev_block: U256::zero(),
+            hash_merkle_root: U256::zero(),
+            hash_final_sapling_root: SaplingMerkleTree::empty_root(),
+            n_time: 0,
+            n_bits: 0,
+            n_nonce: U256::zero(),
+        }
+    }
+
+    // The block hash is a personalized BLAKE2b-256 digest of the serialized header.
+    pub fn get_hash(&self) -> U256 {
+        let mut data = Vec::new();
+        self.write(&mut data)
+            .expect("header should serialize into a Vec");
+
+        let mut h = Blake2b::with_params(32, &[], &[], BLOCK_HASH_PERSONALIZATION);
+        h.update(&data);
+        U256::from(h.finalize().as_bytes())
+    }
+
+    pub fn get_block_time(&self) -> i64 {
+        self.n_time as i64
+    }
+
+    pub fn read<R: Read>(mut reader: R) -> io::Result<Self> {
+        let n_version = reader.read_i32::<LittleEndian>()?;
+        let hash_prev_block = read_u256(&mut reader)?;
+        let hash_merkle_root = read_u256(&mut reader)?;
+        let hash_final_sapling_root = FrHash::read(&mut reader)?;
+        let n_time = reader.read_u32::<LittleEndian>()?;
+        let n_bits = reader.read_u32::<LittleEndian>()?;
+        let n_nonce = read_u256(&mut reader)?;
+        Ok(BlockHeader {
+            n_version,
+            hash_prev_block,
+            hash_merkle_root,
+            hash_final_sapling_root,
+            n_time,
+            n_bits,
+            n_nonce,
+        })
+    }
+
+    pub fn write<W: Write>(&self, mut writer: W) -> io::Result<()> {
+        writer.write_i32::<LittleEndian>(self.n_version)?;
+        write_u256(&mut writer, &self.hash_prev_block)?;
+        write_u256(&mut writer, &self.hash_merkle_root)?;
+        self.hash_final_sapling_root.write(&mut writer)?;
+        writer.write_u32::<LittleEndian>(self.n_time)?;
+        writer.write_u32::<LittleEndian>(self.n_bits)?;
+        write_u256(&mut writer, &self.n_nonce)
+    }
+}
+
 pub struct Block {
+    pub header: BlockHeader,
     pub vtx: Vec<Transaction>,
 }
 
-pub struct DiskBlockPos {}
+fn merkle_node(left: &[u8; 32], right: &[u8; 32]) -> [u8; 32] {
+    let mut h = Blake2b::with_params(32, &[], &[], MERKLE_PERSONALIZATION);
+    h.update(left);
+    h.update(right);
+    let mut node = [0u8; 32];
+    node.copy_from_slice(h.finalize().as_bytes());
+    node
+}
+
+impl Block {
+    pub fn get_hash(&self) -> U256 {
+        self.header.get_hash()
+    }
+
+    //uint256 BlockMerkleRoot(const CBlock& block, bool* mutated)
+    //Levels with an odd number of nodes repeat their last node. The second value is true if
+    //a level has two identical nodes side by side, as then a different transaction list
+    //with the same root exists (CVE-2012-2459).
+    pub fn compute_merkle_root(&self) -> (U256, bool) {
+        let mut level = self
+            .vtx
+            .iter()
+            .map(|tx| {
+                let mut leaf = [0u8; 32];
+                tx.hash().write(&mut leaf[..]).expect("a txid is 32 bytes");
+                leaf
+            })
+            .collect::<Vec<_>>();
+        if level.is_empty() {
+            return (U256::zero(), false);
+        }
+
+        let mut mutated = false;
+        while level.len() > 1 {
+            for pair in level.chunks(2) {
+                if pair.len() == 2 && pair[0] == pair[1] {
+                    mutated = true;
+                }
+            }
+            if level.len() % 2 == 1 {
+                let last = *level.last().unwrap();
+                level.push(last);
+            }
+            level = level
+                .chunks(2)
+                .map(|pair| merkle_node(&pair[0], &pair[1]))
+                .collect();
+        }
+        (U256::from(&level[0][..]), mutated)
+    }
+
+    pub fn get_serialize_size(&self) -> usize {
+        let mut data = Vec::new();
+        self.write(&mut data)
+            .expect("block should serialize into a Vec");
+        data.len()
+    }
+
+    pub fn read<R: Read>(mut reader: R) -> io::Result<Self> {
+        let header = BlockHeader::read(&mut reader)?;
+        let vtx = Vector::read(&mut reader, |r| Transaction::read(r))?;
+        Ok(Block { header, vtx })
+    }
+
+    pub fn write<W: Write>(&self, mut writer: W) -> io::Result<()> {
+        self.header.write(&mut writer)?;
+        Vector::write(&mut writer, &self.vtx, |w, tx| tx.write(w))
+    }
+}
+
+//Location of a record in the blk/rev files, see block_store
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct DiskBlockPos {
+    pub file: i32,
+    pub offset: u32,
+}
+
+impl DiskBlockPos {
+    pub fn new(file: i32, offset: u32) -> Self {
+        DiskBlockPos { file, offset }
+    }
+
+    pub fn null() -> Self {
+        DiskBlockPos {
+            file: -1,
+            offset: 0,
+        }
+    }
+
+    pub fn is_null(&self) -> bool {
+        self.file == -1
+    }
+}
 
 pub struct TxInUndo {
     pub txout: TxOut,      // the txout data before being spent
+    pub f_last: bool,      // whether the outpoint was the last unspent of its transaction
     pub f_coin_base: bool, // if the outpoint was the last unspent: whether it belonged to a coinbase
-    pub n_height: i32,
+    pub n_height: i32,     // if the outpoint was the last unspent: its height
 }
 
 impl TxInUndo {
     pub fn new(txout_in: TxOut) -> Self {
         TxInUndo {
             txout: txout_in,
+            f_last: false,
             f_coin_base: false,
             n_height: 0,
         }
     }
 
-    pub fn set_n_height(&mut self, n_height: i32) {
+    // The genesis coinbase has height 0, so a height can't tell the last output apart.
+    pub fn set_last(&mut self, f_coin_base: bool, n_height: i32) {
+        self.f_last = true;
+        self.f_coin_base = f_coin_base;
         self.n_height = n_height;
     }
 
-    pub fn set_f_coin_base(&mut self, f_coin_base: bool) {
-        self.f_coin_base = f_coin_base;
+    pub fn read<R: Read>(mut reader: R) -> io::Result<Self> {
+        let txout = TxOut::read(&mut reader)?;
+        let f_last = reader.read_u8()? != 0;
+        let f_coin_base = reader.read_u8()? != 0;
+        let n_height = reader.read_i32::<LittleEndian>()?;
+        Ok(TxInUndo {
+            txout,
+            f_last,
+            f_coin_base,
+            n_height,
+        })
+    }
+
+    pub fn write<W: Write>(&self, mut writer: W) -> io::Result<()> {
+        self.txout.write(&mut writer)?;
+        writer.write_u8(self.f_last as u8)?;
+        writer.write_u8(self.f_coin_base as u8)?;
+        writer.write_i32::<LittleEndian>(self.n_height)
     }
 }
 
@@ -45,6 +252,15 @@ impl TxUndo {
             vprevout: Vec::new(),
         }
     }
+
+    pub fn read<R: Read>(mut reader: R) -> io::Result<Self> {
+        let vprevout = Vector::read(&mut reader, |r| TxInUndo::read(r))?;
+        Ok(TxUndo { vprevout })
+    }
+
+    pub fn write<W: Write>(&self, mut writer: W) -> io::Result<()> {
+        Vector::write(&mut writer, &self.vprevout, |w, undo| undo.write(w))
+    }
 }
 
 pub struct BlockUndo {
@@ -57,47 +273,212 @@ impl BlockUndo {
             vtxundo: Vec::new(),
         }
     }
-}
 
-pub struct BlockUndoView {
-    //BlockUndo save
-    pub block_undos: HashMap<U256, BlockUndo>,
-}
-
-impl BlockUndoView {
-    pub fn new() -> Self {
-        BlockUndoView {
-            block_undos: HashMap::new(),
-        }
+    pub fn read<R: Read>(mut reader: R) -> io::Result<Self> {
+        let vtxundo = Vector::read(&mut reader, |r| TxUndo::read(r))?;
+        Ok(BlockUndo { vtxundo })
     }
 
-    pub fn save_blockundo(&mut self, block_hash: U256, blockundo: BlockUndo) {
-        self.block_undos.insert(block_hash, blockundo);
+    pub fn write<W: Write>(&self, mut writer: W) -> io::Result<()> {
+        Vector::write(&mut writer, &self.vtxundo, |w, txundo| txundo.write(w))
     }
 }
 
+// Parsed, version ok, hash satisfies claimed PoW, timestamp not in future
+pub const BLOCK_VALID_HEADER: u32 = 1;
+// All parent headers found, difficulty matches, timestamp > median previous.
+// Implies all parents are also at least TREE.
+pub const BLOCK_VALID_TREE: u32 = 2;
+// Transactions valid, no duplicate txids, size, merkle root.
+// Implies all parents are at least TREE but not necessarily TRANSACTIONS.
+pub const BLOCK_VALID_TRANSACTIONS: u32 = 3;
+// Outputs do not overspend inputs, no double spends.
+// Implies all parents are also at least CHAIN.
+pub const BLOCK_VALID_CHAIN: u32 = 4;
+// Scripts & signatures ok. Implies all parents are also at least SCRIPTS.
+pub const BLOCK_VALID_SCRIPTS: u32 = 5;
+// All validity bits.
+pub const BLOCK_VALID_MASK: u32 = BLOCK_VALID_HEADER
+    | BLOCK_VALID_TREE
+    | BLOCK_VALID_TRANSACTIONS
+    | BLOCK_VALID_CHAIN
+    | BLOCK_VALID_SCRIPTS;
+
+// Full block available in blk*.dat
+pub const BLOCK_HAVE_DATA: u32 = 8;
+// Undo data available in rev*.dat
+pub const BLOCK_HAVE_UNDO: u32 = 16;
+// Stage after last reached validness failed
+pub const BLOCK_FAILED_VALID: u32 = 32;
+// Descends from failed block
+pub const BLOCK_FAILED_CHILD: u32 = 64;
+pub const BLOCK_FAILED_MASK: u32 = BLOCK_FAILED_VALID | BLOCK_FAILED_CHILD;
+
+//typedef boost::unordered_map<uint256, CBlockIndex*, BlockHasher> BlockMap;
+pub type BlockMap = HashMap<U256, BlockIndex>;
+
+// Number of previous blocks whose timestamps make up the median time past
+pub const MEDIAN_TIME_SPAN: usize = 11;
+
 #[derive(Clone)]
 pub struct BlockIndex {
     phash_block: U256,
 
     pub nHeight: i32,
 
-    pub pprev: Option<Box<BlockIndex>>,
+    //The parent is looked up by its hash in the BlockMap, see get_pprev
+    pub hash_prev_block: U256,
     pub hash_final_sapling_root: FrHash,
+
+    //Total amount of work in the chain up to and including this block
+    pub n_chain_work: U256,
+
+    //Which # file this block is stored in (blk?????.dat)
+    pub n_file: i32,
+    //Byte offset within blk?????.dat where this block's data is stored
+    pub n_data_pos: u32,
+    //Byte offset within rev?????.dat where this block's undo data is stored
+    pub n_undo_pos: u32,
+    pub n_status: u32,
+
+    //block header
+    pub n_version: i32,
+    pub hash_merkle_root: U256,
+    pub n_time: u32,
+    pub n_bits: u32,
+    pub n_nonce: U256,
 }
 
 impl BlockIndex {
+    pub fn new(phash_block: U256, n_height: i32) -> Self {
+        BlockIndex {
+            phash_block,
+            nHeight: n_height,
+            hash_prev_block: U256::zero(),
+            hash_final_sapling_root: SaplingMerkleTree::empty_root(),
+            n_chain_work: U256::zero(),
+            n_file: 0,
+            n_data_pos: 0,
+            n_undo_pos: 0,
+            n_status: 0,
+            n_version: 0,
+            hash_merkle_root: U256::zero(),
+            n_time: 0,
+            n_bits: 0,
+            n_nonce: U256::zero(),
+        }
+    }
+
+    //The caller sets the height and chain work, see main_impl::add_to_block_index
+    pub fn from_header(header: &BlockHeader) -> Self {
+        let mut pindex = BlockIndex::new(header.get_hash(), 0);
+        pindex.hash_prev_block = header.hash_prev_block;
+        pindex.hash_final_sapling_root = header.hash_final_sapling_root;
+        pindex.n_version = header.n_version;
+        pindex.hash_merkle_root = header.hash_merkle_root;
+        pindex.n_time = header.n_time;
+        pindex.n_bits = header.n_bits;
+        pindex.n_nonce = header.n_nonce;
+        pindex
+    }
+
+    pub fn get_block_header(&self) -> BlockHeader {
+        BlockHeader {
+            n_version: self.n_version,
+            hash_prev_block: self.hash_prev_block,
+            hash_merkle_root: self.hash_merkle_root,
+            hash_final_sapling_root: self.hash_final_sapling_root,
+            n_time: self.n_time,
+            n_bits: self.n_bits,
+            n_nonce: self.n_nonce,
+        }
+    }
+
     pub fn get_block_time(&self) -> i64 {
-        0
+        self.n_time as i64
+    }
+
+    pub fn get_median_time_past(&self, map_block_index: &BlockMap) -> i64 {
+        let mut v_median = Vec::with_capacity(MEDIAN_TIME_SPAN);
+        let mut pindex = Some(self);
+        while let Some(p) = pindex {
+            if v_median.len() == MEDIAN_TIME_SPAN {
+                break;
+            }
+            v_median.push(p.get_block_time());
+            pindex = p.get_pprev(map_block_index);
+        }
+        median(v_median)
     }
-    pub fn get_pprev(self) -> Option<BlockIndex> {
-        self.pprev.map(|b| *b)
+
+    // Check whether this block index entry is valid up to the passed validity level.
+    pub fn is_valid(&self, n_up_to: u32) -> bool {
+        assert!(n_up_to & !BLOCK_VALID_MASK == 0); // Only validity flags allowed.
+        if self.is_failed() {
+            return false;
+        }
+        (self.n_status & BLOCK_VALID_MASK) >= n_up_to
     }
+
+    // Raise the validity level of this block index entry.
+    // Returns true if the validity was changed.
+    pub fn raise_validity(&mut self, n_up_to: u32) -> bool {
+        assert!(n_up_to & !BLOCK_VALID_MASK == 0); // Only validity flags allowed.
+        if self.is_failed() {
+            return false;
+        }
+        if (self.n_status & BLOCK_VALID_MASK) < n_up_to {
+            self.n_status = (self.n_status & !BLOCK_VALID_MASK) | n_up_to;
+            return true;
+        }
+        false
+    }
+
+    // The genesis block has no parent.
+    pub fn get_pprev<'a>(&self, map_block_index: &'a BlockMap) -> Option<&'a BlockIndex> {
+        if self.nHeight == 0 {
+            return None;
+        }
+        map_block_index.get(&self.hash_prev_block)
+    }
+
     pub fn get_block_hash(&self) -> U256 {
         self.phash_block
     }
+
+    //CBlockIndex* GetAncestor(int height)
+    pub fn get_ancestor<'a>(
+        &'a self,
+        map_block_index: &'a BlockMap,
+        height: i32,
+    ) -> Option<&'a BlockIndex> {
+        if height > self.nHeight || height < 0 {
+            return None;
+        }
+        let mut pindex = self;
+        while pindex.nHeight > height {
+            pindex = pindex.get_pprev(map_block_index)?;
+        }
+        Some(pindex)
+    }
+
+    pub fn is_failed(&self) -> bool {
+        self.n_status & BLOCK_FAILED_MASK != 0
+    }
+    pub fn get_block_pos(&self) -> DiskBlockPos {
+        if self.n_status & BLOCK_HAVE_DATA != 0 {
+            DiskBlockPos::new(self.n_file, self.n_data_pos)
+        } else {
+            DiskBlockPos::null()
+        }
+    }
+
     pub fn get_undo_pos(&self) -> DiskBlockPos {
-        unimplemented!()
+        if self.n_status & BLOCK_HAVE_UNDO != 0 {
+            DiskBlockPos::new(self.n_file, self.n_undo_pos)
+        } else {
+            DiskBlockPos::null()
+        }
     }
 }
 
@@ -116,9 +497,208 @@ impl Chain {
         self.v_chain.last()
     }
 
-    pub fn next(&self, pindex: BlockIndex) -> Option<BlockIndex> {
+    // Return the maximal height in the chain. Is equal to chain.Tip() ? chain.Tip()->nHeight : -1.
+    pub fn height(&self) -> i32 {
+        self.v_chain.len() as i32 - 1
+    }
+
+    // The median time past of the tip, whose ancestors are the blocks before it in the chain.
+    pub fn get_median_time_past(&self) -> Option<i64> {
+        if self.v_chain.is_empty() {
+            return None;
+        }
+        let v_median = self
+            .v_chain
+            .iter()
+            .rev()
+            .take(MEDIAN_TIME_SPAN)
+            .map(|pindex| pindex.get_block_time())
+            .collect();
+        Some(median(v_median))
+    }
+
+    pub fn get(&self, n_height: i32) -> Option<&BlockIndex> {
+        if n_height < 0 {
+            return None;
+        }
+        self.v_chain.get(n_height as usize)
+    }
+
+    // Efficiently check whether a block is present in this chain.
+    pub fn contains(&self, pindex: &BlockIndex) -> bool {
+        self.get(pindex.nHeight)
+            .map_or(false, |p| p.get_block_hash() == pindex.get_block_hash())
+    }
+
+    // Find the successor of a block in this chain, or None if the given index is not found or is the tip.
+    pub fn next(&self, pindex: &BlockIndex) -> Option<&BlockIndex> {
+        if self.contains(pindex) {
+            self.get(pindex.nHeight + 1)
+        } else {
+            None
+        }
+    }
+
+    //const CBlockIndex *CChain::FindFork(const CBlockIndex *pindex) const
+    // Find the last common block between this chain and a block index entry.
+    pub fn find_fork<'b>(
+        &self,
+        map_block_index: &'b BlockMap,
+        pindex: &'b BlockIndex,
+    ) -> Option<&BlockIndex> {
+        let mut pindex = if pindex.nHeight > self.height() {
+            pindex.get_ancestor(map_block_index, self.height())
+        } else {
+            Some(pindex)
+        };
+        while let Some(p) = pindex {
+            if self.contains(p) {
+                return self.get(p.nHeight);
+            }
+            pindex = p.get_pprev(map_block_index);
+        }
         None
     }
 }
 
-pub struct ValidationState {}
+fn median(mut v: Vec<i64>) -> i64 {
+    v.sort();
+    v[v.len() / 2]
+}
+
+//class CValidationState
+//A failed check either found the data invalid or could not be completed, e.g. because of a
+//disk error. Only the former says anything about the validity of a block.
+pub struct ValidationState {
+    f_error: Cell<bool>,
+    str_reject_reason: RefCell<String>,
+}
+
+impl ValidationState {
+    pub fn new() -> Self {
+        ValidationState {
+            f_error: Cell::new(false),
+            str_reject_reason: RefCell::new(String::new()),
+        }
+    }
+
+    //bool CValidationState::Invalid(bool ret, unsigned int chRejectCodeIn,
+    //    const std::string &strRejectReasonIn)
+    pub fn invalid(&self, str_reject_reason: &str) -> bool {
+        *self.str_reject_reason.borrow_mut() = str_reject_reason.to_string();
+        false
+    }
+
+    pub fn get_reject_reason(&self) -> String {
+        self.str_reject_reason.borrow().clone()
+    }
+
+    //bool CValidationState::Error(const std::string& strRejectReasonIn)
+    pub fn error(&self) -> bool {
+        self.f_error.set(true);
+        false
+    }
+
+    pub fn is_error(&self) -> bool {
+        self.f_error.get()
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::transaction::tests::transaction;
+    use crate::transaction::MutableTransaction;
+
+    pub(crate) fn block_index(hash: u64, pprev: Option<&BlockIndex>) -> BlockIndex {
+        let mut pindex = BlockIndex::new(U256::from(hash), 0);
+        pindex.n_chain_work = U256::one();
+        if let Some(pprev) = pprev {
+            pindex.nHeight = pprev.nHeight + 1;
+            pindex.n_chain_work = pprev.n_chain_work + U256::one();
+            pindex.hash_prev_block = pprev.get_block_hash();
+        }
+        pindex.n_status = BLOCK_HAVE_DATA;
+        pindex
+    }
+
+    // Builds `len` blocks on top of `pprev`, with hashes counting up from `first_hash`.
+    pub(crate) fn branch(pprev: Option<&BlockIndex>, first_hash: u64, len: u64) -> Vec<BlockIndex> {
+        let mut blocks: Vec<BlockIndex> = Vec::new();
+        for i in 0..len {
+            let pindex = block_index(first_hash + i, blocks.last().or(pprev));
+            blocks.push(pindex);
+        }
+        blocks
+    }
+
+    pub(crate) fn block_map(blocks: &[BlockIndex]) -> BlockMap {
+        blocks
+            .iter()
+            .map(|pindex| (pindex.get_block_hash(), pindex.clone()))
+            .collect()
+    }
+
+    #[test]
+    fn merkle_root_detects_duplicated_transactions() {
+        let tx = |value| {
+            let mut tx = MutableTransaction::from(&transaction());
+            tx.balancing_value = value;
+            Transaction::from(tx)
+        };
+        let block = |vtx| Block {
+            header: BlockHeader::new(),
+            vtx,
+        };
+
+        let (root, mutated) = block(vec![tx(1), tx(2), tx(3)]).compute_merkle_root();
+        assert!(!mutated);
+        assert!(root != block(vec![tx(2), tx(1), tx(3)]).compute_merkle_root().0);
+        assert!(root != block(vec![tx(1), tx(2), tx(4)]).compute_merkle_root().0);
+
+        // Repeating the last transaction gives the same root, but is flagged.
+        let (root2, mutated) = block(vec![tx(1), tx(2), tx(3), tx(3)]).compute_merkle_root();
+        assert_eq!(root2, root);
+        assert!(mutated);
+    }
+
+    #[test]
+    fn find_fork_and_next() {
+        let chain = Chain {
+            v_chain: branch(None, 0, 5),
+        };
+        let side = branch(Some(&chain.v_chain[2]), 10, 4);
+        let other = branch(None, 20, 2);
+        let mut map_block_index = block_map(&chain.v_chain);
+        map_block_index.extend(block_map(&side));
+        map_block_index.extend(block_map(&other));
+
+        assert_eq!(chain.height(), 4);
+        assert!(chain.contains(&chain.v_chain[3]));
+        assert!(!chain.contains(&side[0]));
+        assert_eq!(
+            side[3]
+                .get_ancestor(&map_block_index, 2)
+                .unwrap()
+                .get_block_hash(),
+            U256::from(2)
+        );
+        assert!(side[3].get_ancestor(&map_block_index, 7).is_none());
+        assert!(chain.v_chain[0].get_pprev(&map_block_index).is_none());
+
+        let fork = chain.find_fork(&map_block_index, &side[3]).unwrap();
+        assert_eq!(fork.get_block_hash(), U256::from(2));
+        let fork = chain
+            .find_fork(&map_block_index, &chain.v_chain[1])
+            .unwrap();
+        assert_eq!(fork.get_block_hash(), U256::from(1));
+        assert!(chain.find_fork(&map_block_index, &other[1]).is_none());
+
+        assert_eq!(
+            chain.next(&chain.v_chain[3]).unwrap().get_block_hash(),
+            U256::from(4)
+        );
+        assert!(chain.next(&chain.v_chain[4]).is_none());
+        assert!(chain.next(&side[1]).is_none());
+    }
+}
@@ -0,0 +1,310 @@
+//! Append-only storage of blocks (`blkNNNNN.dat`) and their undo data (`revNNNNN.dat`).
+//!
+//! Every record is framed as magic bytes, payload length, payload and a checksum of the
+//! payload. Undo checksums also cover the hash of the previous block, so undo data can
+//! never be applied on top of the wrong parent.
+
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use ethereum_types::U256;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use crate::block_chain::{Block, BlockUndo, DiskBlockPos};
+use crate::kv_store::{checksum, CHECKSUM_SIZE};
+
+pub const BLOCKS_DIR: &str = "blocks";
+
+// The maximum size of a blk?????.dat file
+pub const MAX_BLOCKFILE_SIZE: u64 = 0x8000000; // 128 MiB
+
+const MESSAGE_START: [u8; 4] = [0x69, 0x63, 0x65, 0xd9];
+
+const RECORD_HEADER_SIZE: u64 = 8;
+
+lazy_static! {
+    // Set by open_block_files() during startup
+    static ref BLOCK_FILES: Mutex<Option<BlockFileStore>> = Mutex::new(None);
+}
+
+/// Opens the block and undo files in the `blocks` directory of `data_dir`.
+pub fn open_block_files<P: AsRef<Path>>(data_dir: P) -> io::Result<()> {
+    let store = BlockFileStore::open(data_dir.as_ref().join(BLOCKS_DIR))?;
+    *BLOCK_FILES.lock().unwrap() = Some(store);
+    Ok(())
+}
+
+/// Runs `f` on the block files, failing if they were never opened.
+pub fn with_block_files<T, F>(f: F) -> io::Result<T>
+where
+    F: FnOnce(&mut BlockFileStore) -> io::Result<T>,
+{
+    match BLOCK_FILES.lock().unwrap().as_mut() {
+        Some(store) => f(store),
+        None => Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            "block files are not open",
+        )),
+    }
+}
+
+#[derive(Clone, Copy)]
+enum FileKind {
+    Block,
+    Undo,
+}
+
+impl FileKind {
+    fn prefix(&self) -> &'static str {
+        match self {
+            FileKind::Block => "blk",
+            FileKind::Undo => "rev",
+        }
+    }
+}
+
+pub struct BlockFileStore {
+    dir: PathBuf,
+    n_last_block_file: i32,
+}
+
+impl BlockFileStore {
+    /// Opens the store in `dir`, creating the directory if needed.
+    pub fn open<P: AsRef<Path>>(dir: P) -> io::Result<Self> {
+        let dir = dir.as_ref().to_path_buf();
+        fs::create_dir_all(&dir)?;
+
+        let mut n_last_block_file = 0;
+        while dir
+            .join(file_name(FileKind::Block, n_last_block_file + 1))
+            .exists()
+        {
+            n_last_block_file += 1;
+        }
+
+        Ok(BlockFileStore {
+            dir,
+            n_last_block_file,
+        })
+    }
+
+    fn path(&self, kind: FileKind, n_file: i32) -> PathBuf {
+        self.dir.join(file_name(kind, n_file))
+    }
+
+    //bool WriteBlockToDisk(CBlock& block, CDiskBlockPos& pos, ...)
+    pub fn write_block(&mut self, block: &Block) -> io::Result<DiskBlockPos> {
+        let mut payload = Vec::new();
+        block.write(&mut payload)?;
+        let record_size = RECORD_HEADER_SIZE + (payload.len() + CHECKSUM_SIZE) as u64;
+
+        // Start a new file once the current one would grow past its limit.
+        let current_size = file_size(&self.path(FileKind::Block, self.n_last_block_file))?;
+        if current_size > 0 && current_size + record_size > MAX_BLOCKFILE_SIZE {
+            self.n_last_block_file += 1;
+        }
+
+        let sum = checksum(&payload);
+        self.append(FileKind::Block, self.n_last_block_file, &payload, &sum)
+    }
+
+    //bool ReadBlockFromDisk(CBlock& block, const CDiskBlockPos& pos)
+    pub fn read_block(&self, pos: &DiskBlockPos) -> io::Result<Block> {
+        let (payload, sum) = self.read(FileKind::Block, pos)?;
+        if sum != checksum(&payload) {
+            return Err(corrupt("block checksum mismatch"));
+        }
+        Block::read(&payload[..])
+    }
+
+    //bool UndoWriteToDisk(const CBlockUndo& blockundo, CDiskBlockPos& pos, const uint256& hashBlock)
+    //The undo data is stored in the rev file paired with the block's blk file.
+    pub fn write_undo(
+        &mut self,
+        n_file: i32,
+        blockundo: &BlockUndo,
+        hash_block: U256,
+    ) -> io::Result<DiskBlockPos> {
+        let mut payload = Vec::new();
+        blockundo.write(&mut payload)?;
+        let sum = undo_checksum(&payload, hash_block);
+        self.append(FileKind::Undo, n_file, &payload, &sum)
+    }
+
+    //bool UndoReadFromDisk(CBlockUndo& blockundo, const CDiskBlockPos& pos, const uint256& hashBlock)
+    pub fn read_undo(&self, pos: &DiskBlockPos, hash_block: U256) -> io::Result<BlockUndo> {
+        let (payload, sum) = self.read(FileKind::Undo, pos)?;
+        if sum != undo_checksum(&payload, hash_block) {
+            return Err(corrupt("undo checksum mismatch"));
+        }
+        BlockUndo::read(&payload[..])
+    }
+
+    fn append(
+        &self,
+        kind: FileKind,
+        n_file: i32,
+        payload: &[u8],
+        sum: &[u8; CHECKSUM_SIZE],
+    ) -> io::Result<DiskBlockPos> {
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(self.path(kind, n_file))?;
+        let offset = file.seek(SeekFrom::End(0))?;
+
+        let mut record = Vec::with_capacity(payload.len() + 12);
+        record.extend_from_slice(&MESSAGE_START);
+        record.write_u32::<LittleEndian>(payload.len() as u32)?;
+        record.extend_from_slice(payload);
+        record.extend_from_slice(sum);
+        file.write_all(&record)?;
+        file.sync_data()?;
+
+        Ok(DiskBlockPos::new(n_file, offset as u32))
+    }
+
+    fn read(
+        &self,
+        kind: FileKind,
+        pos: &DiskBlockPos,
+    ) -> io::Result<(Vec<u8>, [u8; CHECKSUM_SIZE])> {
+        if pos.is_null() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "null disk position",
+            ));
+        }
+
+        let mut file = File::open(self.path(kind, pos.file))?;
+        file.seek(SeekFrom::Start(pos.offset as u64))?;
+
+        let mut magic = [0u8; 4];
+        file.read_exact(&mut magic)?;
+        if magic != MESSAGE_START {
+            return Err(corrupt("bad record magic"));
+        }
+        let len = file.read_u32::<LittleEndian>()? as u64;
+        if len > MAX_BLOCKFILE_SIZE {
+            return Err(corrupt("record too large"));
+        }
+
+        let mut payload = vec![0u8; len as usize];
+        file.read_exact(&mut payload)?;
+        let mut sum = [0u8; CHECKSUM_SIZE];
+        file.read_exact(&mut sum)?;
+        Ok((payload, sum))
+    }
+}
+
+fn file_name(kind: FileKind, n_file: i32) -> String {
+    format!("{}{:05}.dat", kind.prefix(), n_file)
+}
+
+fn file_size(path: &Path) -> io::Result<u64> {
+    match fs::metadata(path) {
+        Ok(metadata) => Ok(metadata.len()),
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(0),
+        Err(e) => Err(e),
+    }
+}
+
+fn undo_checksum(payload: &[u8], hash_block: U256) -> [u8; CHECKSUM_SIZE] {
+    let mut data = vec![0u8; 32];
+    hash_block.to_little_endian(&mut data[..32]);
+    data.extend_from_slice(payload);
+    checksum(&data)
+}
+
+fn corrupt(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::block_chain::{BlockHeader, TxInUndo, TxUndo};
+    use crate::kv_store::tests::temp_dir;
+    use crate::script::Script;
+    use crate::transaction::TxOut;
+    use std::sync::Once;
+
+    // Opens the block files in a temporary directory that all tests of this run share.
+    pub(crate) fn open_temp_block_files() {
+        static OPEN: Once = Once::new();
+        OPEN.call_once(|| open_block_files(temp_dir("ice_block_files")).unwrap());
+    }
+
+    fn empty_block() -> Block {
+        Block {
+            header: BlockHeader::new(),
+            vtx: vec![],
+        }
+    }
+
+    fn block_undo(n_value: i64) -> BlockUndo {
+        let mut txundo = TxUndo::new();
+        let mut undo = TxInUndo::new(TxOut {
+            n_value,
+            script_pub_key: Script::new(),
+        });
+        undo.set_last(true, 5);
+        txundo.vprevout.push(undo);
+
+        let mut blockundo = BlockUndo::new();
+        blockundo.vtxundo.push(txundo);
+        blockundo
+    }
+
+    #[test]
+    fn blocks_and_undo_round_trip() {
+        let dir = temp_dir("ice_block_store_round_trip");
+        let mut store = BlockFileStore::open(&dir).unwrap();
+
+        let pos1 = store.write_block(&empty_block()).unwrap();
+        let pos2 = store.write_block(&empty_block()).unwrap();
+        assert_eq!(pos1.file, 0);
+        assert_eq!(pos1.offset, 0);
+        assert!(pos2.offset > pos1.offset);
+        assert_eq!(store.read_block(&pos2).unwrap().vtx.len(), 0);
+
+        let hash = U256::from(9);
+        let upos = store.write_undo(pos1.file, &block_undo(77), hash).unwrap();
+        let undo = store.read_undo(&upos, hash).unwrap();
+        assert_eq!(undo.vtxundo.len(), 1);
+        assert_eq!(undo.vtxundo[0].vprevout[0].txout.n_value, 77);
+        assert_eq!(undo.vtxundo[0].vprevout[0].n_height, 5);
+        assert!(undo.vtxundo[0].vprevout[0].f_coin_base);
+        assert!(undo.vtxundo[0].vprevout[0].f_last);
+
+        // Undo data is bound to the block it was written for.
+        assert!(store.read_undo(&upos, U256::from(10)).is_err());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn corrupted_block_is_rejected() {
+        let dir = temp_dir("ice_block_store_corrupt");
+        let mut store = BlockFileStore::open(&dir).unwrap();
+        let pos = store.write_block(&empty_block()).unwrap();
+
+        let path = dir.join(file_name(FileKind::Block, pos.file));
+        let mut data = fs::read(&path).unwrap();
+        let last = data.len() - 1;
+        data[last] ^= 0xff;
+        fs::write(&path, data).unwrap();
+
+        assert!(store.read_block(&pos).is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn opened_block_files_are_shared() {
+        open_temp_block_files();
+        let pos = with_block_files(|store| store.write_block(&empty_block())).unwrap();
+        let block = with_block_files(|store| store.read_block(&pos)).unwrap();
+        assert_eq!(block.vtx.len(), 0);
+    }
+}
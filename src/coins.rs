@@ -1,12 +1,24 @@
 use crate::block_chain::BlockUndo;
+use crate::incremental_tree::merkle_tree::Hashable;
+use crate::incremental_tree::serialize::Vector;
 use crate::incremental_tree::tree::SaplingMerkleTree;
 use crate::key::key_management::FrHash;
+use crate::kv_store::{KvStore, WriteBatch};
+use crate::sendmany::CAmount;
 use crate::transaction::Transaction;
 use crate::transaction::{TxIn, TxOut};
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
 use std::collections::hash_map::HashMap;
+use std::io::{self, Read, Write};
+use std::mem;
+use std::path::Path;
 
 use ethereum_types::U256;
 
+// The priority of transactions with shielded spends
+pub const MAX_PRIORITY: f64 = 1e16;
+
+#[derive(Clone)]
 pub struct Coins {
     pub f_coin_base: bool,
 
@@ -46,6 +58,7 @@ impl Coins {
 
     pub fn clear(&mut self) {
         self.f_coin_base = false;
+        self.vout.clear();
         self.n_height = 0;
     }
 
@@ -74,6 +87,23 @@ impl Coins {
         self.n_height = n_height;
         self.clear_unspendable();
     }
+
+    pub fn read<R: Read>(mut reader: R) -> io::Result<Self> {
+        let f_coin_base = reader.read_u8()? != 0;
+        let n_height = reader.read_i32::<LittleEndian>()?;
+        let vout = Vector::read(&mut reader, |r| TxOut::read(r))?;
+        Ok(Coins {
+            f_coin_base,
+            n_height,
+            vout,
+        })
+    }
+
+    pub fn write<W: Write>(&self, mut writer: W) -> io::Result<()> {
+        writer.write_u8(self.f_coin_base as u8)?;
+        writer.write_i32::<LittleEndian>(self.n_height)?;
+        Vector::write(&mut writer, &self.vout, |w, txout| txout.write(w))
+    }
 }
 
 pub struct CoinsModifier<'a> {
@@ -173,62 +203,242 @@ pub trait CoinsView {
 
     fn set_best_block(&mut self, block_hash: U256);
 
-    fn have_coins(&self, txid: FrHash) -> bool;
+    //Retrieve the CCoins (unspent transaction outputs) for a given txid
+    fn get_coins(&mut self, txid: FrHash) -> Option<Coins>;
+
+    fn have_coins(&mut self, txid: FrHash) -> bool;
 }
 
-//
-pub struct CoinViewDB {}
+const DB_COINS: u8 = b'c';
+const DB_SAPLING_ANCHOR: u8 = b'Z';
+const DB_SAPLING_NULLIFIER: u8 = b'S';
+const DB_BEST_BLOCK: u8 = b'B';
+const DB_BEST_SAPLING_ANCHOR: u8 = b'z';
+
+fn fr_hash_key(prefix: u8, hash: &FrHash) -> Vec<u8> {
+    let mut key = vec![prefix];
+    hash.write(&mut key)
+        .expect("writing to a Vec should not fail");
+    key
+}
+
+fn u256_key(prefix: u8, value: &U256) -> Vec<u8> {
+    let mut bytes = [0u8; 32];
+    value.to_little_endian(&mut bytes);
+    let mut key = vec![prefix];
+    key.extend_from_slice(&bytes);
+    key
+}
+
+// The log of a CoinViewDB is compacted once it is at least this large and has doubled
+// since it was last compacted.
+pub const DB_COMPACT_MIN_SIZE: u64 = 32 << 20; // 32 MiB
+
+//Coins, anchors and nullifiers of the best chain, persisted in a KvStore
+pub struct CoinViewDB {
+    db: KvStore,
+    compact_min_size: u64,
+}
 
 impl CoinViewDB {
+    // Backed by memory only, nothing survives a restart.
     pub fn new() -> Self {
-        CoinViewDB {}
+        CoinViewDB {
+            db: KvStore::memory(),
+            compact_min_size: DB_COMPACT_MIN_SIZE,
+        }
+    }
+
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        Ok(CoinViewDB {
+            db: KvStore::open(path)?,
+            compact_min_size: DB_COMPACT_MIN_SIZE,
+        })
+    }
+
+    // Write a batch and compact the log when it has grown too much.
+    fn write_batch(&mut self, batch: WriteBatch) -> io::Result<()> {
+        self.db.write_batch(batch)?;
+        let log_size = self.db.log_size();
+        if log_size >= self.compact_min_size && log_size >= 2 * self.db.compacted_size() {
+            self.db.compact()?;
+        }
+        Ok(())
+    }
+
+    //bool CCoinsViewDB::BatchWrite(CCoinsMap &mapCoins, const uint256 &hashBlock, ...)
+    //Write all dirty entries together with the best block in one atomic batch.
+    fn batch_write(
+        &mut self,
+        map_coins: CoinsMap,
+        hash_block: U256,
+        hash_sapling_anchor: Option<FrHash>,
+        map_sapling_anchors: AnchorsSaplingMap,
+        map_sapling_nullifiers: NullifiersMap,
+    ) -> io::Result<()> {
+        let mut batch = WriteBatch::new();
+
+        for (txid, entry) in map_coins.into_iter() {
+            if !entry.dirty {
+                continue;
+            }
+            let key = fr_hash_key(DB_COINS, &txid);
+            if entry.coins.is_pruned() {
+                // A fresh entry was never written, so there is nothing to erase.
+                if !entry.fresh {
+                    batch.delete(key);
+                }
+            } else {
+                let mut value = Vec::new();
+                entry.coins.write(&mut value)?;
+                batch.put(key, value);
+            }
+        }
+
+        for (rt, entry) in map_sapling_anchors.into_iter() {
+            if !entry.dirty {
+                continue;
+            }
+            let key = fr_hash_key(DB_SAPLING_ANCHOR, &rt);
+            if entry.entered {
+                let mut value = Vec::new();
+                entry.tree.write(&mut value)?;
+                batch.put(key, value);
+            } else {
+                batch.delete(key);
+            }
+        }
+
+        for (nullifier, entry) in map_sapling_nullifiers.into_iter() {
+            if !entry.dirty {
+                continue;
+            }
+            let key = u256_key(DB_SAPLING_NULLIFIER, &nullifier);
+            if entry.entered {
+                batch.put(key, vec![1]);
+            } else {
+                batch.delete(key);
+            }
+        }
+
+        if !hash_block.is_zero() {
+            let mut bytes = [0u8; 32];
+            hash_block.to_little_endian(&mut bytes);
+            batch.put(vec![DB_BEST_BLOCK], bytes.to_vec());
+        }
+        if let Some(rt) = hash_sapling_anchor {
+            let mut value = Vec::new();
+            rt.write(&mut value)?;
+            batch.put(vec![DB_BEST_SAPLING_ANCHOR], value);
+        }
+
+        self.write_batch(batch)
     }
 }
 
-//TODO, not necessary now
 impl CoinsView for CoinViewDB {
     fn get_best_anchor(&self) -> Option<FrHash> {
-        None
+        match self.db.get(&[DB_BEST_SAPLING_ANCHOR]) {
+            Some(data) => FrHash::read(data).ok(),
+            None => Some(SaplingMerkleTree::empty_root()),
+        }
     }
 
     fn get_best_block(&self) -> U256 {
-        unimplemented!()
+        self.db
+            .get(&[DB_BEST_BLOCK])
+            .map(|data| U256::from_little_endian(data))
+            .unwrap_or_else(U256::zero)
     }
 
     fn get_sapling_anchor_at(&mut self, rt: FrHash) -> Option<SaplingMerkleTree> {
-        None
+        if rt == SaplingMerkleTree::empty_root() {
+            return Some(SaplingMerkleTree::new());
+        }
+        self.db
+            .get(&fr_hash_key(DB_SAPLING_ANCHOR, &rt))
+            .and_then(|data| SaplingMerkleTree::read(data).ok())
     }
 
     fn get_nullifier(&mut self, nullifier: U256) -> bool {
-        false
+        self.db
+            .contains_key(&u256_key(DB_SAPLING_NULLIFIER, &nullifier))
+    }
+
+    fn set_best_block(&mut self, block_hash: U256) {
+        let mut bytes = [0u8; 32];
+        block_hash.to_little_endian(&mut bytes);
+        let mut batch = WriteBatch::new();
+        batch.put(vec![DB_BEST_BLOCK], bytes.to_vec());
+        if let Err(e) = self.write_batch(batch) {
+            error!("CoinViewDB::set_best_block(): {}", e);
+        }
     }
 
-    fn set_best_block(&mut self, block_hash: U256) {}
+    fn get_coins(&mut self, txid: FrHash) -> Option<Coins> {
+        self.db
+            .get(&fr_hash_key(DB_COINS, &txid))
+            .and_then(|data| Coins::read(data).ok())
+    }
 
-    fn have_coins(&self, txid: FrHash) -> bool {
-        false
+    fn have_coins(&mut self, txid: FrHash) -> bool {
+        self.get_coins(txid).is_some()
     }
 }
 
-pub struct CoinViewCache {
+//A cache on top of another view. Only a cache on the CoinViewDB can be flushed, others are
+//scratch views, see CoinViewMemPool.
+pub struct CoinViewCache<B = CoinViewDB> {
     //mutable uint256 hashSaplingAnchor;
     hash_block: U256,
     hash_sapling_anchor: Option<FrHash>,
     cache_coins: CoinsMap,
     cached_sapling_anchors: AnchorsSaplingMap,
     cached_sapling_nullifiers: NullifiersMap,
-    base: CoinViewDB,
+    base: B,
 }
 
 impl CoinViewCache {
     pub fn new() -> Self {
+        CoinViewCache::with_base(CoinViewDB::new())
+    }
+
+    //bool CCoinsViewCache::Flush()
+    //Push all modifications to the base in one batch and empty the cache.
+    pub fn flush(&mut self) -> bool {
+        let hash_block = self.get_best_block();
+        let hash_sapling_anchor = self.hash_sapling_anchor;
+        let cache_coins = mem::replace(&mut self.cache_coins, CoinsMap::new());
+        let cached_sapling_anchors =
+            mem::replace(&mut self.cached_sapling_anchors, AnchorsSaplingMap::new());
+        let cached_sapling_nullifiers =
+            mem::replace(&mut self.cached_sapling_nullifiers, NullifiersMap::new());
+
+        match self.base.batch_write(
+            cache_coins,
+            hash_block,
+            hash_sapling_anchor,
+            cached_sapling_anchors,
+            cached_sapling_nullifiers,
+        ) {
+            Ok(()) => true,
+            Err(e) => {
+                error!("CoinViewCache::flush(): {}", e);
+                false
+            }
+        }
+    }
+}
+
+impl<B: CoinsView> CoinViewCache<B> {
+    pub fn with_base(base: B) -> Self {
         CoinViewCache {
             hash_block: U256::from(0),
             hash_sapling_anchor: None,
             cache_coins: CoinsMap::new(),
             cached_sapling_anchors: AnchorsSaplingMap::new(),
             cached_sapling_nullifiers: NullifiersMap::new(),
-            base: CoinViewDB::new(),
+            base,
         }
     }
 
@@ -247,14 +457,13 @@ impl CoinViewCache {
     /*pub fn get_blockundo(&self, block_hash: U256) -> Option<&BlockUndo> {
         self.block_undos.get(&block_hash)
     }*/
-}
 
-impl CoinViewCache {
     pub fn push_anchor(&mut self, tree: SaplingMerkleTree) {
-        let newrt = tree.root().unwrap();
+        let newrt = tree.root();
         let current_root = self.get_best_anchor().unwrap();
         if newrt != current_root {
-            let cache_entry = AnchorsSaplingCacheEntry::new(tree);
+            let mut cache_entry = AnchorsSaplingCacheEntry::new(tree);
+            cache_entry.entered = true;
             self.cached_sapling_anchors.insert(newrt, cache_entry);
         }
         self.hash_sapling_anchor = Some(newrt);
@@ -265,31 +474,28 @@ impl CoinViewCache {
         if !current_root.is_none() {
             let current_root = current_root.unwrap();
             if current_root != newrt {
+                // Bring the entry into the cache so erasing it reaches the base on flush.
+                self.get_sapling_anchor_at(current_root);
                 let entry = self.cached_sapling_anchors.get_mut(&current_root);
                 if !entry.is_none() {
                     let e = entry.unwrap();
                     e.entered = false;
                     e.dirty = true;
                 }
-                /*entry.and_then(|e| {
-                    e.entered = false;
-                    e.dirty = true;
-                });*/
             };
             self.hash_sapling_anchor = Some(newrt);
         }
-        /*current_root.and_then(|current_root| {
-            if current_root != newrt {
-                let entry = self.cached_sapling_anchors.get_mut(&current_root);
-                entry.and_then(|e| {
-                    e.entered = false;
-                    e.dirty = true;
-                    None
-                });
-            };
-            self.hash_sapling_anchor = Some(newrt);
-            None
-        });*/
+    }
+
+    //Load the coins of txid from the base into the cache if they are not cached yet
+    fn fetch_coins(&mut self, txid: FrHash) -> Option<&mut CoinsCacheEntry> {
+        if !self.cache_coins.contains_key(&txid) {
+            let coins = self.base.get_coins(txid)?;
+            let mut entry = CoinsCacheEntry::new();
+            entry.coins = coins;
+            self.cache_coins.insert(txid, entry);
+        }
+        self.cache_coins.get_mut(&txid)
     }
 
     /**
@@ -297,19 +503,15 @@ impl CoinViewCache {
      * txid exists, a new one is created. Simultaneous modifications are not
      * allowed.
      */
-
-    //For now omit this, since we omit transaction check
     pub fn modify_coins(&mut self, txid: FrHash) -> Option<CoinsModifier> {
-        //assert();
-        if !self.cache_coins.contains_key(&txid) {
-            self.cache_coins.insert(txid, CoinsCacheEntry::new());
+        if self.fetch_coins(txid).is_none() {
+            let mut entry = CoinsCacheEntry::new();
+            // The base has never seen this txid, nothing needs erasing on flush
+            entry.fresh = true;
+            self.cache_coins.insert(txid, entry);
         }
         let entry = self.cache_coins.get_mut(&txid);
 
-        /*if !entry.is_none() {
-            entry.unwrap().dirty = true;
-        }*/
-
         entry.and_then(|mut e| {
             e.dirty = true;
             Some(CoinsModifier::new(e))
@@ -324,17 +526,13 @@ impl CoinViewCache {
         })
     }
 
-    pub fn get_coins() -> Option<Coins> {
-        unimplemented!()
-    }
-
-    pub fn have_inputs(&self, tx: &Transaction) -> bool {
+    pub fn have_inputs(&mut self, tx: &Transaction) -> bool {
         if !tx.is_coin_base() {
             for txin in tx.vin.iter() {
                 let prevout = &txin.prevout;
                 let coins = self.access_coins(prevout.hash);
 
-                if !coins.is_none() && coins.unwrap().is_available(prevout.n) {
+                if coins.is_none() || !coins.unwrap().is_available(prevout.n) {
                     return false;
                 }
             }
@@ -342,14 +540,54 @@ impl CoinViewCache {
         true
     }
 
-    //Code include fetch_coins
-    //TODO, implement DB action
-    pub fn access_coins(&self, txid: FrHash) -> Option<&Coins> {
-        let entry = self.cache_coins.get(&txid);
-        entry.map(|e| &e.coins)
+    //CAmount CCoinsViewCache::GetValueIn(const CTransaction& tx) const
+    //The inputs must be available, see have_inputs.
+    pub fn get_value_in(&mut self, tx: &Transaction) -> CAmount {
+        if tx.is_coin_base() {
+            return 0;
+        }
+        let mut n_result = 0;
+        for txin in tx.vin.iter() {
+            let coins = self
+                .access_coins(txin.prevout.hash)
+                .expect("inputs should be available");
+            n_result += coins.vout[txin.prevout.n].n_value;
+        }
+        n_result + tx.get_shielded_value_in()
     }
 
-    //pub fn fetch_coins(txid: FrHash)
+    //double CCoinsViewCache::GetPriority(const CTransaction &tx, int nHeight) const
+    //The sum of the input values weighted by their age at n_height, per byte.
+    pub fn get_priority(&mut self, tx: &Transaction, n_height: i32) -> f64 {
+        if tx.is_coin_base() {
+            return 0.0;
+        }
+        // Shielded spends reveal neither the value nor the age of the note, so they get
+        // the maximum priority.
+        if !tx.v_shielded_spend.is_empty() {
+            return MAX_PRIORITY;
+        }
+        let mut d_result = 0.0;
+        for txin in tx.vin.iter() {
+            let coins = match self.access_coins(txin.prevout.hash) {
+                Some(coins) => coins,
+                None => continue,
+            };
+            if !coins.is_available(txin.prevout.n) {
+                continue;
+            }
+            if coins.n_height < n_height {
+                d_result +=
+                    coins.vout[txin.prevout.n].n_value as f64 * (n_height - coins.n_height) as f64;
+            }
+        }
+        tx.compute_priority(d_result, tx.get_serialize_size())
+    }
+
+    pub fn access_coins(&mut self, txid: FrHash) -> Option<&Coins> {
+        let entry = self.fetch_coins(txid);
+        entry.map(|e| &e.coins)
+    }
 
     //Check if all sapling spend requirement(anchors/nullifiers) are satisfied.
     pub fn have_shield_requirements(&mut self, tx: &Transaction) -> bool {
@@ -366,64 +604,146 @@ impl CoinViewCache {
     }
 }
 
-impl CoinsView for CoinViewCache {
+impl<B: CoinsView> CoinsView for CoinViewCache<B> {
     fn get_best_anchor(&self) -> Option<FrHash> {
         self.hash_sapling_anchor
+            .or_else(|| self.base.get_best_anchor())
     }
 
     fn get_best_block(&self) -> U256 {
+        if self.hash_block.is_zero() {
+            return self.base.get_best_block();
+        }
         self.hash_block
     }
 
     //bool CCoinsViewCache::GetSaplingAnchorAt(const uint256 &rt, SaplingMerkleTree &tree) const {
     fn get_sapling_anchor_at(&mut self, rt: FrHash) -> Option<SaplingMerkleTree> {
-        let res = self.cached_sapling_anchors.get(&rt);
-        match res {
-            None => {
-                return None;
-            }
-            Some(ref entry) => {
-                if entry.entered {
-                    return Some(entry.tree.clone());
-                } else {
-                    return None;
-                }
-            }
+        if rt == SaplingMerkleTree::empty_root() {
+            return Some(SaplingMerkleTree::new());
         }
 
-        //TODO, implement DB
-        /*let tree = self.base.get_sapling_anchor_at(rt.clone());
-        let tree_clone = tree.clone();
-        match tree_clone {
-            None => {
+        if let Some(entry) = self.cached_sapling_anchors.get(&rt) {
+            if entry.entered {
+                return Some(entry.tree.clone());
+            } else {
                 return None;
             }
-            Some(t) => {
-                let entry = AnchorsSaplingCacheEntry::new(t);
-                let rt_clone = rt.clone();
-                self.cached_sapling_anchors.insert(rt_clone, entry);
-                tree
-            }
-        }*/
+        }
+
+        let tree = self.base.get_sapling_anchor_at(rt);
+        if let Some(ref t) = tree {
+            let mut entry = AnchorsSaplingCacheEntry::new(t.clone());
+            entry.entered = true;
+            entry.dirty = false;
+            self.cached_sapling_anchors.insert(rt, entry);
+        }
+        tree
     }
 
     fn get_nullifier(&mut self, nullifier: U256) -> bool {
-        let entry = self.cached_sapling_nullifiers.get(&nullifier);
-        let res = entry.map(|e| e.entered);
-        if res.is_none() {
-            return false;
-        } else {
-            return res.unwrap();
+        if let Some(entry) = self.cached_sapling_nullifiers.get(&nullifier) {
+            return entry.entered;
         }
+
+        let spent = self.base.get_nullifier(nullifier);
+        let mut entry = NullifiersCacheEntry::new();
+        entry.entered = spent;
+        self.cached_sapling_nullifiers.insert(nullifier, entry);
+        spent
     }
 
-    fn set_best_block(&mut self, block_hash: U256) {}
+    fn set_best_block(&mut self, block_hash: U256) {
+        self.hash_block = block_hash;
+    }
 
-    fn have_coins(&self, txid: FrHash) -> bool {
-        self.cache_coins.contains_key(&txid)
+    fn get_coins(&mut self, txid: FrHash) -> Option<Coins> {
+        self.access_coins(txid).cloned()
     }
 
-    /*fn fetch_coins(&self, txid: U256) {
-        self.cache_coins.
-    }*/
+    fn have_coins(&mut self, txid: FrHash) -> bool {
+        self.access_coins(txid)
+            .map(|coins| !coins.is_pruned())
+            .unwrap_or(false)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::kv_store::tests::temp_dir;
+    use ff::PrimeField;
+    use pairing::bls12_381::{Fr, FrRepr};
+    use std::fs;
+
+    fn fr_hash(n: u64) -> FrHash {
+        FrHash(Fr::from_repr(FrRepr::from(n)).unwrap())
+    }
+
+    #[test]
+    fn flush_persists_coins_and_anchors() {
+        let dir = temp_dir("ice_coins_flush");
+        let path = dir.join("chainstate.db");
+
+        let txid = fr_hash(7);
+        let mut tree = SaplingMerkleTree::new();
+        tree.append(fr_hash(1)).unwrap();
+        let rt = tree.root();
+        {
+            let mut view = CoinViewCache::with_base(CoinViewDB::open(&path).unwrap());
+            {
+                let mut coins = view.modify_new_coins(txid).unwrap();
+                coins.entry.coins.n_height = 3;
+                coins.entry.coins.vout.push(TxOut {
+                    n_value: 50,
+                    script_pub_key: crate::script::Script::new(),
+                });
+            }
+            view.push_anchor(tree);
+            view.set_best_block(U256::from(42));
+            assert!(view.flush());
+        }
+
+        let mut view = CoinViewCache::with_base(CoinViewDB::open(&path).unwrap());
+        assert_eq!(view.get_best_block(), U256::from(42));
+        assert_eq!(view.get_best_anchor(), Some(rt));
+        assert!(view.get_sapling_anchor_at(rt).is_some());
+        assert!(view.have_coins(txid));
+        assert_eq!(view.access_coins(txid).unwrap().vout[0].n_value, 50);
+
+        // Spending the last output erases the coins from the database.
+        view.modify_coins(txid).unwrap().entry.coins.spend(0);
+        view.pop_anchor(SaplingMerkleTree::empty_root());
+        assert!(view.flush());
+
+        let mut view = CoinViewCache::with_base(CoinViewDB::open(&path).unwrap());
+        assert!(!view.have_coins(txid));
+        assert!(view.get_sapling_anchor_at(rt).is_none());
+        assert_eq!(
+            view.get_best_anchor(),
+            Some(SaplingMerkleTree::empty_root())
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn log_is_compacted_when_it_grows() {
+        let dir = temp_dir("ice_coins_compact");
+        let path = dir.join("chainstate.db");
+        let mut db = CoinViewDB::open(&path).unwrap();
+        db.compact_min_size = 1;
+
+        db.set_best_block(U256::from(1));
+        let record_size = db.db.log_size();
+        for n in 2..=20 {
+            db.set_best_block(U256::from(n));
+            // Only the latest best block is live, older writes are dropped.
+            assert!(db.db.log_size() <= 2 * record_size);
+        }
+        assert_eq!(db.db.log_size(), fs::metadata(&path).unwrap().len());
+
+        let db = CoinViewDB::open(&path).unwrap();
+        assert_eq!(db.get_best_block(), U256::from(20));
+        fs::remove_dir_all(&dir).unwrap();
+    }
 }
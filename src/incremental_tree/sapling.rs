@@ -12,6 +12,7 @@ use sapling_crypto::{
 use std::io::{self, Read, Write};
 
 use crate::incremental_tree::merkle_tree::Hashable;
+use crate::key::key_management::FrHash;
 use zcash_primitives::JUBJUB;
 
 pub(crate) const SAPLING_COMMITMENT_TREE_DEPTH: usize =
@@ -94,6 +95,33 @@ impl From<Node> for Fr {
     }
 }
 
+impl Hashable for FrHash {
+    fn read<R: Read>(mut reader: R) -> io::Result<Self> {
+        let mut repr = FrRepr::default();
+        repr.read_le(&mut reader)?;
+        Fr::from_repr(repr)
+            .map(FrHash)
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "node not in field"))
+    }
+
+    fn write<W: Write>(&self, mut writer: W) -> io::Result<()> {
+        self.0.into_repr().write_le(&mut writer)
+    }
+
+    fn combine(depth: usize, lhs: &Self, rhs: &Self) -> Self {
+        let repr = merkle_hash(depth, &lhs.0.into_repr(), &rhs.0.into_repr());
+        FrHash(Fr::from_repr(repr).expect("Tree nodes should be in the prime field"))
+    }
+
+    fn blank() -> Self {
+        FrHash(Note::<Bls12>::uncommitted())
+    }
+
+    fn empty_root(depth: usize) -> Self {
+        FrHash(Fr::from(EMPTY_ROOTS[depth]))
+    }
+}
+
 lazy_static! {
     static ref EMPTY_ROOTS: Vec<Node> = {
         let mut v = vec![Node::blank()];
@@ -135,3 +163,32 @@ pub fn spend_sig(
         params,
     )
 }
+
+/// Create the bindingSig for a Sapling transaction.
+///
+/// `bsk` is the sum of the spend value commitment randomness minus the sum of the
+/// output value commitment randomness.
+pub fn binding_sig(bsk: Fs, sighash: &[u8; 32], params: &JubjubBls12) -> Signature {
+    // Initialize secure RNG
+    let mut rng = OsRng::new().expect("should be able to construct RNG");
+
+    let bsk = PrivateKey::<Bls12>(bsk);
+
+    // Grab the `bvk` using DerivePublic.
+    let bvk = PublicKey::from_private(&bsk, FixedGenerators::ValueCommitmentRandomness, params);
+
+    // Compute the signature's message for bvk/binding_sig
+    let mut data_to_be_signed = [0u8; 64];
+    bvk.0
+        .write(&mut data_to_be_signed[0..32])
+        .expect("message buffer should be 32 bytes");
+    (&mut data_to_be_signed[32..64]).copy_from_slice(&sighash[..]);
+
+    // Do the signing
+    bsk.sign(
+        &data_to_be_signed,
+        &mut rng,
+        FixedGenerators::ValueCommitmentRandomness,
+        params,
+    )
+}
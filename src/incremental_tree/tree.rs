@@ -1,80 +1,164 @@
-use ethereum_types::U256;
-use ff::PrimeField;
-use pairing::bls12_381::{Bls12, Fr, FrRepr};
+use std::io::{self, Read, Write};
 
+use crate::incremental_tree::merkle_tree::{self, CommitmentTree, CommitmentTreeWitness, Hashable};
+use crate::incremental_tree::sapling::SAPLING_COMMITMENT_TREE_DEPTH;
 use crate::key::key_management::FrHash;
 
-/*pub trait IncrementalMerkleTreeTrait<H> {
-
-    fn path(&self, filler_hashs: Vec<H>) -> MerklePath;
-
-    fn root(&self) -> H;
-}*/
-
+/// An incremental Merkle tree of note commitments, backed by [`CommitmentTree`].
 #[derive(Clone)]
-pub struct IncrementalMerkleTree<H> {
-    pub tmp: Vec<H>,
+pub struct IncrementalMerkleTree<H: Hashable> {
+    tree: CommitmentTree<H>,
 }
 
-impl<H> IncrementalMerkleTree<H> {
-    pub fn append(&self, hash: H) {}
+impl<H: Hashable> IncrementalMerkleTree<H> {
+    /// Creates an empty tree.
+    pub fn new() -> Self {
+        IncrementalMerkleTree {
+            tree: CommitmentTree::new(),
+        }
+    }
 
-    pub fn witness(&self) -> Option<IncrementalWitness<H>> {
-        None
+    /// Returns the root of the empty tree.
+    pub fn empty_root() -> H {
+        H::empty_root(SAPLING_COMMITMENT_TREE_DEPTH)
     }
 
-    pub fn path(&self, filler_hashs: Vec<H>) -> Option<MerklePath> {
-        None
+    /// Reads a tree from its serialized form.
+    pub fn read<R: Read>(reader: R) -> io::Result<Self> {
+        let tree = CommitmentTree::read(reader)?;
+        Ok(IncrementalMerkleTree { tree })
     }
 
-    pub fn root(&self) -> Option<H> {
-        None
+    /// Serializes this tree as an array of bytes.
+    pub fn write<W: Write>(&self, writer: W) -> io::Result<()> {
+        self.tree.write(writer)
     }
-}
 
-pub struct MerklePath {}
+    /// Returns the number of commitments in the tree.
+    pub fn size(&self) -> usize {
+        self.tree.size()
+    }
 
-impl MerklePath {}
+    /// Adds a commitment to the tree.
+    ///
+    /// Returns an error if the tree is full.
+    pub fn append(&mut self, hash: H) -> Result<(), ()> {
+        self.tree.append(hash)
+    }
 
-/*pub trait IncrementalWitnessTrait<H>{
+    /// Creates a witness for the most recently appended commitment.
+    pub fn witness(&self) -> IncrementalWitness<H> {
+        IncrementalWitness {
+            witness: merkle_tree::IncrementalWitness::from_tree(&self.tree),
+        }
+    }
 
-    fn path(&self) -> MerklePath;
+    /// Returns the current root of the tree.
+    pub fn root(&self) -> H {
+        self.tree.root()
+    }
+}
 
-    fn partial_path(&self) -> Vec<H>;
+/// An authentication path from a note commitment to the tree root.
+pub type MerklePath<H> = CommitmentTreeWitness<H>;
 
-    fn root(&self) -> H;
+/// A witness to a commitment, kept up to date by appending every later commitment.
+#[derive(Clone)]
+pub struct IncrementalWitness<H: Hashable> {
+    witness: merkle_tree::IncrementalWitness<H>,
 }
-*/
 
-pub struct IncrementalWitness<H> {
-    pub tree: IncrementalMerkleTree<H>,
-    pub filled: Vec<H>,
-}
+impl<H: Hashable> IncrementalWitness<H> {
+    /// Reads a witness from its serialized form.
+    pub fn read<R: Read>(reader: R) -> io::Result<Self> {
+        let witness = merkle_tree::IncrementalWitness::read(reader)?;
+        Ok(IncrementalWitness { witness })
+    }
 
-impl<H> IncrementalWitness<H> {
-    /*MerklePath path() const {
-    return tree.path(partial_path());
-    }*/
-    fn path(&self) -> Option<MerklePath> {
-        //self.tree.path(self.partial_path())
-        None
+    /// Serializes this witness as an array of bytes.
+    pub fn write<W: Write>(&self, writer: W) -> io::Result<()> {
+        self.witness.write(writer)
     }
 
-    fn partial_path(&self) -> Option<Vec<H>> {
-        None
+    /// Returns the position of the witnessed commitment in the tree.
+    pub fn position(&self) -> usize {
+        self.witness.position()
     }
 
-    /*Hash root() const {
-    return tree.root(Depth, partial_path());
-    }*/
-    pub fn root(&self) -> Option<H> {
-        //self.tree.root()
-        None
+    /// Returns the authentication path, or None if the tree is empty.
+    pub fn path(&self) -> Option<MerklePath<H>> {
+        self.witness.path()
     }
 
-    pub fn append(&self, hash: H) {}
+    /// Returns the root of the tree this witness currently points into.
+    pub fn root(&self) -> H {
+        self.witness.root()
+    }
+
+    /// Tracks a commitment that has been appended to the underlying tree.
+    ///
+    /// Returns an error if the tree is full.
+    pub fn append(&mut self, hash: H) -> Result<(), ()> {
+        self.witness.append(hash)
+    }
 }
 
 pub type SaplingWitness = IncrementalWitness<FrHash>;
 
 pub type SaplingMerkleTree = IncrementalMerkleTree<FrHash>;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::incremental_tree::sapling::Node;
+
+    use ff::PrimeField;
+    use pairing::bls12_381::Fr;
+    use rand::{Rand, SeedableRng, XorShiftRng};
+
+    #[test]
+    fn sapling_tree_matches_node_tree() {
+        let mut rng = XorShiftRng::from_seed([0x5dbe6259, 0x8d313d76, 0x3237db17, 0xe5bc0654]);
+        let mut tree = SaplingMerkleTree::new();
+        let mut node_tree = CommitmentTree::<Node>::new();
+
+        assert_eq!(tree.root(), SaplingMerkleTree::empty_root());
+        assert_eq!(tree.root(), FrHash(Fr::from(node_tree.root())));
+
+        for _ in 0..5 {
+            let cm = Fr::rand(&mut rng);
+            tree.append(FrHash(cm)).unwrap();
+            node_tree.append(Node::new(cm.into_repr())).unwrap();
+            assert_eq!(tree.root(), FrHash(Fr::from(node_tree.root())));
+        }
+        assert_eq!(tree.size(), 5);
+    }
+
+    #[test]
+    fn sapling_witness_follows_tree() {
+        let mut rng = XorShiftRng::from_seed([0x5dbe6259, 0x8d313d76, 0x3237db17, 0xe5bc0654]);
+        let mut tree = SaplingMerkleTree::new();
+
+        tree.append(FrHash(Fr::rand(&mut rng))).unwrap();
+        tree.append(FrHash(Fr::rand(&mut rng))).unwrap();
+        let mut witness = tree.witness();
+        assert_eq!(witness.position(), 1);
+        assert_eq!(witness.root(), tree.root());
+
+        for _ in 0..10 {
+            let cm = FrHash(Fr::rand(&mut rng));
+            tree.append(cm).unwrap();
+            witness.append(cm).unwrap();
+            assert_eq!(witness.root(), tree.root());
+        }
+
+        let path = witness.path().unwrap();
+        assert_eq!(path.position, 1);
+        assert_eq!(path.auth_path.len(), SAPLING_COMMITMENT_TREE_DEPTH);
+
+        let mut data = vec![];
+        witness.write(&mut data).unwrap();
+        let witness2 = SaplingWitness::read(&data[..]).unwrap();
+        assert_eq!(witness2.root(), tree.root());
+    }
+}
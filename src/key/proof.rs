@@ -1 +1,375 @@
-pub struct ProofVerifier {}
+use bellman::groth16::{verify_proof, PreparedVerifyingKey, Proof, VerifyingKey};
+use ff::{Field, PrimeField};
+use pairing::bls12_381::{Bls12, Fr};
+use pairing::{CurveAffine, CurveProjective, Engine};
+use rand::{OsRng, Rand};
+use sapling_crypto::circuit::multipack;
+use sapling_crypto::jubjub::{
+    edwards, fs::Fs, fs::FsRepr, FixedGenerators, JubjubBls12, JubjubParams, Unknown,
+};
+use sapling_crypto::redjubjub::{PublicKey, Signature};
+use zcash_primitives::JUBJUB;
+
+use crate::key::key_management::FrHash;
+use crate::transaction::Transaction;
+use crate::zkp::{OUTPUT_VERIFYING_KEY, OUTPUT_VK, SPEND_VERIFYING_KEY, SPEND_VK};
+
+//Groth16 proofs of one circuit, checked together with a random linear combination.
+pub struct BatchVerifier {
+    items: Vec<(Proof<Bls12>, Vec<Fr>)>,
+}
+
+impl BatchVerifier {
+    pub fn new() -> Self {
+        BatchVerifier { items: Vec::new() }
+    }
+
+    pub fn len(&self) -> usize {
+        self.items.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.items.is_empty()
+    }
+
+    pub fn queue(&mut self, proof: Proof<Bls12>, public_inputs: Vec<Fr>) {
+        self.items.push((proof, public_inputs));
+    }
+
+    pub fn clear(&mut self) {
+        self.items.clear();
+    }
+
+    // Each proof satisfies e(A, B) = e(alpha, beta) * e(IC, gamma) * e(C, delta), where IC
+    // is the linear combination of the public inputs. With a random r_i per proof, all of
+    // them hold (except with negligible probability) if
+    //   prod e(r_i * A_i, B_i) * e(-sum r_i * alpha, beta) * e(-sum r_i * IC_i, gamma)
+    //   * e(-sum r_i * C_i, delta) = 1,
+    // which only needs a single final exponentiation.
+    pub fn verify(&self, vk: &VerifyingKey<Bls12>) -> bool {
+        if self.is_empty() {
+            return true;
+        }
+        let mut rng = OsRng::new().expect("should be able to construct RNG");
+
+        let mut sum_r = Fr::zero();
+        let mut acc_ic = <Bls12 as Engine>::G1::zero();
+        let mut acc_c = <Bls12 as Engine>::G1::zero();
+        let mut terms = Vec::with_capacity(self.items.len() + 3);
+
+        for (proof, public_inputs) in self.items.iter() {
+            if public_inputs.len() + 1 != vk.ic.len() {
+                return false;
+            }
+            let r = Fr::rand(&mut rng);
+            sum_r.add_assign(&r);
+
+            let mut ic = vk.ic[0].into_projective();
+            for (input, base) in public_inputs.iter().zip(vk.ic.iter().skip(1)) {
+                ic.add_assign(&base.mul(input.into_repr()));
+            }
+            ic.mul_assign(r.into_repr());
+            acc_ic.add_assign(&ic);
+            acc_c.add_assign(&proof.c.mul(r.into_repr()));
+
+            let a = proof.a.mul(r.into_repr()).into_affine();
+            terms.push((a.prepare(), proof.b.prepare()));
+        }
+
+        let mut acc_alpha = vk.alpha_g1.mul(sum_r.into_repr());
+        acc_alpha.negate();
+        acc_ic.negate();
+        acc_c.negate();
+        terms.push((acc_alpha.into_affine().prepare(), vk.beta_g2.prepare()));
+        terms.push((acc_ic.into_affine().prepare(), vk.gamma_g2.prepare()));
+        terms.push((acc_c.into_affine().prepare(), vk.delta_g2.prepare()));
+
+        let refs = terms.iter().map(|(g1, g2)| (g1, g2)).collect::<Vec<_>>();
+        match Bls12::final_exponentiation(&Bls12::miller_loop(refs.iter())) {
+            Some(result) => result == <Bls12 as Engine>::Fqk::one(),
+            None => false,
+        }
+    }
+
+    // Checks the proofs one at a time and returns the index of the first invalid one.
+    pub fn find_invalid(&self, pvk: &PreparedVerifyingKey<Bls12>) -> Option<usize> {
+        self.items.iter().position(|(proof, public_inputs)| {
+            match verify_proof(pvk, proof, public_inputs) {
+                Ok(valid) => !valid,
+                Err(_) => true,
+            }
+        })
+    }
+}
+
+fn is_small_order(p: &edwards::Point<Bls12, Unknown>, params: &JubjubBls12) -> bool {
+    p.double(params).double(params).double(params) == edwards::Point::zero()
+}
+
+// The value commitment of value with zero randomness.
+fn compute_value_balance(
+    value: i64,
+    params: &JubjubBls12,
+) -> Option<edwards::Point<Bls12, Unknown>> {
+    let abs = value.checked_abs()? as u64;
+    let abs = Fs::from_repr(FsRepr::from(abs)).ok()?;
+    let value_balance = params
+        .generator(FixedGenerators::ValueCommitmentValue)
+        .mul(abs, params);
+    let value_balance: edwards::Point<Bls12, Unknown> = value_balance.into();
+    if value < 0 {
+        Some(value_balance.negate())
+    } else {
+        Some(value_balance)
+    }
+}
+
+//Checks the shielded signatures of transactions and collects their spend and output
+//proofs, so the proofs of a whole block (or a batch of mempool transactions) can be
+//verified at once.
+pub struct ProofVerifier {
+    perform_verification: bool,
+    spends: BatchVerifier,
+    outputs: BatchVerifier,
+    // The transaction each queued proof belongs to
+    spend_txids: Vec<FrHash>,
+    output_txids: Vec<FrHash>,
+}
+
+impl ProofVerifier {
+    // Creates a verification context that strictly validates proofs.
+    pub fn strict() -> Self {
+        ProofVerifier {
+            perform_verification: true,
+            spends: BatchVerifier::new(),
+            outputs: BatchVerifier::new(),
+            spend_txids: Vec::new(),
+            output_txids: Vec::new(),
+        }
+    }
+
+    // Creates a verification context that performs no validation.
+    pub fn disabled() -> Self {
+        ProofVerifier {
+            perform_verification: false,
+            ..ProofVerifier::strict()
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.perform_verification
+    }
+
+    //Checks the curve points, the spend authorization signatures and the binding signature
+    //of tx, as SaplingVerificationContext does, and queues its proofs for verify.
+    pub fn check_transaction(&mut self, tx: &Transaction, sighash: &[u8; 32]) -> bool {
+        let params = &*JUBJUB;
+        let mut cv_sum = edwards::Point::<Bls12, Unknown>::zero();
+        let mut spends = Vec::with_capacity(tx.v_shielded_spend.len());
+        let mut outputs = Vec::with_capacity(tx.v_shielded_output.len());
+
+        for spend in tx.v_shielded_spend.iter() {
+            if is_small_order(&spend.cv, params) || is_small_order(&spend.rk.0, params) {
+                return false;
+            }
+            cv_sum = cv_sum.add(&spend.cv, params);
+
+            // Compute the signature's message for rk/spend_auth_sig
+            let spend_auth_sig = match spend.spend_auth_sig {
+                Some(ref sig) => sig,
+                None => return false,
+            };
+            let mut data_to_be_signed = [0u8; 64];
+            spend
+                .rk
+                .0
+                .write(&mut data_to_be_signed[0..32])
+                .expect("message buffer should be 32 bytes");
+            (&mut data_to_be_signed[32..64]).copy_from_slice(&sighash[..]);
+            if !spend.rk.verify(
+                &data_to_be_signed,
+                spend_auth_sig,
+                FixedGenerators::SpendingKeyGenerator,
+                params,
+            ) {
+                return false;
+            }
+
+            let zkproof = match Proof::<Bls12>::read(&spend.zkproof[..]) {
+                Ok(p) => p,
+                Err(_) => return false,
+            };
+            // Construct public input for circuit
+            let mut public_input = Vec::with_capacity(7);
+            let (x, y) = spend.rk.0.into_xy();
+            public_input.push(x);
+            public_input.push(y);
+            let (x, y) = spend.cv.into_xy();
+            public_input.push(x);
+            public_input.push(y);
+            public_input.push(spend.anchor);
+            // Add the nullifier through multiscalar packing
+            let nullifier = multipack::bytes_to_bits_le(&spend.nullifier);
+            public_input.extend(multipack::compute_multipacking::<Bls12>(&nullifier));
+            spends.push((zkproof, public_input));
+        }
+
+        for output in tx.v_shielded_output.iter() {
+            if is_small_order(&output.cv, params) || is_small_order(&output.ephemeral_key, params) {
+                return false;
+            }
+            cv_sum = cv_sum.add(&output.cv.negate(), params);
+
+            let zkproof = match Proof::<Bls12>::read(&output.zkproof[..]) {
+                Ok(p) => p,
+                Err(_) => return false,
+            };
+            // Construct public input for circuit
+            let mut public_input = Vec::with_capacity(5);
+            let (x, y) = output.cv.into_xy();
+            public_input.push(x);
+            public_input.push(y);
+            let (x, y) = output.ephemeral_key.into_xy();
+            public_input.push(x);
+            public_input.push(y);
+            public_input.push(output.cmu);
+            outputs.push((zkproof, public_input));
+        }
+
+        if tx.has_shielded() {
+            // Obtain the current bvk from the accumulated cv and the value balance
+            let value_balance = match compute_value_balance(tx.balancing_value, params) {
+                Some(value_balance) => value_balance,
+                None => return false,
+            };
+            let bvk = PublicKey::<Bls12>(cv_sum.add(&value_balance.negate(), params));
+
+            // Compute the signature's message for bvk/binding_sig
+            let binding_sig = match Signature::read(&tx.binding_sig[..]) {
+                Ok(sig) => sig,
+                Err(_) => return false,
+            };
+            let mut data_to_be_signed = [0u8; 64];
+            bvk.0
+                .write(&mut data_to_be_signed[0..32])
+                .expect("message buffer should be 32 bytes");
+            (&mut data_to_be_signed[32..64]).copy_from_slice(&sighash[..]);
+            if !bvk.verify(
+                &data_to_be_signed,
+                &binding_sig,
+                FixedGenerators::ValueCommitmentRandomness,
+                params,
+            ) {
+                return false;
+            }
+        }
+
+        if self.perform_verification {
+            for (zkproof, public_input) in spends {
+                self.spends.queue(zkproof, public_input);
+                self.spend_txids.push(tx.hash());
+            }
+            for (zkproof, public_input) in outputs {
+                self.outputs.queue(zkproof, public_input);
+                self.output_txids.push(tx.hash());
+            }
+        }
+        true
+    }
+
+    //Verifies every queued proof and empties the queue. If the batch doesn't hold, the proofs
+    //are checked one by one to return the txid of the first transaction with an invalid proof.
+    pub fn verify(&mut self) -> Result<(), FrHash> {
+        if self.spends.is_empty() && self.outputs.is_empty() {
+            return Ok(());
+        }
+        let result = if self.spends.verify(&SPEND_VERIFYING_KEY)
+            && self.outputs.verify(&OUTPUT_VERIFYING_KEY)
+        {
+            Ok(())
+        } else if let Some(i) = self.spends.find_invalid(&SPEND_VK) {
+            Err(self.spend_txids[i])
+        } else if let Some(i) = self.outputs.find_invalid(&OUTPUT_VK) {
+            Err(self.output_txids[i])
+        } else {
+            // Every single proof is fine, the batch only failed by chance.
+            Ok(())
+        };
+
+        self.spends.clear();
+        self.outputs.clear();
+        self.spend_txids.clear();
+        self.output_txids.clear();
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bellman::groth16::{
+        create_random_proof, generate_random_parameters, prepare_verifying_key,
+    };
+    use bellman::{Circuit, ConstraintSystem, SynthesisError};
+
+    // Proves knowledge of a and b with a * b = c for a public c.
+    struct MulCircuit {
+        a: Option<Fr>,
+        b: Option<Fr>,
+    }
+
+    impl Circuit<Bls12> for MulCircuit {
+        fn synthesize<CS: ConstraintSystem<Bls12>>(
+            self,
+            cs: &mut CS,
+        ) -> Result<(), SynthesisError> {
+            let a = cs.alloc(|| "a", || self.a.ok_or(SynthesisError::AssignmentMissing))?;
+            let b = cs.alloc(|| "b", || self.b.ok_or(SynthesisError::AssignmentMissing))?;
+            let c = cs.alloc_input(
+                || "c",
+                || {
+                    let mut c = self.a.ok_or(SynthesisError::AssignmentMissing)?;
+                    c.mul_assign(&self.b.ok_or(SynthesisError::AssignmentMissing)?);
+                    Ok(c)
+                },
+            )?;
+            cs.enforce(|| "a * b = c", |lc| lc + a, |lc| lc + b, |lc| lc + c);
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn batch_verification_finds_the_invalid_proof() {
+        let mut rng = OsRng::new().unwrap();
+        let params =
+            generate_random_parameters::<Bls12, _, _>(MulCircuit { a: None, b: None }, &mut rng)
+                .unwrap();
+        let pvk = prepare_verifying_key(&params.vk);
+
+        let mut batch = BatchVerifier::new();
+        for _ in 0..4 {
+            let a = Fr::rand(&mut rng);
+            let b = Fr::rand(&mut rng);
+            let mut c = a;
+            c.mul_assign(&b);
+            let circuit = MulCircuit {
+                a: Some(a),
+                b: Some(b),
+            };
+            let proof = create_random_proof(circuit, &params, &mut rng).unwrap();
+            batch.queue(proof, vec![c]);
+        }
+        assert_eq!(batch.len(), 4);
+        assert!(batch.verify(&params.vk));
+        assert_eq!(batch.find_invalid(&pvk), None);
+
+        // A proof for the wrong public input spoils the batch.
+        let proof = batch.items[1].0.clone();
+        batch.queue(proof, vec![Fr::one()]);
+        assert!(!batch.verify(&params.vk));
+        assert_eq!(batch.find_invalid(&pvk), Some(4));
+
+        batch.clear();
+        assert!(batch.verify(&params.vk));
+    }
+}
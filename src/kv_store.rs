@@ -0,0 +1,419 @@
+//! A minimal embedded key-value store.
+//!
+//! All entries are kept in memory and every change is appended to a log file as a
+//! checksummed batch. A batch is either replayed entirely on open or, if it was torn
+//! by a crash, discarded. Only the last batch can be torn, a damaged batch anywhere else
+//! is reported as corruption.
+
+use blake2_rfc::blake2b::Blake2b;
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use std::collections::hash_map::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufReader, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+use crate::incremental_tree::serialize::{Vector, MAX_SIZE};
+
+const CHECKSUM_PERSONALIZATION: &[u8; 16] = b"ice_KvChecksum__";
+
+pub const CHECKSUM_SIZE: usize = 4;
+
+// Payload bytes per batch written by compaction, which keeps every batch readable
+const COMPACT_BATCH_SIZE: usize = 1 << 24;
+
+/// Returns the first bytes of a personalized BLAKE2b-256 digest of `data`.
+pub fn checksum(data: &[u8]) -> [u8; CHECKSUM_SIZE] {
+    let mut h = Blake2b::with_params(32, &[], &[], CHECKSUM_PERSONALIZATION);
+    h.update(data);
+    let mut ret = [0u8; CHECKSUM_SIZE];
+    ret.copy_from_slice(&h.finalize().as_bytes()[..CHECKSUM_SIZE]);
+    ret
+}
+
+const OP_PUT: u8 = 0;
+const OP_DELETE: u8 = 1;
+
+enum BatchOp {
+    Put(Vec<u8>, Vec<u8>),
+    Delete(Vec<u8>),
+}
+
+/// A set of writes that is applied atomically by [`KvStore::write_batch`].
+pub struct WriteBatch {
+    ops: Vec<BatchOp>,
+}
+
+impl WriteBatch {
+    pub fn new() -> Self {
+        WriteBatch { ops: Vec::new() }
+    }
+
+    pub fn put(&mut self, key: Vec<u8>, value: Vec<u8>) {
+        self.ops.push(BatchOp::Put(key, value));
+    }
+
+    pub fn delete(&mut self, key: Vec<u8>) {
+        self.ops.push(BatchOp::Delete(key));
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ops.is_empty()
+    }
+
+    fn read<R: Read>(mut reader: R) -> io::Result<Self> {
+        let ops = Vector::read(&mut reader, |r| {
+            let op = r.read_u8()?;
+            let key = Vector::read(&mut *r, |r| r.read_u8())?;
+            match op {
+                OP_PUT => {
+                    let value = Vector::read(&mut *r, |r| r.read_u8())?;
+                    Ok(BatchOp::Put(key, value))
+                }
+                OP_DELETE => Ok(BatchOp::Delete(key)),
+                _ => Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "unknown batch operation",
+                )),
+            }
+        })?;
+        Ok(WriteBatch { ops })
+    }
+
+    fn write<W: Write>(&self, mut writer: W) -> io::Result<()> {
+        Vector::write(&mut writer, &self.ops, |w, op| match op {
+            BatchOp::Put(key, value) => {
+                w.write_u8(OP_PUT)?;
+                Vector::write(&mut *w, key, |w, b| w.write_u8(*b))?;
+                Vector::write(&mut *w, value, |w, b| w.write_u8(*b))
+            }
+            BatchOp::Delete(key) => {
+                w.write_u8(OP_DELETE)?;
+                Vector::write(&mut *w, key, |w, b| w.write_u8(*b))
+            }
+        })
+    }
+}
+
+pub struct KvStore {
+    path: Option<PathBuf>,
+    file: Option<File>,
+    map: HashMap<Vec<u8>, Vec<u8>>,
+    // Current length of the log and its length when it was last opened or compacted
+    log_size: u64,
+    compacted_size: u64,
+}
+
+impl KvStore {
+    /// Creates a store that is never written to disk.
+    pub fn memory() -> Self {
+        KvStore {
+            path: None,
+            file: None,
+            map: HashMap::new(),
+            log_size: 0,
+            compacted_size: 0,
+        }
+    }
+
+    /// Opens the store at `path`, creating it if it does not exist.
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .open(&path)?;
+
+        let file_len = file.metadata()?.len();
+        let mut map = HashMap::new();
+        let mut valid_len = 0u64;
+        {
+            let mut reader = BufReader::new(&mut file);
+            while let Some((batch, len)) = read_record(&mut reader, file_len - valid_len)? {
+                apply(&mut map, batch);
+                valid_len += len;
+            }
+        }
+
+        // Drop a torn batch left behind by an interrupted write.
+        file.set_len(valid_len)?;
+        file.seek(SeekFrom::End(0))?;
+
+        Ok(KvStore {
+            path: Some(path),
+            file: Some(file),
+            map,
+            log_size: valid_len,
+            compacted_size: valid_len,
+        })
+    }
+
+    pub fn get(&self, key: &[u8]) -> Option<&[u8]> {
+        self.map.get(key).map(|v| &v[..])
+    }
+
+    pub fn contains_key(&self, key: &[u8]) -> bool {
+        self.map.contains_key(key)
+    }
+
+    pub fn len(&self) -> usize {
+        self.map.len()
+    }
+
+    /// Bytes currently used by the log on disk.
+    pub fn log_size(&self) -> u64 {
+        self.log_size
+    }
+
+    /// Bytes the log used right after it was last opened or compacted.
+    pub fn compacted_size(&self) -> u64 {
+        self.compacted_size
+    }
+
+    /// Applies all writes in `batch`, syncing them to disk first.
+    pub fn write_batch(&mut self, batch: WriteBatch) -> io::Result<()> {
+        if batch.is_empty() {
+            return Ok(());
+        }
+        if let Some(file) = self.file.as_mut() {
+            let record = encode_record(&batch)?;
+            if let Err(e) = file.write_all(&record).and_then(|()| file.sync_data()) {
+                // Cut off what was written of the record, so the next batch doesn't follow
+                // a torn one.
+                file.set_len(self.log_size)?;
+                file.seek(SeekFrom::End(0))?;
+                return Err(e);
+            }
+            self.log_size += record.len() as u64;
+        }
+        apply(&mut self.map, batch);
+        Ok(())
+    }
+
+    /// Rewrites the log so it holds the live entries only, in batches of bounded size.
+    pub fn compact(&mut self) -> io::Result<()> {
+        let path = match self.path {
+            Some(ref path) => path.clone(),
+            None => return Ok(()),
+        };
+
+        let tmp_path = path.with_extension("tmp");
+        let mut log_size = 0;
+        {
+            let mut tmp = File::create(&tmp_path)?;
+            let mut batch = WriteBatch::new();
+            let mut batch_size = 0;
+            let mut entries = self.map.iter().peekable();
+            while let Some((key, value)) = entries.next() {
+                batch_size += key.len() + value.len();
+                batch.put(key.clone(), value.clone());
+                if batch_size >= COMPACT_BATCH_SIZE || entries.peek().is_none() {
+                    let record = encode_record(&batch)?;
+                    tmp.write_all(&record)?;
+                    log_size += record.len() as u64;
+                    batch = WriteBatch::new();
+                    batch_size = 0;
+                }
+            }
+            tmp.sync_all()?;
+        }
+        fs::rename(&tmp_path, &path)?;
+
+        let mut file = OpenOptions::new().read(true).write(true).open(&path)?;
+        file.seek(SeekFrom::End(0))?;
+        self.file = Some(file);
+        self.log_size = log_size;
+        self.compacted_size = log_size;
+        Ok(())
+    }
+}
+
+// Record layout: payload length (u32), payload, checksum of the payload.
+fn encode_record(batch: &WriteBatch) -> io::Result<Vec<u8>> {
+    if batch.ops.len() > MAX_SIZE {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "too many operations in a batch",
+        ));
+    }
+    let mut payload = Vec::new();
+    batch.write(&mut payload)?;
+    if payload.len() > u32::max_value() as usize {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "batch too large for a record",
+        ));
+    }
+
+    let mut record = Vec::with_capacity(payload.len() + 4 + CHECKSUM_SIZE);
+    record.write_u32::<LittleEndian>(payload.len() as u32)?;
+    record.extend_from_slice(&payload);
+    record.extend_from_slice(&checksum(&payload));
+    Ok(record)
+}
+
+// `remaining` is the number of bytes left in the log. Returns None at the end of the log
+// or for a torn record, one that runs up to or beyond the end of the log and doesn't
+// check out. A bad record with more data after it is corruption.
+fn read_record<R: Read>(mut reader: R, remaining: u64) -> io::Result<Option<(WriteBatch, u64)>> {
+    if remaining < 4 {
+        return Ok(None);
+    }
+    let len = reader.read_u32::<LittleEndian>()? as u64;
+    let record_len = 4 + len + CHECKSUM_SIZE as u64;
+    if record_len > remaining {
+        return Ok(None);
+    }
+    let mut payload = vec![0u8; len as usize];
+    reader.read_exact(&mut payload)?;
+    let mut sum = [0u8; CHECKSUM_SIZE];
+    reader.read_exact(&mut sum)?;
+    if sum != checksum(&payload) {
+        if record_len == remaining {
+            return Ok(None);
+        }
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "corrupted batch in the log",
+        ));
+    }
+    let batch = WriteBatch::read(&payload[..])?;
+    Ok(Some((batch, record_len)))
+}
+
+fn apply(map: &mut HashMap<Vec<u8>, Vec<u8>>, batch: WriteBatch) {
+    for op in batch.ops {
+        match op {
+            BatchOp::Put(key, value) => {
+                map.insert(key, value);
+            }
+            BatchOp::Delete(key) => {
+                map.remove(&key);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use std::env;
+    use std::process;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    // A fresh directory for one test, tests running in parallel never share files.
+    pub(crate) fn temp_dir(name: &str) -> PathBuf {
+        static N_DIRS: AtomicUsize = AtomicUsize::new(0);
+        let dir = env::temp_dir().join(format!(
+            "{}_{}_{}",
+            name,
+            process::id(),
+            N_DIRS.fetch_add(1, Ordering::SeqCst)
+        ));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn batches_survive_reopen() {
+        let dir = temp_dir("ice_kv_store_reopen");
+        let path = dir.join("store.db");
+        {
+            let mut store = KvStore::open(&path).unwrap();
+            let mut batch = WriteBatch::new();
+            batch.put(b"a".to_vec(), b"1".to_vec());
+            batch.put(b"b".to_vec(), b"2".to_vec());
+            store.write_batch(batch).unwrap();
+
+            let mut batch = WriteBatch::new();
+            batch.delete(b"a".to_vec());
+            batch.put(b"c".to_vec(), vec![3; 300]);
+            store.write_batch(batch).unwrap();
+        }
+
+        let mut store = KvStore::open(&path).unwrap();
+        assert_eq!(store.get(b"a"), None);
+        assert_eq!(store.get(b"b"), Some(&b"2"[..]));
+        assert_eq!(store.get(b"c"), Some(&[3; 300][..]));
+
+        let old_size = store.log_size();
+        store.compact().unwrap();
+        assert!(store.log_size() < old_size);
+        assert_eq!(store.log_size(), fs::metadata(&path).unwrap().len());
+        let store = KvStore::open(&path).unwrap();
+        assert_eq!(store.len(), 2);
+        assert_eq!(store.get(b"b"), Some(&b"2"[..]));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn torn_batch_is_discarded() {
+        let dir = temp_dir("ice_kv_store_torn");
+        let path = dir.join("store.db");
+        {
+            let mut store = KvStore::open(&path).unwrap();
+            let mut batch = WriteBatch::new();
+            batch.put(b"a".to_vec(), b"1".to_vec());
+            store.write_batch(batch).unwrap();
+        }
+        let good_len = fs::metadata(&path).unwrap().len();
+        {
+            let mut batch = WriteBatch::new();
+            batch.put(b"a".to_vec(), b"2".to_vec());
+            let record = encode_record(&batch).unwrap();
+            let mut file = OpenOptions::new().append(true).open(&path).unwrap();
+            file.write_all(&record[..record.len() - 1]).unwrap();
+        }
+
+        let store = KvStore::open(&path).unwrap();
+        assert_eq!(store.get(b"a"), Some(&b"1"[..]));
+        assert_eq!(fs::metadata(&path).unwrap().len(), good_len);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn oversized_length_is_discarded() {
+        let dir = temp_dir("ice_kv_store_oversized");
+        let path = dir.join("store.db");
+        {
+            let mut store = KvStore::open(&path).unwrap();
+            let mut batch = WriteBatch::new();
+            batch.put(b"a".to_vec(), b"1".to_vec());
+            store.write_batch(batch).unwrap();
+        }
+        let good_len = fs::metadata(&path).unwrap().len();
+        {
+            let mut file = OpenOptions::new().append(true).open(&path).unwrap();
+            file.write_u32::<LittleEndian>(u32::max_value()).unwrap();
+        }
+
+        let store = KvStore::open(&path).unwrap();
+        assert_eq!(store.get(b"a"), Some(&b"1"[..]));
+        assert_eq!(store.log_size(), good_len);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn damaged_batch_before_the_end_is_corruption() {
+        let dir = temp_dir("ice_kv_store_corrupt");
+        let path = dir.join("store.db");
+        {
+            let mut store = KvStore::open(&path).unwrap();
+            for value in [b"1", b"2"].iter() {
+                let mut batch = WriteBatch::new();
+                batch.put(b"a".to_vec(), value.to_vec());
+                store.write_batch(batch).unwrap();
+            }
+        }
+        let mut data = fs::read(&path).unwrap();
+        data[5] ^= 1;
+        fs::write(&path, &data).unwrap();
+
+        let e = KvStore::open(&path).err().unwrap();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+        // Nothing was truncated
+        assert_eq!(fs::read(&path).unwrap(), data);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
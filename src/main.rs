@@ -1,17 +1,21 @@
 mod amount;
 mod block_chain;
+mod block_store;
 mod coins;
 mod incremental_tree;
 mod key;
+mod kv_store;
 mod main_impl;
 mod my;
 mod other;
+mod pow;
 mod script;
 mod sendmany;
 mod transaction;
 mod transaction_builder;
 mod txmempool;
 mod wallet;
+mod work_queue;
 mod zkp;
 
 #[macro_use]
@@ -27,7 +31,7 @@ use std::thread;
 use crate::sendmany::SendMany;
 
 use crate::block_chain::Chain;
-use crate::coins::CoinViewCache;
+use crate::coins::{CoinViewCache, CoinViewDB};
 use crate::key::key_store::KeyStore;
 use crate::other::sanity_check::SanityChecker;
 use crate::wallet::Wallet;
@@ -40,14 +44,17 @@ fn main() {
 
     thread::spawn(move || {
         let chain_active = Chain::new();
-        let mut pcoins_tip = CoinViewCache::new();
-        let wallet = Wallet::new(&mut pcoins_tip, &chain_active);
+        block_store::open_block_files(".").expect("failed to open block files");
+        let coins_db = CoinViewDB::open("chainstate.db").expect("failed to open coins database");
+        let pcoins_tip = CoinViewCache::with_base(coins_db);
+        let wallet = Wallet::new();
 
         let sanity_checker = SanityChecker::new();
         let key_store = KeyStore::new();
 
         let sender = SendMany {
             main_wallet: &wallet,
+            chain_active: &chain_active,
             sanity_checker: sanity_checker,
             key_store: key_store,
         };
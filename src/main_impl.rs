@@ -1,108 +1,437 @@
 //Functions and Operation that related to chain operation
 
+use crate::amount::{money_range, MAX_MONEY};
 use crate::block_chain::{
-    Block, BlockIndex, BlockUndo, BlockUndoView, Chain, DiskBlockPos, TxInUndo, TxUndo,
-    ValidationState,
+    Block, BlockHeader, BlockIndex, BlockMap, BlockUndo, Chain, DiskBlockPos, TxInUndo, TxUndo,
+    ValidationState, BLOCK_FAILED_CHILD, BLOCK_FAILED_VALID, BLOCK_HAVE_DATA, BLOCK_HAVE_UNDO,
+    BLOCK_VALID_SCRIPTS, BLOCK_VALID_TRANSACTIONS, BLOCK_VALID_TREE, CURRENT_BLOCK_VERSION,
 };
+use crate::block_store::with_block_files;
 use crate::coins::{CoinViewCache, Coins, CoinsView};
+use crate::incremental_tree::tree::SaplingMerkleTree;
 use crate::key::key_management::FrHash;
 use crate::key::proof::ProofVerifier;
-use crate::sendmany::SaplingOutPoint;
-use crate::transaction::Transaction;
-use crate::txmempool::{TxMemPool, TxMemPoolEntry};
+use crate::my::constants::{
+    COINBASE_MATURITY, MAX_BLOCK_SIZE, MAX_BLOCK_SUBSIDY, MAX_REORG_LENGTH,
+    SUBSIDY_HALVING_INTERVAL, TX_EXPIRING_SOON_THRESHOLD,
+};
+use crate::pow::{check_proof_of_work, get_block_proof, get_next_work_required};
+use crate::script::{
+    verify_script, Script, LOCKTIME_THRESHOLD, MANDATORY_SCRIPT_VERIFY_FLAGS,
+    STANDARD_SCRIPT_VERIFY_FLAGS,
+};
+use crate::sendmany::{CAmount, SaplingOutPoint};
+use crate::transaction::{MutableTransaction, Transaction, TxOut, TX_EXPIRY_HEIGHT_THRESHOLD};
+use crate::txmempool::{CoinViewMemPool, TxMemPool, TxMemPoolEntry};
 use crate::wallet::Wallet;
-use crate::zkp::{OUTPUT_VK, SPEND_VK};
+use crate::work_queue::{Job, VERIFY_QUEUE};
 
-use bellman::groth16::Proof;
+use blake2_rfc::blake2b::Blake2b;
+use byteorder::{LittleEndian, WriteBytesExt};
 use ethereum_types::U256;
-use pairing::bls12_381::Bls12;
-use sapling_crypto::redjubjub::Signature;
+use ff::{PrimeField, PrimeFieldRepr};
 use std::collections::hash_set::HashSet;
-use zcash_primitives::JUBJUB;
-use zcash_proofs::sapling::SaplingVerificationContext;
+use std::collections::VecDeque;
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
 
 //bool ReadBlockFromDisk(CBlock& block, const CBlockIndex* pindex)
 
 pub fn read_block_from_disk(pindex: &BlockIndex) -> Option<Block> {
-    unimplemented!()
+    let pos = pindex.get_block_pos();
+    match with_block_files(|store| store.read_block(&pos)) {
+        Ok(block) => Some(block),
+        Err(e) => {
+            error!(
+                "ReadBlockFromDisk(): failed to read block at {:?}: {}",
+                pos, e
+            );
+            None
+        }
+    }
+}
+
+//bool WriteBlockToDisk(CBlock& block, CDiskBlockPos& pos, const CMessageHeader::MessageStartChars& messageStart)
+pub fn write_block_to_disk(block: &Block) -> Option<DiskBlockPos> {
+    match with_block_files(|store| store.write_block(block)) {
+        Ok(pos) => Some(pos),
+        Err(e) => {
+            error!("WriteBlockToDisk(): {}", e);
+            None
+        }
+    }
+}
+
+/**
+ * Return the tip of the chain with the most work in it, that isn't
+ * known to be invalid (it's however far from certain to be valid).
+ */
+//static CBlockIndex* FindMostWorkChain() {
+pub fn find_most_work_chain(map_block_index: &mut BlockMap, chain_active: &Chain) -> Option<U256> {
+    let tip_work = chain_active.tip().map(|tip| tip.n_chain_work);
+    let mut candidates = map_block_index
+        .values()
+        .filter(|pindex| tip_work.map_or(true, |work| pindex.n_chain_work > work))
+        .map(|pindex| (pindex.n_chain_work, pindex.get_block_hash()))
+        .collect::<Vec<_>>();
+    candidates.sort_by(|a, b| b.cmp(a));
+
+    for (_, hash_candidate) in candidates {
+        // Check whether all blocks on the path between the currently active chain and the candidate are valid.
+        // Just going until the active chain is an optimization, as we know all blocks in it are valid already.
+        let mut hash_test = Some(hash_candidate);
+        let mut hash_failed = None;
+        while let Some(hash) = hash_test {
+            let pindex_test = match map_block_index.get(&hash) {
+                Some(pindex) => pindex,
+                None => {
+                    hash_failed = Some(hash);
+                    break;
+                }
+            };
+            if chain_active.contains(pindex_test) {
+                break;
+            }
+            if pindex_test.is_failed() || pindex_test.n_status & BLOCK_HAVE_DATA == 0 {
+                hash_failed = Some(hash);
+                break;
+            }
+            hash_test = if pindex_test.nHeight > 0 {
+                Some(pindex_test.hash_prev_block)
+            } else {
+                None
+            };
+        }
+
+        match hash_failed {
+            None => return Some(hash_candidate),
+            // Candidate chain is not usable (either invalid or missing data)
+            Some(hash) => {
+                let f_failed_chain = map_block_index.get(&hash).map_or(false, |p| p.is_failed());
+                if f_failed_chain && hash != hash_candidate {
+                    map_block_index.get_mut(&hash_candidate).unwrap().n_status |=
+                        BLOCK_FAILED_CHILD;
+                }
+            }
+        }
+    }
+    None
 }
 
 /**
  * Make the best chain active, in multiple steps. The result is either failure
- * or an activated best chain. pblock is either NULL or a pointer to a block
- * that is already loaded (to avoid loading it again from disk).
+ * or an activated best chain.
  */
 //bool ActivateBestChain(CValidationState &state, CBlock *pblock) {
-pub fn active_best_chain() {}
+//Blocks are read back from disk, so only entries stored by accept_block (BLOCK_HAVE_DATA)
+//can become part of the active chain.
+pub fn active_best_chain(
+    chain_active: &mut Chain,
+    map_block_index: &mut BlockMap,
+    pcoins_tip: &mut CoinViewCache,
+    wallet: &mut Wallet,
+    mempool: &TxMemPool,
+    state: &ValidationState,
+) -> bool {
+    loop {
+        let hash_most_work = match find_most_work_chain(map_block_index, chain_active) {
+            Some(hash) => hash,
+            // Whether we have anything to do at all.
+            None => return true,
+        };
+
+        if !active_best_chain_step(
+            chain_active,
+            map_block_index,
+            pcoins_tip,
+            wallet,
+            mempool,
+            state,
+            hash_most_work,
+        ) {
+            return false;
+        }
+    }
+}
 
 /**
  * Try to make some progress towards making pindexMostWork the active block.
- * pblock is either NULL or a pointer to a CBlock corresponding to pindexMostWork.
  */
 //static bool ActivateBestChainStep(CValidationState &state, CBlockIndex *pindexMostWork, CBlock *pblock) {
-pub fn active_best_chain_step() {
-    //call connect_tip
+//If a block of the new branch turns out to be invalid it is marked as failed, the old branch
+//is reconnected and true is returned, so the caller can move on to the next best chain.
+pub fn active_best_chain_step(
+    chain_active: &mut Chain,
+    map_block_index: &mut BlockMap,
+    pcoins_tip: &mut CoinViewCache,
+    wallet: &mut Wallet,
+    mempool: &TxMemPool,
+    state: &ValidationState,
+    hash_most_work: U256,
+) -> bool {
+    let pindex_most_work = match map_block_index.get(&hash_most_work) {
+        Some(pindex) => pindex.clone(),
+        None => {
+            error!("ActivateBestChainStep(): unknown block {}", hash_most_work);
+            return false;
+        }
+    };
+    let n_fork_height = chain_active
+        .find_fork(map_block_index, &pindex_most_work)
+        .map_or(-1, |pindex_fork| pindex_fork.nHeight);
+
+    let n_reorg_length = chain_active.height() - n_fork_height;
+    if n_reorg_length > MAX_REORG_LENGTH as i32 {
+        error!(
+            "ActivateBestChainStep(): a reorganization of {} blocks exceeds the maximum of {}",
+            n_reorg_length, MAX_REORG_LENGTH
+        );
+        return false;
+    }
+
+    // Disconnect active blocks which are no longer in the best chain.
+    let f_blocks_disconnected = chain_active.height() > n_fork_height;
+    let mut v_disconnected = Vec::new();
+    while chain_active.height() > n_fork_height {
+        let pindex_old = chain_active.tip().unwrap().clone();
+        if !disconnect_tip(chain_active, pcoins_tip, wallet, mempool, state, false) {
+            return false;
+        }
+        v_disconnected.push(pindex_old);
+    }
+
+    // Build list of new blocks to connect.
+    let mut v_to_connect = Vec::new();
+    for n_height in (n_fork_height + 1)..=pindex_most_work.nHeight {
+        let hash = pindex_most_work
+            .get_ancestor(map_block_index, n_height)
+            .unwrap()
+            .get_block_hash();
+        v_to_connect.push(hash);
+    }
+
+    // Connect new blocks.
+    for hash in v_to_connect {
+        let pindex_connect = map_block_index.get(&hash).unwrap().clone();
+        let block = match read_block_from_disk(&pindex_connect) {
+            Some(block) => block,
+            None => {
+                error!("ActivateBestChainStep(): failed to read block {}", hash);
+                return state.error();
+            }
+        };
+        if connect_tip(
+            chain_active,
+            pcoins_tip,
+            wallet,
+            mempool,
+            state,
+            pindex_connect,
+            &block,
+        ) {
+            // Keep the status and disk positions recorded while connecting.
+            map_block_index.insert(hash, chain_active.tip().unwrap().clone());
+            continue;
+        }
+        if state.is_error() {
+            // A disk error says nothing about the block, it may be connected later.
+            error!("ActivateBestChainStep(): failed to connect block {}", hash);
+            return false;
+        }
+
+        // The block is invalid, give up on it and everything built on top of it.
+        map_block_index.get_mut(&hash).unwrap().n_status |= BLOCK_FAILED_VALID;
+
+        // Go back to the branch we were on before.
+        while chain_active.height() > n_fork_height {
+            if !disconnect_tip(chain_active, pcoins_tip, wallet, mempool, state, false) {
+                return false;
+            }
+        }
+        for pindex_old in v_disconnected.into_iter().rev() {
+            let block = match read_block_from_disk(&pindex_old) {
+                Some(block) => block,
+                None => return state.error(),
+            };
+            if !connect_tip(
+                chain_active,
+                pcoins_tip,
+                wallet,
+                mempool,
+                state,
+                pindex_old,
+                &block,
+            ) {
+                error!("ActivateBestChainStep(): failed to reconnect the previous best chain");
+                return false;
+            }
+        }
+        break;
+    }
+
+    if f_blocks_disconnected {
+        let mut removed = VecDeque::new();
+        mempool
+            .lock()
+            .remove_for_reorg(pcoins_tip, chain_active, &mut removed);
+        for tx in removed.iter() {
+            wallet.sync_transaction(tx, None);
+        }
+    }
+
+    true
 }
 
 //bool static ConnectTip(CValidationState &state, CBlockIndex *pindexNew, CBlock *pblock)
 pub fn connect_tip(
+    chain_active: &mut Chain,
     pcoins_tip: &mut CoinViewCache,
-    block_undo_view: &mut BlockUndoView,
     wallet: &mut Wallet,
+    mempool: &TxMemPool,
     state: &ValidationState,
-    pindex_new: &BlockIndex,
+    mut pindex_new: BlockIndex,
     pblock: &Block,
-) {
-    //call connect_block
-    //call wallet.chain_tip
+) -> bool {
+    assert!(pindex_new.nHeight == chain_active.height() + 1);
 
     //SproutMerkleTree oldSproutTree;
     //SaplingMerkleTree oldSaplingTree;
     //assert(pcoinsTip->GetSproutAnchorAt(pcoinsTip->GetBestAnchor(SPROUT), oldSproutTree));
     //assert(pcoinsTip->GetSaplingAnchorAt(pcoinsTip->GetBestAnchor(SAPLING), oldSaplingTree));
-    //let old_sapling_tree
-    //    = pcoins_tip.get_sapling_anchor_at(pcoins_tip.get_best_anchor());
-    let old_sapling_tree = pcoins_tip
+    let mut old_sapling_tree = pcoins_tip
         .get_best_anchor()
-        .and_then(|anchor| pcoins_tip.get_sapling_anchor_at(anchor));
-    connect_block(
-        pblock,
-        state,
-        pindex_new,
-        pcoins_tip,
-        block_undo_view,
-        false,
-    );
+        .and_then(|anchor| pcoins_tip.get_sapling_anchor_at(anchor))
+        .expect("the best anchor should be in the coins view");
+    if !connect_block(pblock, state, &mut pindex_new, pcoins_tip, false) {
+        error!(
+            "ConnectTip(): ConnectBlock {} failed",
+            pindex_new.get_block_hash()
+        );
+        return false;
+    }
+
+    // Remove conflicting transactions from the mempool.
+    let mut tx_conflicted = VecDeque::new();
+    let mut tx_expired = VecDeque::new();
+    {
+        let mut pool = mempool.lock();
+        pool.remove_for_block(
+            &pblock.vtx,
+            pindex_new.nHeight as u32,
+            &mut tx_conflicted,
+            true,
+        );
+        // Remove transactions that expire at the new height
+        pool.remove_expired(pindex_new.nHeight, &mut tx_expired);
+    }
+    if !tx_expired.is_empty() {
+        debug!(
+            "ConnectTip(): removed {} expired transactions from the mempool",
+            tx_expired.len()
+        );
+    }
+
+    // Tell wallet about transactions that went from mempool to conflicted:
+    for tx in tx_conflicted.iter() {
+        wallet.sync_transaction(tx, None);
+    }
+    // ... and about transactions that got confirmed:
+    for tx in pblock.vtx.iter() {
+        wallet.sync_transaction(tx, Some(pblock));
+    }
+    // Update cached incremental witnesses
+    wallet.chain_tip(&pindex_new, pblock, &mut old_sapling_tree, true);
 
-    wallet.chain_tip(pindex_new, pblock, &mut old_sapling_tree.unwrap(), true);
+    chain_active.v_chain.push(pindex_new);
+    true
 }
 
 /**
- * Disconnect chainActive's tip. You probably want to call mempool.removeForReorg and
- * mempool.removeWithoutBranchId after this, with cs_main held.
+ * Disconnect chainActive's tip. You probably want to call mempool.removeForReorg after
+ * this, see active_best_chain_step.
  */
 //bool static DisconnectTip(CValidationState &state, bool fBare = false) {
 pub fn disconnect_tip(
-    chain_active: &Chain,
+    chain_active: &mut Chain,
     pcoins_tip: &mut CoinViewCache,
-    block_undo_view: &mut BlockUndoView,
+    wallet: &mut Wallet,
+    mempool: &TxMemPool,
     state: &ValidationState,
     f_bare: bool,
-) {
-    let pindex_delete = chain_active.tip();
-    //need_integrate
-    let block = pindex_delete.and_then(|pindex| read_block_from_disk(pindex));
-    let sapling_anchor_before_disconnect = pcoins_tip.get_best_anchor();
-    if !disconnect_block(
-        &block.unwrap(),
-        state,
-        &pindex_delete.unwrap(),
-        pcoins_tip,
-        block_undo_view,
-    ) {
-        //Report error here
-        return;
+) -> bool {
+    let pindex_delete = match chain_active.tip() {
+        Some(pindex) => pindex.clone(),
+        None => return false,
+    };
+    let block = match read_block_from_disk(&pindex_delete) {
+        Some(block) => block,
+        None => {
+            error!("DisconnectTip(): failed to read block");
+            return state.error();
+        }
+    };
+    let sapling_anchor_before_disconnect = pcoins_tip
+        .get_best_anchor()
+        .expect("the coins view should have a best anchor");
+    let mut sapling_tree_before_disconnect = pcoins_tip
+        .get_sapling_anchor_at(sapling_anchor_before_disconnect)
+        .expect("the best anchor should be in the coins view");
+    let pindex_prev = chain_active.get(pindex_delete.nHeight - 1);
+    if !disconnect_block(&block, state, &pindex_delete, pindex_prev, pcoins_tip) {
+        error!(
+            "DisconnectTip(): DisconnectBlock {} failed",
+            pindex_delete.get_block_hash()
+        );
+        return false;
     }
+
+    let sapling_anchor_after_disconnect = pcoins_tip.get_best_anchor();
+
+    // Update chainActive and related variables, the resurrected transactions are checked
+    // against the new tip.
+    chain_active.v_chain.pop();
+
+    if !f_bare {
+        // Resurrect mempool transactions from the disconnected block.
+        for tx in block.vtx.iter() {
+            // ignore validation errors in resurrected transactions
+            let mut removed = VecDeque::new();
+            if tx.is_coin_base() {
+                mempool.lock().remove(tx, &mut removed, true);
+                continue;
+            }
+            let ptx = Arc::new(tx.clone());
+            if !accept_to_mem_pool(mempool, state, ptx, pcoins_tip, chain_active) {
+                mempool.lock().remove(tx, &mut removed, true);
+            }
+        }
+        if sapling_anchor_after_disconnect != Some(sapling_anchor_before_disconnect) {
+            // The anchor may not change between block disconnects,
+            // in which case we don't want to evict from the mempool yet!
+            let mut removed = VecDeque::new();
+            mempool
+                .lock()
+                .remove_with_anchor(sapling_anchor_before_disconnect, &mut removed);
+            for tx in removed.iter() {
+                wallet.sync_transaction(tx, None);
+            }
+        }
+    }
+
+    // Let wallets know transactions went from 1-confirmed to
+    // 0-confirmed or conflicted:
+    for tx in block.vtx.iter() {
+        wallet.sync_transaction(tx, None);
+    }
+    // Update cached incremental witnesses
+    wallet.chain_tip(
+        &pindex_delete,
+        &block,
+        &mut sapling_tree_before_disconnect,
+        false,
+    );
+
+    true
 }
 
 //TODO
@@ -110,70 +439,100 @@ pub fn disconnect_block(
     block: &Block,
     state: &ValidationState,
     pindex: &BlockIndex,
+    pindex_prev: Option<&BlockIndex>,
     view: &mut CoinViewCache,
-    block_undo_view: &mut BlockUndoView,
 ) -> bool {
     assert!(pindex.get_block_hash() == view.get_best_block());
     let mut f_clean = true;
 
-    /*
     let pos = pindex.get_undo_pos();
-    let hash = pindex.pprev.as_ref().unwrap().get_block_hash();
-    let block_undo = undo_read_from_disk(pos, hash);
-    */
-    let block_hash = pindex.get_block_hash();
-    let block_undo_op = block_undo_view.block_undos.get(&block_hash);
-    let block_undo = block_undo_op.unwrap();
+    if pos.is_null() {
+        error!("DisconnectBlock(): no undo data available");
+        return false;
+    }
+    let block_undo = match undo_read_from_disk(pos, pindex.hash_prev_block) {
+        Some(block_undo) => block_undo,
+        None => {
+            error!("DisconnectBlock(): failure reading undo data");
+            return state.error();
+        }
+    };
 
-    assert!(block_undo.vtxundo.len() + 1 == block.vtx.len());
+    if block_undo.vtxundo.len() + 1 != block.vtx.len() {
+        error!("DisconnectBlock(): block and undo data inconsistent");
+        return false;
+    }
 
     for i in (0..block.vtx.len()).rev() {
-        let tx = &block.vtx[i];
-        let hash = tx.hash;
-
-        {
-            let outs_op = view.modify_coins(hash);
-            if let Some(mut outs) = outs_op {
-                outs.clear_unspendable();
-                outs.clear();
-            }
-
-            //let outs_block = Coins::new();
-            //outs.and_then(|outs| {outs.clear(); None});
+        // The coinbase has no undo data
+        let tx_undo = if i > 0 {
+            Some(&block_undo.vtxundo[i - 1])
+        } else {
+            None
+        };
+        if !revert_transaction(&block.vtx[i], tx_undo, view) {
+            f_clean = false;
         }
+    }
 
-        view.set_nullifiers(&tx, false);
+    // Disconnecting the genesis block leaves an empty view.
+    view.pop_anchor(
+        pindex_prev
+            .map(|pprev| pprev.hash_final_sapling_root)
+            .unwrap_or_else(SaplingMerkleTree::empty_root),
+    );
 
-        //restore inputs
-        if i > 0 {
-            // not coinbases
-            let tx_undo = &block_undo.vtxundo[i - 1];
-            if tx_undo.vprevout.len() != tx.vin.len() {
-                error!("DisconnectBlock(): transaction and undo data inconsistent");
-            }
-            for j in (0..tx.vin.len()).rev() {
-                let out = &tx.vin[j].prevout;
-                let undo = &tx_undo.vprevout[j];
-                if !apply_tx_in_undo(undo, view, out) {
-                    f_clean = false;
-                }
-            }
+    view.set_best_block(pindex.hash_prev_block);
+
+    f_clean
+}
+
+//Removes the outputs of tx from view and restores the coins it spent.
+fn revert_transaction(
+    tx: &Transaction,
+    tx_undo: Option<&TxUndo>,
+    view: &mut CoinViewCache,
+) -> bool {
+    let mut f_clean = true;
+    {
+        let outs_op = view.modify_coins(tx.hash());
+        if let Some(mut outs) = outs_op {
+            outs.clear_unspendable();
+            outs.clear();
         }
-    }
 
-    view.pop_anchor(pindex.pprev.as_ref().unwrap().hash_final_sapling_root);
+        //let outs_block = Coins::new();
+        //outs.and_then(|outs| {outs.clear(); None});
+    }
 
-    view.set_best_block(pindex.pprev.as_ref().unwrap().get_block_hash());
+    view.set_nullifiers(&tx, false);
 
+    //restore inputs
+    if let Some(tx_undo) = tx_undo {
+        if tx_undo.vprevout.len() != tx.vin.len() {
+            error!("DisconnectBlock(): transaction and undo data inconsistent");
+            return false;
+        }
+        for j in (0..tx.vin.len()).rev() {
+            let out = &tx.vin[j].prevout;
+            let undo = &tx_undo.vprevout[j];
+            if !apply_tx_in_undo(undo, view, out) {
+                f_clean = false;
+            }
+        }
+    }
     f_clean
 }
 
 pub fn apply_tx_in_undo(undo: &TxInUndo, view: &mut CoinViewCache, out: &SaplingOutPoint) -> bool {
     let mut f_clean = true;
 
-    let mut coins = view.modify_coins(out.hash).unwrap();
-    if undo.n_height != 0 {
-        // undo data cointains height: this is the last output of the prevout tx being spent
+    let mut coins = match view.modify_coins(out.hash) {
+        Some(coins) => coins,
+        None => return false,
+    };
+    if undo.f_last {
+        // this is the last output of the prevout tx being spent
         if !coins.is_pruned() {
             error!("undo data overwriting existing transaction");
             f_clean = false;
@@ -194,15 +553,41 @@ pub fn apply_tx_in_undo(undo: &TxInUndo, view: &mut CoinViewCache, out: &Sapling
         f_clean = false;
         return f_clean;
     }
-    /*if coins.entry.coins.vout.len() < out.n+1 {
-
-    }*/
-    coins.entry.coins.vout[out.n] = undo.txout;
+    // Spending pruned the trailing outputs, bring them back as spent ones
+    if coins.entry.coins.vout.len() < out.n + 1 {
+        coins.entry.coins.vout.resize(out.n + 1, TxOut::null());
+    }
+    coins.entry.coins.vout[out.n] = undo.txout.clone();
     f_clean
 }
 
-pub fn undo_read_from_disk(pos: DiskBlockPos, block_hash: U256) -> BlockUndo {
-    unimplemented!()
+//bool UndoReadFromDisk(CBlockUndo& blockundo, const CDiskBlockPos& pos, const uint256& hashBlock)
+pub fn undo_read_from_disk(pos: DiskBlockPos, block_hash: U256) -> Option<BlockUndo> {
+    match with_block_files(|store| store.read_undo(&pos, block_hash)) {
+        Ok(blockundo) => Some(blockundo),
+        Err(e) => {
+            error!(
+                "UndoReadFromDisk(): failed to read undo at {:?}: {}",
+                pos, e
+            );
+            None
+        }
+    }
+}
+
+//bool UndoWriteToDisk(const CBlockUndo& blockundo, CDiskBlockPos& pos, const uint256& hashBlock, ...)
+pub fn undo_write_to_disk(
+    blockundo: &BlockUndo,
+    n_file: i32,
+    block_hash: U256,
+) -> Option<DiskBlockPos> {
+    match with_block_files(|store| store.write_undo(n_file, blockundo, block_hash)) {
+        Ok(pos) => Some(pos),
+        Err(e) => {
+            error!("UndoWriteToDisk(): {}", e);
+            None
+        }
+    }
 }
 
 //bool ConnectBlock(const CBlock& block, CValidationState& state,
@@ -217,14 +602,15 @@ pub fn update_coins(tx: &Transaction, inputs: &mut CoinViewCache, n_height: i32)
                 let coins = &mut coins.entry.coins;
 
                 assert!(n_pos < coins.vout.len() && !coins.vout[n_pos].is_null());
-                txundo.vprevout.push(TxInUndo::new(coins.vout[n_pos]));
+                txundo
+                    .vprevout
+                    .push(TxInUndo::new(coins.vout[n_pos].clone()));
 
                 coins.spend(n_pos);
 
                 if coins.vout.len() == 0 {
                     if let Some(mut undo) = txundo.vprevout.last_mut() {
-                        undo.set_n_height(coins.n_height);
-                        undo.set_f_coin_base(coins.f_coin_base);
+                        undo.set_last(coins.f_coin_base, coins.n_height);
                     }
                 }
             }
@@ -235,7 +621,7 @@ pub fn update_coins(tx: &Transaction, inputs: &mut CoinViewCache, n_height: i32)
 
     //add outputs
 
-    inputs.modify_new_coins(tx.hash).and_then(|mut modifier| {
+    inputs.modify_new_coins(tx.hash()).and_then(|mut modifier| {
         modifier.from_tx(tx, n_height);
         Some(1)
     });
@@ -243,223 +629,980 @@ pub fn update_coins(tx: &Transaction, inputs: &mut CoinViewCache, n_height: i32)
     txundo
 }
 
+//Splits the transactions of block into one job per verification thread. Each job checks the
+//scripts of the transparent inputs against the outputs they spend, given per transaction in
+//spent_outputs, as well as the signatures and batch-verified proofs of the shielded parts.
+fn verification_jobs(block: &Block, spent_outputs: Vec<Vec<TxOut>>, n_threads: usize) -> Vec<Job> {
+    let checks: Vec<(Transaction, Vec<TxOut>)> = block
+        .vtx
+        .iter()
+        .zip(spent_outputs)
+        .filter(|(tx, spent)| tx.has_shielded() || !spent.is_empty())
+        .map(|(tx, spent)| (tx.clone(), spent))
+        .collect();
+    if checks.is_empty() {
+        return Vec::new();
+    }
+    let chunk_size = (checks.len() + n_threads - 1) / n_threads;
+    checks
+        .chunks(chunk_size)
+        .map(|chunk| {
+            let chunk = chunk.to_vec();
+            Box::new(move || {
+                let state = ValidationState::new();
+                let mut verifier = ProofVerifier::strict();
+                for (tx, spent) in chunk.iter() {
+                    if !check_input_scripts(tx, spent, MANDATORY_SCRIPT_VERIFY_FLAGS) {
+                        return false;
+                    }
+                    if tx.has_shielded() && !contextual_check_transaction(tx, &state, &mut verifier)
+                    {
+                        error!("ConnectBlock(): invalid signature in {:?}", tx.hash());
+                        return false;
+                    }
+                }
+                match verifier.verify() {
+                    Ok(()) => true,
+                    Err(txid) => {
+                        error!("ConnectBlock(): invalid proof in {:?}", txid);
+                        false
+                    }
+                }
+            }) as Job
+        })
+        .collect()
+}
+
+//Reverts the first n_connected transactions of block after connect_block failed half way.
+fn revert_connected(
+    block: &Block,
+    n_connected: usize,
+    blockundo: &BlockUndo,
+    view: &mut CoinViewCache,
+) {
+    for i in (0..n_connected).rev() {
+        let tx_undo = if i > 0 {
+            Some(&blockundo.vtxundo[i - 1])
+        } else {
+            None
+        };
+        revert_transaction(&block.vtx[i], tx_undo, view);
+    }
+}
+
+//CAmount GetBlockSubsidy(int nHeight, const Consensus::Params& consensusParams)
+pub fn get_block_subsidy(n_height: i32) -> CAmount {
+    let halvings = n_height / SUBSIDY_HALVING_INTERVAL;
+    // Force block reward to zero when right shift is undefined.
+    if halvings >= 64 {
+        return 0;
+    }
+    // Subsidy is cut in half every 840,000 blocks.
+    MAX_BLOCK_SUBSIDY >> halvings
+}
+
 pub fn connect_block(
     block: &Block,
     state: &ValidationState,
-    pindex: &BlockIndex,
+    pindex: &mut BlockIndex,
     view: &mut CoinViewCache,
-    block_undo_view: &mut BlockUndoView,
     f_just_check: bool,
-) {
+) -> bool {
     /*BOOST_FOREACH(const CTransaction& tx, block.vtx) {
         const CCoins* coins = view.AccessCoins(tx.GetHash());
         if (coins && !coins->IsPruned())
             return state.DoS(100, error("ConnectBlock(): tried to overwrite transaction"),
                              REJECT_INVALID, "bad-txns-BIP30");
     }*/
-    let sapling_tree = view
+    let mut sapling_tree = view
         .get_best_anchor()
         .and_then(|anchor| view.get_sapling_anchor_at(anchor))
         .unwrap();
 
+    // Check it again in case a previous version let a bad block in. Scripts, signatures and
+    // proofs are left to the verification threads.
+    let mut verifier = ProofVerifier::disabled();
+    if !check_block(block, state, &mut verifier, !f_just_check, !f_just_check) {
+        return false;
+    }
+
     let mut blockundo = BlockUndo::new();
-    let mut i = 0;
-    for tx in block.vtx.iter() {
+    let mut n_fees = 0;
+    let mut spent_outputs = Vec::with_capacity(block.vtx.len());
+    for (i, tx) in block.vtx.iter().enumerate() {
+        if tx.is_coin_base() {
+            spent_outputs.push(Vec::new());
+        } else {
+            // The anchors must be earlier commitment tree roots and the nullifiers unspent
+            if !view.have_shield_requirements(tx) {
+                error!(
+                    "ConnectBlock(): shielded requirements of {:?} not met",
+                    tx.hash()
+                );
+                revert_connected(block, i, &blockundo, view);
+                return state.invalid("bad-txns-joinsplit-requirements-not-met");
+            }
+            if !contextual_check_inputs(
+                tx,
+                state,
+                view,
+                pindex.nHeight,
+                false,
+                MANDATORY_SCRIPT_VERIFY_FLAGS,
+            ) {
+                error!("ConnectBlock(): inputs of {:?} are invalid", tx.hash());
+                revert_connected(block, i, &blockundo, view);
+                return false;
+            }
+            n_fees += view.get_value_in(tx) - tx.get_value_out();
+            // Collected before update_coins spends them
+            spent_outputs.push(get_spent_outputs(tx, view));
+        }
+
         let txundo = update_coins(tx, view, pindex.nHeight);
         if i > 0 {
             blockundo.vtxundo.push(txundo);
         }
+    }
+
+    let block_reward = n_fees + get_block_subsidy(pindex.nHeight);
+    if block.vtx[0].get_value_out() > block_reward {
+        error!(
+            "ConnectBlock(): coinbase pays too much (actual={} vs limit={})",
+            block.vtx[0].get_value_out(),
+            block_reward
+        );
+        revert_connected(block, block.vtx.len(), &blockundo, view);
+        return false;
+    }
 
-        for output in tx.v_shielded_output.iter() {
-            sapling_tree.append(FrHash(output.cmu));
+    let queue = VERIFY_QUEUE.lock().unwrap();
+    queue.add(verification_jobs(block, spent_outputs, queue.n_threads()));
+
+    // The new anchor is computed while the workers verify.
+    let mut f_tree_full = false;
+    for output in block.vtx.iter().flat_map(|tx| tx.v_shielded_output.iter()) {
+        if sapling_tree.append(FrHash(output.cmu)).is_err() {
+            f_tree_full = true;
+            break;
         }
-        i = i + 1;
+    }
+    let f_verified = queue.wait();
+    drop(queue);
+    if f_tree_full {
+        error!("ConnectBlock(): sapling commitment tree is full");
+        revert_connected(block, block.vtx.len(), &blockundo, view);
+        return false;
+    }
+    if sapling_tree.root() != block.header.hash_final_sapling_root {
+        error!("ConnectBlock(): block's hashFinalSaplingRoot is incorrect");
+        revert_connected(block, block.vtx.len(), &blockundo, view);
+        return false;
+    }
+    if !f_verified {
+        error!("ConnectBlock(): script, signature or proof verification failed");
+        revert_connected(block, block.vtx.len(), &blockundo, view);
+        return false;
+    }
+
+    if f_just_check {
+        view.push_anchor(sapling_tree);
+        return true;
     }
 
-    block_undo_view.save_blockundo(pindex.get_block_hash(), blockundo);
+    // The undo data is written before the anchor moves, so a disk error can still be
+    // rolled back completely.
+    if pindex.n_status & BLOCK_HAVE_UNDO == 0 {
+        match undo_write_to_disk(&blockundo, pindex.n_file, pindex.hash_prev_block) {
+            Some(pos) => {
+                pindex.n_undo_pos = pos.offset;
+                pindex.n_status |= BLOCK_HAVE_UNDO;
+            }
+            None => {
+                revert_connected(block, block.vtx.len(), &blockundo, view);
+                return state.error();
+            }
+        }
+    }
+    pindex.raise_validity(BLOCK_VALID_SCRIPTS);
 
     view.push_anchor(sapling_tree);
 
     view.set_best_block(pindex.get_block_hash());
+    true
 }
 
+//bool CheckBlock(const CBlock& block, CValidationState& state,
+// libzcash::ProofVerifier& verifier, bool fCheckPOW, bool fCheckMerkleRoot)
 pub fn check_block(
     block: &Block,
     state: &ValidationState,
-    verifier: &ProofVerifier,
+    verifier: &mut ProofVerifier,
     f_check_POW: bool,
     f_check_merkle_root: bool,
 ) -> bool {
-    if !check_block_header(block, state, f_check_POW) {
+    // These are checks that are independent of context.
+
+    // Check that the header is valid (particularly PoW).  This is mostly
+    // redundant with the call in AcceptBlockHeader.
+    if !check_block_header(&block.header, state, f_check_POW) {
         return false;
     }
 
-    true
-}
-
-pub fn check_block_header(block: &Block, state: &ValidationState, f_check_POW: bool) -> bool {
-    true
-}
-
-//bool ProcessNewBlock(CValidationState &state, CNode* pfrom,
-// CBlock* pblock, bool fForceProcessing, CDiskBlockPos *dbp)
-
-pub fn process_new_block() {
-    accept_block();
-    //call active_best_chain
-}
-
-//bool AcceptBlock(CBlock& block, CValidationState& state,
-// CBlockIndex** ppindex, bool fRequested, CDiskBlockPos* dbp)
+    // Check the merkle root.
+    if f_check_merkle_root {
+        let (hash_merkle_root, mutated) = block.compute_merkle_root();
+        if block.header.hash_merkle_root != hash_merkle_root {
+            error!("CheckBlock(): hashMerkleRoot mismatch");
+            return false;
+        }
 
-pub fn accept_block() {
-    accept_block_header()
-}
+        // Check for merkle tree malleability (CVE-2012-2459): repeating sequences
+        // of transactions in a block without affecting the merkle root of a block,
+        // while still invalidating it.
+        if mutated {
+            error!("CheckBlock(): duplicate transaction");
+            return false;
+        }
+    }
 
-//bool AcceptBlockHeader(const CBlockHeader& block,
-// CValidationState& state, CBlockIndex** ppindex)
+    // Size limits
+    if block.vtx.is_empty() || block.get_serialize_size() > MAX_BLOCK_SIZE {
+        error!("CheckBlock(): size limits failed");
+        return false;
+    }
 
-pub fn accept_block_header() {}
+    // First transaction must be coinbase, the rest must not be
+    if !block.vtx[0].is_coin_base() {
+        error!("CheckBlock(): first tx is not coinbase");
+        return false;
+    }
+    if block.vtx[1..].iter().any(|tx| tx.is_coin_base()) {
+        error!("CheckBlock(): more than one coinbase");
+        return false;
+    }
 
-pub fn check_transaction_without_proof_verification(
-    tx: &Transaction,
-    state: &ValidationState,
-) -> bool {
-    let mut v_sapling_nullifiers = HashSet::new();
-    for spend in tx.v_shielded_spend.iter() {
-        if v_sapling_nullifiers.contains(&spend.nullifier) {
+    let mut set_txids = HashSet::new();
+    let mut set_nullifiers = HashSet::new();
+    for tx in block.vtx.iter() {
+        if !set_txids.insert(tx.hash()) {
+            error!("CheckBlock(): duplicate transaction {:?}", tx.hash());
             return false;
         }
-        v_sapling_nullifiers.insert(spend.nullifier);
-    }
-    if tx.is_coin_base() {
-        if tx.v_shielded_spend.len() > 0 {
+        // A nullifier can only be revealed once, also across transactions
+        for spend in tx.v_shielded_spend.iter() {
+            if !set_nullifiers.insert(spend.nullifier) {
+                error!("CheckBlock(): nullifier reused within the block");
+                return false;
+            }
+        }
+
+        // Check transactions
+        if !check_transaction(tx, state) {
+            error!("CheckBlock(): CheckTransaction failed");
             return false;
         }
-        if tx.v_shielded_output.len() > 0 {
+        if verifier.is_enabled() && !contextual_check_transaction(tx, state, verifier) {
+            error!("CheckBlock(): invalid signature in {:?}", tx.hash());
             return false;
         }
-    } else {
-
     }
-    true
-}
 
-pub fn check_transaction(tx: &Transaction, state: &ValidationState) -> bool {
-    if !check_transaction_without_proof_verification(tx, state) {
+    // All proofs of the block are verified in one batch
+    if let Err(txid) = verifier.verify() {
+        error!("CheckBlock(): invalid proof in {:?}", txid);
         return false;
     }
+
     true
 }
 
-//TODO wu xin
-//check out SignatureHash implements
-fn signature_hash(tx: &Transaction) -> [u8; 32] {
-    [0u8; 32]
-}
+// How far a block timestamp may be ahead of our clock
+pub const MAX_FUTURE_BLOCK_TIME: i64 = 2 * 60 * 60;
 
-// Check spend, output, and value balance signature.
-pub fn contextual_check_transaction(tx: &Transaction, state: &ValidationState) -> bool {
-    if !tx.v_shielded_spend.is_empty() || !tx.v_shielded_output.is_empty() {
-        let mut ctx = SaplingVerificationContext::new();
-        let sighash = signature_hash(&tx);
+//int64_t GetAdjustedTime()
+//There is no network time offset, so this is the local clock.
+fn get_adjusted_time() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs() as i64)
+        .unwrap_or(0)
+}
 
-        for spend in &tx.v_shielded_spend {
-            let zkproof = match Proof::<Bls12>::read(&spend.zkproof[..]) {
-                Ok(p) => p,
-                Err(_) => return false,
-            };
-            if !ctx.check_spend(
-                spend.cv,
-                spend.anchor,
-                &spend.nullifier,
-                spend.rk.clone(),
-                &sighash,
-                spend.spend_auth_sig.unwrap(),
-                zkproof,
-                &SPEND_VK,
-                &JUBJUB,
-            ) {
-                return false;
-            }
+//bool CheckBlockHeader(const CBlockHeader& block, CValidationState& state, bool fCheckPOW)
+pub fn check_block_header(
+    header: &BlockHeader,
+    state: &ValidationState,
+    f_check_POW: bool,
+) -> bool {
+    // Check block version
+    if header.n_version < CURRENT_BLOCK_VERSION {
+        error!("CheckBlockHeader(): block version too low");
+        return false;
+    }
+
+    // Check proof of work matches claimed amount
+    if f_check_POW && !check_proof_of_work(header.get_hash(), header.n_bits) {
+        error!("CheckBlockHeader(): proof of work failed");
+        return false;
+    }
+
+    // Check timestamp
+    if header.get_block_time() > get_adjusted_time() + MAX_FUTURE_BLOCK_TIME {
+        error!("CheckBlockHeader(): block timestamp too far in the future");
+        return false;
+    }
+
+    true
+}
+
+//bool ContextualCheckBlockHeader(const CBlockHeader& block, CValidationState& state, CBlockIndex * const pindexPrev)
+pub fn contextual_check_block_header(
+    header: &BlockHeader,
+    state: &ValidationState,
+    pindex_prev: Option<&BlockIndex>,
+    map_block_index: &BlockMap,
+) -> bool {
+    // Check proof of work
+    if header.n_bits != get_next_work_required(pindex_prev) {
+        error!("ContextualCheckBlockHeader(): incorrect proof of work");
+        return false;
+    }
+
+    // Check timestamp against prev
+    if let Some(pindex_prev) = pindex_prev {
+        if header.get_block_time() <= pindex_prev.get_median_time_past(map_block_index) {
+            error!("ContextualCheckBlockHeader(): block's timestamp is too early");
+            return false;
         }
+    }
 
-        for output in &tx.v_shielded_output {
-            let zkproof = match Proof::<Bls12>::read(&output.zkproof[..]) {
-                Ok(p) => p,
-                Err(_) => return false,
-            };
-            if !ctx.check_output(
-                output.cv,
-                output.cmu,
-                output.ephemeral_key,
-                zkproof,
-                &OUTPUT_VK,
-                &JUBJUB,
-            ) {
-                return false;
+    true
+}
+
+//bool ProcessNewBlock(CValidationState &state, CNode* pfrom,
+// CBlock* pblock, bool fForceProcessing, CDiskBlockPos *dbp)
+pub fn process_new_block(
+    block: &Block,
+    state: &ValidationState,
+    chain_active: &mut Chain,
+    map_block_index: &mut BlockMap,
+    pcoins_tip: &mut CoinViewCache,
+    wallet: &mut Wallet,
+    mempool: &TxMemPool,
+) -> bool {
+    // Preliminary checks. Proofs are verified when the block is connected.
+    let mut verifier = ProofVerifier::disabled();
+    if !check_block(block, state, &mut verifier, true, true) {
+        error!("ProcessNewBlock(): CheckBlock FAILED");
+        return false;
+    }
+
+    // Store to disk
+    if !accept_block(block, state, map_block_index) {
+        error!("ProcessNewBlock(): AcceptBlock FAILED");
+        return false;
+    }
+
+    active_best_chain(
+        chain_active,
+        map_block_index,
+        pcoins_tip,
+        wallet,
+        mempool,
+        state,
+    )
+}
+
+//bool AcceptBlock(CBlock& block, CValidationState& state,
+// CBlockIndex** ppindex, bool fRequested, CDiskBlockPos* dbp)
+pub fn accept_block(
+    block: &Block,
+    state: &ValidationState,
+    map_block_index: &mut BlockMap,
+) -> bool {
+    let hash = match accept_block_header(&block.header, state, map_block_index) {
+        Some(hash) => hash,
+        None => return false,
+    };
+    let pindex = &map_block_index[&hash];
+
+    // Nothing to do if we already have the block.
+    if pindex.n_status & BLOCK_HAVE_DATA != 0 {
+        return true;
+    }
+
+    // Proofs are verified when the block is connected.
+    let mut verifier = ProofVerifier::disabled();
+    let f_valid = check_block(block, state, &mut verifier, true, true)
+        && contextual_check_block(
+            block,
+            state,
+            pindex.get_pprev(map_block_index),
+            map_block_index,
+        );
+    let pindex = map_block_index.get_mut(&hash).unwrap();
+    if !f_valid {
+        pindex.n_status |= BLOCK_FAILED_VALID;
+        return false;
+    }
+
+    // Write block to history file
+    match write_block_to_disk(block) {
+        Some(pos) => {
+            pindex.n_file = pos.file;
+            pindex.n_data_pos = pos.offset;
+            pindex.n_status |= BLOCK_HAVE_DATA;
+            pindex.raise_validity(BLOCK_VALID_TRANSACTIONS);
+        }
+        None => return state.error(),
+    }
+
+    true
+}
+
+//bool ContextualCheckBlock(const CBlock& block, CValidationState& state, CBlockIndex * const pindexPrev)
+pub fn contextual_check_block(
+    block: &Block,
+    state: &ValidationState,
+    pindex_prev: Option<&BlockIndex>,
+    map_block_index: &BlockMap,
+) -> bool {
+    let n_height = pindex_prev.map(|p| p.nHeight + 1).unwrap_or(0);
+    // Lock times are compared against the median time past of the parent, so that the
+    // miner can't move them forward with the block timestamp.
+    let n_lock_time_cutoff = pindex_prev
+        .map(|p| p.get_median_time_past(map_block_index))
+        .unwrap_or_else(|| block.header.get_block_time());
+
+    // Check that all transactions are finalized and not expired
+    for tx in block.vtx.iter() {
+        if !is_final_tx(tx, n_height, n_lock_time_cutoff) {
+            error!("ContextualCheckBlock(): contains a non-final transaction");
+            return false;
+        }
+        if !contextual_check_tx_expiry(tx, n_height) {
+            error!("ContextualCheckBlock(): contains an expired transaction");
+            return false;
+        }
+    }
+
+    // Enforce block.nVersion=2 rule that the coinbase starts with serialized block height
+    if block.vtx[0].get_coinbase_height() != Some(n_height) {
+        error!("ContextualCheckBlock(): block height mismatch in coinbase");
+        return false;
+    }
+    true
+}
+
+//bool AcceptBlockHeader(const CBlockHeader& block,
+// CValidationState& state, CBlockIndex** ppindex)
+//Returns the hash of the index entry of the header, which is added if it is new.
+pub fn accept_block_header(
+    header: &BlockHeader,
+    state: &ValidationState,
+    map_block_index: &mut BlockMap,
+) -> Option<U256> {
+    // Check for duplicate
+    let hash = header.get_hash();
+    if let Some(pindex) = map_block_index.get(&hash) {
+        if pindex.is_failed() {
+            error!("AcceptBlockHeader(): block {} is marked invalid", hash);
+            return None;
+        }
+        return Some(hash);
+    }
+
+    if !check_block_header(header, state, true) {
+        return None;
+    }
+
+    // Get prev block index. A block without a parent is a genesis block, which
+    // is only accepted into an empty index.
+    let pindex_prev = if header.hash_prev_block.is_zero() {
+        if !map_block_index.is_empty() {
+            error!("AcceptBlockHeader(): unexpected genesis block");
+            return None;
+        }
+        None
+    } else {
+        match map_block_index.get(&header.hash_prev_block) {
+            Some(pindex_prev) if pindex_prev.is_failed() => {
+                error!("AcceptBlockHeader(): prev block invalid");
+                return None;
+            }
+            Some(pindex_prev) => Some(pindex_prev),
+            None => {
+                error!("AcceptBlockHeader(): prev block not found");
+                return None;
             }
         }
-        return ctx.final_check(
-            tx.balancing_value,
-            &sighash,
-            Signature::read(&tx.binding_sig[..]).unwrap(),
-            &JUBJUB,
+    };
+
+    if !contextual_check_block_header(header, state, pindex_prev, map_block_index) {
+        return None;
+    }
+
+    Some(add_to_block_index(header, map_block_index))
+}
+
+//CBlockIndex* AddToBlockIndex(const CBlockHeader& block)
+pub fn add_to_block_index(header: &BlockHeader, map_block_index: &mut BlockMap) -> U256 {
+    let mut pindex_new = BlockIndex::from_header(header);
+    let hash = pindex_new.get_block_hash();
+    if let Some(pprev) = map_block_index.get(&header.hash_prev_block) {
+        pindex_new.nHeight = pprev.nHeight + 1;
+        pindex_new.n_chain_work = pprev.n_chain_work;
+    }
+    pindex_new.n_chain_work = pindex_new.n_chain_work + get_block_proof(header.n_bits);
+    pindex_new.raise_validity(BLOCK_VALID_TREE);
+
+    map_block_index.insert(hash, pindex_new);
+    hash
+}
+
+pub fn check_transaction_without_proof_verification(
+    tx: &Transaction,
+    state: &ValidationState,
+) -> bool {
+    let mut v_sapling_nullifiers = HashSet::new();
+    for spend in tx.v_shielded_spend.iter() {
+        if v_sapling_nullifiers.contains(&spend.nullifier) {
+            return false;
+        }
+        v_sapling_nullifiers.insert(spend.nullifier);
+    }
+
+    // Check for negative or overflow output values
+    let mut n_value_out = 0;
+    for txout in tx.vout.iter() {
+        if !money_range(txout.n_value) {
+            error!("CheckTransaction(): txout.nValue out of range");
+            return false;
+        }
+        n_value_out += txout.n_value;
+        if !money_range(n_value_out) {
+            error!("CheckTransaction(): txout total out of range");
+            return false;
+        }
+    }
+
+    // Check for non-zero valueBalance when there are no Sapling inputs or outputs
+    if !tx.has_shielded() && tx.balancing_value != 0 {
+        error!("CheckTransaction(): tx.valueBalance has no sources or sinks");
+        return false;
+    }
+    // Check for overflow valueBalance
+    if tx.balancing_value.abs() > MAX_MONEY {
+        error!("CheckTransaction(): abs(tx.valueBalance) too large");
+        return false;
+    }
+    // Ensure that the value going out of the transparent pool is in range too
+    if !money_range(tx.get_value_out()) {
+        error!("CheckTransaction(): txout total out of range");
+        return false;
+    }
+
+    if tx.is_coin_base() {
+        if tx.v_shielded_spend.len() > 0 {
+            return false;
+        }
+        if tx.v_shielded_output.len() > 0 {
+            return false;
+        }
+    } else {
+    }
+    true
+}
+
+pub fn check_transaction(tx: &Transaction, state: &ValidationState) -> bool {
+    if !check_transaction_without_proof_verification(tx, state) {
+        return false;
+    }
+    true
+}
+
+pub const SIGHASH_ALL: u32 = 1;
+pub const SIGHASH_NONE: u32 = 2;
+pub const SIGHASH_SINGLE: u32 = 3;
+pub const SIGHASH_ANYONECANPAY: u32 = 0x80;
+
+// Passed as nIn when the signature does not belong to a transparent input, as is the
+// case for spend_auth_sig and binding_sig.
+pub const NOT_AN_INPUT: usize = usize::max_value();
+
+const SIGHASH_PERSONALIZATION: &[u8; 16] = b"ice_SigHash_____";
+const PREVOUTS_HASH_PERSONALIZATION: &[u8; 16] = b"ice_PrevoutHash_";
+const OUTPUTS_HASH_PERSONALIZATION: &[u8; 16] = b"ice_OutputsHash_";
+const SHIELDED_SPENDS_HASH_PERSONALIZATION: &[u8; 16] = b"ice_SSpendsHash_";
+const SHIELDED_OUTPUTS_HASH_PERSONALIZATION: &[u8; 16] = b"ice_SOutputHash_";
+
+fn blake2b_256(personalization: &[u8; 16], data: &[u8]) -> [u8; 32] {
+    let mut h = Blake2b::with_params(32, &[], &[], personalization);
+    h.update(data);
+    let mut ret = [0u8; 32];
+    ret.copy_from_slice(h.finalize().as_bytes());
+    ret
+}
+
+fn get_prevout_hash(tx: &MutableTransaction) -> [u8; 32] {
+    let mut data = Vec::new();
+    for txin in &tx.vin {
+        txin.prevout.write(&mut data).unwrap();
+    }
+    blake2b_256(PREVOUTS_HASH_PERSONALIZATION, &data)
+}
+
+fn get_outputs_hash(vout: &[TxOut]) -> [u8; 32] {
+    let mut data = Vec::new();
+    for txout in vout {
+        txout.write(&mut data).unwrap();
+    }
+    blake2b_256(OUTPUTS_HASH_PERSONALIZATION, &data)
+}
+
+// Commits to every spend field except spend_auth_sig, which signs this hash.
+fn get_shielded_spends_hash(tx: &MutableTransaction) -> [u8; 32] {
+    let mut data = Vec::new();
+    for spend in &tx.v_shielded_spend {
+        spend.cv.write(&mut data).unwrap();
+        spend.anchor.into_repr().write_le(&mut data).unwrap();
+        data.extend_from_slice(&spend.nullifier);
+        spend.rk.write(&mut data).unwrap();
+        data.extend_from_slice(&spend.zkproof);
+    }
+    blake2b_256(SHIELDED_SPENDS_HASH_PERSONALIZATION, &data)
+}
+
+fn get_shielded_outputs_hash(tx: &MutableTransaction) -> [u8; 32] {
+    let mut data = Vec::new();
+    for output in &tx.v_shielded_output {
+        output.write(&mut data).unwrap();
+    }
+    blake2b_256(SHIELDED_OUTPUTS_HASH_PERSONALIZATION, &data)
+}
+
+//uint256 SignatureHash(const CScript& scriptCode, const CTransaction& txTo, unsigned int nIn,
+//    int nHashType, const CAmount& amount, uint32_t consensusBranchId)
+//The hash covers the serialized transaction without spend_auth_sig and binding_sig.
+//Shielded signatures use NOT_AN_INPUT with SIGHASH_ALL. It is computed before the
+//signatures are added, so it takes the contents of a transaction.
+pub fn signature_hash(
+    script_code: &Script,
+    tx: &MutableTransaction,
+    n_in: usize,
+    n_hash_type: u32,
+    amount: i64,
+) -> [u8; 32] {
+    if n_in != NOT_AN_INPUT {
+        assert!(
+            n_in < tx.vin.len(),
+            "SignatureHash(): input index is out of range"
+        );
+    }
+
+    let base_type = n_hash_type & 0x1f;
+    let mut hash_prevouts = [0u8; 32];
+    let mut hash_outputs = [0u8; 32];
+
+    if n_hash_type & SIGHASH_ANYONECANPAY == 0 {
+        hash_prevouts = get_prevout_hash(tx);
+    }
+
+    if base_type != SIGHASH_SINGLE && base_type != SIGHASH_NONE {
+        hash_outputs = get_outputs_hash(&tx.vout);
+    } else if base_type == SIGHASH_SINGLE && n_in < tx.vout.len() {
+        hash_outputs = get_outputs_hash(&tx.vout[n_in..n_in + 1]);
+    }
+
+    let mut data = Vec::new();
+    data.write_u32::<LittleEndian>(tx.n_version).unwrap();
+    data.extend_from_slice(&hash_prevouts);
+    data.extend_from_slice(&hash_outputs);
+    if !tx.v_shielded_spend.is_empty() {
+        data.extend_from_slice(&get_shielded_spends_hash(tx));
+    } else {
+        data.extend_from_slice(&[0u8; 32]);
+    }
+    if !tx.v_shielded_output.is_empty() {
+        data.extend_from_slice(&get_shielded_outputs_hash(tx));
+    } else {
+        data.extend_from_slice(&[0u8; 32]);
+    }
+    data.write_u32::<LittleEndian>(tx.lock_time).unwrap();
+    data.write_u32::<LittleEndian>(tx.expiry_height).unwrap();
+    data.write_i64::<LittleEndian>(tx.balancing_value).unwrap();
+    data.write_u32::<LittleEndian>(n_hash_type).unwrap();
+
+    if n_in != NOT_AN_INPUT {
+        tx.vin[n_in].prevout.write(&mut data).unwrap();
+        script_code.write(&mut data).unwrap();
+        data.write_i64::<LittleEndian>(amount).unwrap();
+    }
+
+    blake2b_256(SIGHASH_PERSONALIZATION, &data)
+}
+
+// Check spend, output, and value balance signature. The proofs are queued in verifier,
+// the caller checks them all at once with ProofVerifier::verify.
+pub fn contextual_check_transaction(
+    tx: &Transaction,
+    state: &ValidationState,
+    verifier: &mut ProofVerifier,
+) -> bool {
+    if tx.has_shielded() {
+        let sighash = signature_hash(&Script::new(), &tx, NOT_AN_INPUT, SIGHASH_ALL, 0);
+        return verifier.check_transaction(tx, &sighash);
+    }
+    true
+}
+
+//bool Consensus::CheckTxInputs(const CTransaction& tx, CValidationState& state,
+// const CCoinsViewCache& inputs, int nSpendHeight, const Consensus::Params& consensusParams)
+//n_spend_height is the height of the block the transaction is (or would be) included in.
+//Returns the fee paid by tx.
+pub fn check_tx_inputs<B: CoinsView>(
+    tx: &Transaction,
+    state: &ValidationState,
+    inputs: &mut CoinViewCache<B>,
+    n_spend_height: i32,
+) -> Option<CAmount> {
+    // This doesn't trigger the DoS code on purpose; if it did, it would make it easier
+    // for an attacker to attempt to split the network.
+    if !inputs.have_inputs(tx) {
+        error!("CheckInputs(): {:?} inputs unavailable", tx.hash());
+        return None;
+    }
+
+    let mut n_value_in = 0;
+    for txin in tx.vin.iter() {
+        let coins = inputs
+            .access_coins(txin.prevout.hash)
+            .expect("inputs should be available");
+
+        // If prev is coinbase, check that it's matured
+        if coins.f_coin_base && n_spend_height - coins.n_height < COINBASE_MATURITY as i32 {
+            error!(
+                "CheckInputs(): tried to spend coinbase at depth {}",
+                n_spend_height - coins.n_height
+            );
+            return None;
+        }
+
+        // Check for negative or overflow input values
+        n_value_in += coins.vout[txin.prevout.n].n_value;
+        if !money_range(coins.vout[txin.prevout.n].n_value) || !money_range(n_value_in) {
+            error!("CheckInputs(): txin values out of range");
+            return None;
+        }
+    }
+
+    n_value_in += tx.get_shielded_value_in();
+    if !money_range(tx.get_shielded_value_in()) || !money_range(n_value_in) {
+        error!("CheckInputs(): shielded input to transparent value pool out of range");
+        return None;
+    }
+
+    if n_value_in < tx.get_value_out() {
+        error!(
+            "CheckInputs(): {:?} value in ({}) < value out ({})",
+            tx.hash(),
+            n_value_in,
+            tx.get_value_out()
         );
+        return None;
+    }
+
+    // Tally transaction fees
+    let n_tx_fee = n_value_in - tx.get_value_out();
+    if !money_range(n_tx_fee) {
+        error!("CheckInputs(): nFees out of range");
+        return None;
+    }
+    Some(n_tx_fee)
+}
+
+//bool ContextualCheckInputs(const CTransaction& tx, CValidationState &state,
+// const CCoinsViewCache &inputs, bool fScriptChecks, unsigned int flags, ...)
+//Checks the values of the inputs (see check_tx_inputs) and, with f_script_checks, that every
+//input satisfies the script it spends under flags.
+pub fn contextual_check_inputs(
+    tx: &Transaction,
+    state: &ValidationState,
+    inputs: &mut CoinViewCache,
+    n_spend_height: i32,
+    f_script_checks: bool,
+    flags: u32,
+) -> bool {
+    if tx.is_coin_base() {
+        return true;
+    }
+    if check_tx_inputs(tx, state, inputs, n_spend_height).is_none() {
+        return false;
+    }
+
+    if f_script_checks {
+        let spent_outputs = get_spent_outputs(tx, inputs);
+        return check_input_scripts(tx, &spent_outputs, flags);
     }
     true
 }
 
-fn contextual_check_inputs() -> bool {
+//The outputs spent by the inputs of tx, in input order. The inputs must be available.
+fn get_spent_outputs<B: CoinsView>(tx: &Transaction, inputs: &mut CoinViewCache<B>) -> Vec<TxOut> {
+    tx.vin
+        .iter()
+        .map(|txin| {
+            inputs
+                .access_coins(txin.prevout.hash)
+                .expect("inputs should be available")
+                .vout[txin.prevout.n]
+                .clone()
+        })
+        .collect()
+}
+
+//The script part of ContextualCheckInputs: checks that every input of tx satisfies the
+//script of the output it spends, spent_outputs[i] being the one spent by input i.
+fn check_input_scripts(tx: &Transaction, spent_outputs: &[TxOut], flags: u32) -> bool {
+    for (i, (txin, prevout)) in tx.vin.iter().zip(spent_outputs.iter()).enumerate() {
+        // Verify signature
+        if let Err(serror) = verify_script(
+            &txin.script_sig,
+            &prevout.script_pub_key,
+            flags,
+            tx,
+            i,
+            prevout.n_value,
+        ) {
+            error!(
+                "CheckInputs(): script verification of {:?} input {} failed: {:?}",
+                tx.hash(),
+                i,
+                serror
+            );
+            return false;
+        }
+    }
     true
 }
 
-//TODO
-fn check_final_tx() -> bool {
+//bool IsFinalTx(const CTransaction &tx, int nBlockHeight, int64_t nBlockTime)
+//There are no input sequence numbers, so a lock time can't be overridden.
+pub fn is_final_tx(tx: &Transaction, n_block_height: i32, n_block_time: i64) -> bool {
+    if tx.lock_time == 0 {
+        return true;
+    }
+    let n_lock_time = tx.lock_time as i64;
+    if n_lock_time < LOCKTIME_THRESHOLD as i64 {
+        n_lock_time < n_block_height as i64
+    } else {
+        n_lock_time < n_block_time
+    }
+}
+
+//bool IsExpiredTx(const CTransaction &tx, int nBlockHeight)
+pub fn is_expired_tx(tx: &Transaction, n_block_height: i32) -> bool {
+    if tx.expiry_height == 0 || tx.is_coin_base() {
+        return false;
+    }
+    n_block_height as i64 > tx.expiry_height as i64
+}
+
+//bool IsExpiringSoonTx(const CTransaction &tx, int nNextBlockHeight)
+pub fn is_expiring_soon_tx(tx: &Transaction, n_next_block_height: i32) -> bool {
+    is_expired_tx(tx, n_next_block_height + TX_EXPIRING_SOON_THRESHOLD)
+}
+
+//The expiry part of ContextualCheckTransaction, for a transaction mined at n_height.
+fn contextual_check_tx_expiry(tx: &Transaction, n_height: i32) -> bool {
+    // Check that all transactions are unexpired
+    if is_expired_tx(tx, n_height) {
+        error!(
+            "ContextualCheckTransaction(): transaction {:?} is expired",
+            tx.hash()
+        );
+        return false;
+    }
+    if tx.expiry_height >= TX_EXPIRY_HEIGHT_THRESHOLD {
+        error!("ContextualCheckTransaction(): expiry height is too high");
+        return false;
+    }
     true
 }
 
-pub fn accept_to_mem_pool<'a>(
-    pool: &'a mut TxMemPool<'a>,
+//bool CheckFinalTx(const CTransaction &tx, int flags)
+//Whether tx could be mined in the block after the tip of chain_active.
+pub fn check_final_tx(tx: &Transaction, chain_active: &Chain) -> bool {
+    // CheckFinalTx() uses chainActive.Height()+1 to evaluate
+    // nLockTime because when IsFinalTx() is called within
+    // CBlock::AcceptBlock(), the height of the block *being*
+    // evaluated is what is used. Thus if we want to know if a
+    // transaction can be part of the *next* block, we need to call
+    // IsFinalTx() with one more than chainActive.Height().
+    let n_block_height = chain_active.height() + 1;
+
+    // Timestamps are compared against the median time past of the tip, like
+    // contextual_check_block does for the next block.
+    let n_block_time = chain_active
+        .get_median_time_past()
+        .unwrap_or_else(get_adjusted_time);
+
+    is_final_tx(tx, n_block_height, n_block_time)
+}
+
+pub fn accept_to_mem_pool(
+    pool: &TxMemPool,
     state: &ValidationState,
-    tx: &'a Transaction,
+    ptx: Arc<Transaction>,
     pcoins_tip: &mut CoinViewCache,
+    chain_active: &Chain,
 ) -> bool {
+    let tx = &*ptx;
     if !check_transaction(tx, state) {
         return false;
     }
-    if !contextual_check_transaction(tx, state) {
+    let mut verifier = ProofVerifier::strict();
+    if !contextual_check_transaction(tx, state, &mut verifier) || verifier.verify().is_err() {
         return false;
     }
     // Coinbase is only valid in a block, not as a loose transaction
     if tx.is_coin_base() {
         return false;
     }
-    if !check_final_tx() {
+    // Only accept nLockTime-using transactions that can be mined in the next
+    // block; we don't want our mempool filled up with transactions that can't
+    // be mined yet.
+    if !check_final_tx(tx, chain_active) {
+        error!(
+            "AcceptToMemoryPool(): non-final transaction {:?}",
+            tx.hash()
+        );
         return false;
     }
-
-    let hash = tx.hash;
-    if pool.exists(hash) {
+    let n_next_block_height = chain_active.height() + 1;
+    if !contextual_check_tx_expiry(tx, n_next_block_height) {
         return false;
     }
-
-    for txin in tx.vin.iter() {
-        if pool.mapNextTx.contains_key(&txin.prevout) {
-            //Disable replacement feature for now
-            return false;
-        }
+    // Don't accept transactions that would expire before they have a fair chance to be mined
+    if is_expiring_soon_tx(tx, n_next_block_height) {
+        error!(
+            "AcceptToMemoryPool(): transaction {:?} is expiring soon",
+            tx.hash()
+        );
+        return false;
     }
 
-    for spend_description in tx.v_shielded_spend.iter() {
-        if pool.nullifier_exists(U256::from(spend_description.nullifier)) {
+    let hash = tx.hash();
+
+    // The coins of the tip together with the outputs of the mempool, so that children of
+    // mempool transactions can be accepted too. The pool is only locked while reading them.
+    let (n_fees, spent_outputs, f_spends_coinbase, d_priority, vparents) = {
+        let pool = pool.lock();
+        if pool.exists(hash) {
             return false;
         }
-    }
-
-    {
-        //TODO, backed view
-        let mut view = pcoins_tip;
+        let mut view = CoinViewCache::with_base(CoinViewMemPool::new(pcoins_tip, &pool));
 
         if view.have_coins(hash) {
             return false;
@@ -480,20 +1623,907 @@ pub fn accept_to_mem_pool<'a>(
             return false;
         }
 
-        let entry = TxMemPoolEntry::new(tx);
-        //let entry_ptr: &'a TxMemPoolEntry = &entry;
+        // The transaction would be mined in the block after the tip
+        let n_spend_height = chain_active.height() + 1;
+        let n_fees = match check_tx_inputs(tx, state, &mut view, n_spend_height) {
+            Some(n_fees) => n_fees,
+            None => return false,
+        };
+        debug!("AcceptToMemoryPool(): {:?} pays a fee of {}", hash, n_fees);
+
+        // Keep track of transactions that spend a coinbase, which we re-scan
+        // during reorgs to ensure COINBASE_MATURITY is still met.
+        let f_spends_coinbase = tx.vin.iter().any(|txin| {
+            view.access_coins(txin.prevout.hash)
+                .map_or(false, |coins| coins.f_coin_base)
+        });
+        let d_priority = view.get_priority(tx, chain_active.height());
+        // The parents still in the mempool, which must not leave it before the insertion
+        let vparents: Vec<FrHash> = tx
+            .vin
+            .iter()
+            .map(|txin| txin.prevout.hash)
+            .filter(|&parent| pool.exists(parent))
+            .collect();
+        (
+            n_fees,
+            get_spent_outputs(tx, &mut view),
+            f_spends_coinbase,
+            d_priority,
+            vparents,
+        )
+    };
+
+    // Check against previous transactions
+    // This is done last to help prevent CPU exhaustion denial-of-service attacks.
+    // The input values were checked above, so only the scripts are left.
+    if !check_input_scripts(tx, &spent_outputs, STANDARD_SCRIPT_VERIFY_FLAGS) {
+        return false;
+    }
 
-        if !contextual_check_inputs() {
-            return false;
+    // Check again against just the consensus-critical mandatory script
+    // verification flags, in case of bugs in the standard flags that cause
+    // transactions to pass as valid when they're actually invalid. For
+    // instance the STRICTENC flag was incorrectly allowing certain
+    // CHECKSIG NOT scripts to pass, even though they were invalid.
+    if !check_input_scripts(tx, &spent_outputs, MANDATORY_SCRIPT_VERIFY_FLAGS) {
+        error!(
+            "AcceptToMemoryPool(): BUG! PLEASE REPORT THIS! ConnectInputs failed against \
+             MANDATORY but not STANDARD flags {:?}",
+            hash
+        );
+        return false;
+    }
+
+    // Hold the pool lock from the conflict check to the insertion so that no conflicting
+    // transaction can slip in between them.
+    let mut pool = pool.lock();
+    if pool.exists(hash) {
+        return false;
+    }
+    if vparents.iter().any(|&parent| !pool.exists(parent)) {
+        error!(
+            "AcceptToMemoryPool(): an input of {:?} left the mempool",
+            hash
+        );
+        return false;
+    }
+
+    // Check for conflicts with in-memory transactions
+    let set_conflicts = pool.get_conflicts(tx);
+    if !set_conflicts.is_empty() && !pool.is_replacement_enabled() {
+        error!(
+            "AcceptToMemoryPool(): {:?} conflicts with the mempool",
+            hash
+        );
+        return false;
+    }
+
+    let entry = TxMemPoolEntry::new(
+        ptx.clone(),
+        n_fees,
+        get_adjusted_time(),
+        d_priority,
+        chain_active.height() as u32,
+        pool.has_no_inputs_of(tx),
+        f_spends_coinbase,
+    );
+
+    // Calculate in-mempool ancestors, up to a limit.
+    let mut set_ancestors = HashSet::new();
+    let package_limits = pool.get_package_limits();
+    if !pool.calculate_mem_pool_ancestors(&entry, &mut set_ancestors, Some(&package_limits)) {
+        error!(
+            "AcceptToMemoryPool(): {:?} exceeds the package limits",
+            hash
+        );
+        return false;
+    }
+
+    // A transaction that spends outputs or nullifiers that would be replaced by it is
+    // only accepted if it pays more, see TxMemPoolInner::check_replacement.
+    if !set_conflicts.is_empty() && !pool.check_replacement(&entry, &set_conflicts, &set_ancestors)
+    {
+        return false;
+    }
+
+    // Trim the mempool and check if tx was trimmed, in which case the conflicts stay
+    let mut replaced = VecDeque::new();
+    if !pool.add_replacing(hash, entry, &set_ancestors, &set_conflicts, &mut replaced) {
+        error!(
+            "AcceptToMemoryPool(): mempool full, {:?} not accepted",
+            hash
+        );
+        return false;
+    }
+    for ptx_replaced in replaced.iter() {
+        debug!(
+            "AcceptToMemoryPool(): replacing tx {:?} with {:?}",
+            ptx_replaced.hash(),
+            hash
+        );
+    }
+
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::block_chain::tests::{block_index, block_map, branch};
+    use crate::block_store::tests::open_temp_block_files;
+    use crate::pow::{get_compact, pow_limit};
+    use crate::script::OP_1;
+    use crate::transaction::tests::transaction;
+    use crate::transaction::TxIn;
+    use crate::txmempool::{PackageLimits, DEFAULT_MAX_MEMPOOL_SIZE};
+
+    fn shielded_sighash(tx: &MutableTransaction) -> [u8; 32] {
+        signature_hash(&Script::new(), tx, NOT_AN_INPUT, SIGHASH_ALL, 0)
+    }
+
+    #[test]
+    fn sighash_excludes_signatures() {
+        let tx = MutableTransaction::from(&transaction());
+        let mut tx2 = tx.clone();
+        tx2.binding_sig = [6u8; 64];
+        tx2.v_shielded_spend[0].spend_auth_sig = None;
+        assert_eq!(shielded_sighash(&tx), shielded_sighash(&tx2));
+    }
+
+    #[test]
+    fn sighash_commits_to_transaction() {
+        let tx = MutableTransaction::from(&transaction());
+
+        let mut tx2 = tx.clone();
+        tx2.vout[0].n_value += 1;
+        assert!(shielded_sighash(&tx) != shielded_sighash(&tx2));
+
+        let mut tx2 = tx.clone();
+        tx2.balancing_value += 1;
+        assert!(shielded_sighash(&tx) != shielded_sighash(&tx2));
+
+        let mut tx2 = tx.clone();
+        tx2.v_shielded_spend[0].nullifier[0] ^= 1;
+        assert!(shielded_sighash(&tx) != shielded_sighash(&tx2));
+
+        let mut tx2 = tx.clone();
+        tx2.v_shielded_output[0].enc_ciphertext[0] ^= 1;
+        assert!(shielded_sighash(&tx) != shielded_sighash(&tx2));
+
+        let mut tx2 = tx.clone();
+        tx2.lock_time = 1;
+        assert!(shielded_sighash(&tx) != shielded_sighash(&tx2));
+
+        let mut tx2 = tx.clone();
+        tx2.expiry_height = 1;
+        assert!(shielded_sighash(&tx) != shielded_sighash(&tx2));
+    }
+
+    #[test]
+    fn sighash_types_for_transparent_inputs() {
+        let mut tx = MutableTransaction::from(&transaction());
+        let mut other = tx.vin[0].clone();
+        other.prevout.n += 1;
+        tx.vin.push(other);
+
+        let hash = |tx: &MutableTransaction, n_in, n_hash_type| {
+            signature_hash(&Script::new(), tx, n_in, n_hash_type, 1000)
+        };
+        let all = hash(&tx, 0, SIGHASH_ALL);
+        assert!(all != hash(&tx, 1, SIGHASH_ALL));
+        assert!(all != hash(&tx, 0, SIGHASH_NONE));
+        assert!(all != hash(&tx, NOT_AN_INPUT, SIGHASH_ALL));
+
+        // ANYONECANPAY does not commit to the other inputs.
+        let mut tx2 = tx.clone();
+        tx2.vin.truncate(1);
+        let anyone = SIGHASH_ALL | SIGHASH_ANYONECANPAY;
+        assert_eq!(hash(&tx, 0, anyone), hash(&tx2, 0, anyone));
+        assert!(hash(&tx, 0, SIGHASH_ALL) != hash(&tx2, 0, SIGHASH_ALL));
+
+        // NONE does not commit to the transparent outputs.
+        let mut tx2 = tx.clone();
+        tx2.vout[0].n_value += 1;
+        assert_eq!(hash(&tx, 0, SIGHASH_NONE), hash(&tx2, 0, SIGHASH_NONE));
+        assert!(hash(&tx, 0, SIGHASH_SINGLE) != hash(&tx2, 0, SIGHASH_SINGLE));
+        assert_eq!(hash(&tx, 1, SIGHASH_SINGLE), hash(&tx2, 1, SIGHASH_SINGLE));
+    }
+
+    #[test]
+    fn most_work_chain_skips_invalid_and_missing_blocks() {
+        let chain_active = Chain {
+            v_chain: branch(None, 0, 3),
+        };
+        let short = branch(Some(&chain_active.v_chain[1]), 10, 2);
+        let long = branch(Some(&chain_active.v_chain[1]), 20, 4);
+        let mut map_block_index = block_map(&chain_active.v_chain);
+        map_block_index.extend(block_map(&short));
+        map_block_index.extend(block_map(&long));
+
+        assert_eq!(
+            find_most_work_chain(&mut map_block_index, &chain_active),
+            Some(U256::from(23))
+        );
+
+        // A failed block rules out everything built on top of it.
+        map_block_index.get_mut(&U256::from(21)).unwrap().n_status |= BLOCK_FAILED_VALID;
+        assert_eq!(
+            find_most_work_chain(&mut map_block_index, &chain_active),
+            Some(U256::from(11))
+        );
+        assert!(map_block_index[&U256::from(23)].n_status & BLOCK_FAILED_CHILD != 0);
+
+        // Blocks without data can't be connected yet but are not invalid.
+        let extended = branch(Some(&short[1]), 30, 2);
+        map_block_index.extend(block_map(&extended));
+        map_block_index.get_mut(&U256::from(30)).unwrap().n_status &= !BLOCK_HAVE_DATA;
+        assert_eq!(
+            find_most_work_chain(&mut map_block_index, &chain_active),
+            Some(U256::from(11))
+        );
+        assert!(!map_block_index[&U256::from(31)].is_failed());
+
+        map_block_index.get_mut(&U256::from(30)).unwrap().n_status |= BLOCK_HAVE_DATA;
+        assert_eq!(
+            find_most_work_chain(&mut map_block_index, &chain_active),
+            Some(U256::from(31))
+        );
+    }
+
+    fn mine(mut header: BlockHeader) -> BlockHeader {
+        while !check_proof_of_work(header.get_hash(), header.n_bits) {
+            header.n_nonce = header.n_nonce + U256::one();
         }
+        header
+    }
 
-        //Different check, with different flags
-        if !contextual_check_inputs() {
-            return false;
+    fn next_header(pindex_prev: Option<&BlockIndex>, n_time: u32) -> BlockHeader {
+        let mut header = BlockHeader::new();
+        header.hash_prev_block = pindex_prev
+            .map(|pindex| pindex.get_block_hash())
+            .unwrap_or_default();
+        header.n_time = n_time;
+        header.n_bits = get_next_work_required(pindex_prev);
+        mine(header)
+    }
+
+    #[test]
+    fn accepted_headers_extend_the_block_index() {
+        let state = ValidationState::new();
+        let mut map_block_index = BlockMap::new();
+
+        let genesis = next_header(None, 1000);
+        let hash_genesis = accept_block_header(&genesis, &state, &mut map_block_index).unwrap();
+        let hash = accept_block_header(
+            &next_header(Some(&map_block_index[&hash_genesis]), 1001),
+            &state,
+            &mut map_block_index,
+        )
+        .unwrap();
+        let pindex = map_block_index[&hash].clone();
+        assert_eq!(pindex.nHeight, 1);
+        assert_eq!(pindex.hash_prev_block, hash_genesis);
+        assert_eq!(
+            pindex.get_pprev(&map_block_index).unwrap().get_block_hash(),
+            hash_genesis
+        );
+        assert_eq!(
+            pindex.n_chain_work,
+            get_block_proof(pindex.n_bits) * U256::from(2)
+        );
+        assert!(pindex.is_valid(BLOCK_VALID_TREE));
+        assert!(!pindex.is_valid(BLOCK_VALID_TRANSACTIONS));
+        assert_eq!(pindex.get_block_header().get_hash(), hash);
+
+        // Known headers are not added twice.
+        assert_eq!(
+            accept_block_header(&genesis, &state, &mut map_block_index),
+            Some(hash_genesis)
+        );
+        assert_eq!(map_block_index.len(), 2);
+        assert!(
+            accept_block_header(&next_header(None, 1002), &state, &mut map_block_index).is_none()
+        );
+
+        // The parent has to be known and valid.
+        let mut orphan = next_header(Some(&pindex), 1002);
+        orphan.hash_prev_block = U256::from(5);
+        assert!(accept_block_header(&mine(orphan), &state, &mut map_block_index).is_none());
+
+        // Timestamps have to move past the median of the previous blocks.
+        let early = next_header(Some(&pindex), 1000);
+        assert!(accept_block_header(&early, &state, &mut map_block_index).is_none());
+
+        // The difficulty can't be chosen freely.
+        let mut header = next_header(Some(&pindex), 1002);
+        header.n_bits = get_compact(pow_limit() >> 1);
+        assert!(accept_block_header(&mine(header), &state, &mut map_block_index).is_none());
+
+        // Nor can the proof of work be skipped.
+        let mut header = next_header(Some(&pindex), 1002);
+        while check_proof_of_work(header.get_hash(), header.n_bits) {
+            header.n_nonce = header.n_nonce + U256::one();
         }
+        assert!(accept_block_header(&header, &state, &mut map_block_index).is_none());
 
-        pool.add_unchecked(hash, entry);
+        map_block_index.get_mut(&hash).unwrap().n_status |= BLOCK_FAILED_VALID;
+        let child = next_header(Some(&pindex), 1002);
+        assert!(accept_block_header(&child, &state, &mut map_block_index).is_none());
+        assert_eq!(map_block_index.len(), 2);
     }
 
-    true
+    // Its output can be spent with an empty script_sig.
+    fn coinbase(n_value: i64) -> Transaction {
+        let txout = TxOut {
+            n_value,
+            script_pub_key: Script(vec![OP_1]),
+        };
+        Transaction::new_coinbase(0, vec![txout])
+    }
+
+    fn block_with(vtx: Vec<Transaction>) -> Block {
+        let mut block = Block {
+            header: BlockHeader::new(),
+            vtx,
+        };
+        block.header.hash_merkle_root = block.compute_merkle_root().0;
+        block
+    }
+
+    #[test]
+    fn check_block_rejects_malformed_transaction_lists() {
+        let state = ValidationState::new();
+        // The test transactions don't carry valid proofs.
+        let mut verifier = ProofVerifier::disabled();
+        let mut check = |block: &Block| check_block(block, &state, &mut verifier, false, true);
+
+        let mut transparent = MutableTransaction::from(&transaction());
+        transparent.v_shielded_spend.clear();
+        transparent.v_shielded_output.clear();
+        let transparent = Transaction::from(transparent);
+        assert!(check(&block_with(vec![
+            coinbase(50),
+            transaction(),
+            transparent.clone()
+        ])));
+
+        let mut block = block_with(vec![coinbase(50), transaction()]);
+        block.header.hash_merkle_root = U256::one();
+        assert!(!check(&block));
+
+        assert!(!check(&block_with(vec![])));
+        assert!(!check(&block_with(vec![transaction(), coinbase(50)])));
+        assert!(!check(&block_with(vec![coinbase(50), coinbase(51)])));
+        assert!(!check(&block_with(vec![
+            coinbase(50),
+            transaction(),
+            transparent,
+            transaction()
+        ])));
+
+        // Another transaction revealing the same nullifier
+        let mut double_spend = MutableTransaction::from(&transaction());
+        double_spend.vout[0].n_value += 1;
+        let double_spend = Transaction::from(double_spend);
+        assert!(!check(&block_with(vec![
+            coinbase(50),
+            transaction(),
+            double_spend
+        ])));
+    }
+
+    #[test]
+    fn coinbase_commits_to_height_and_subsidy_halves() {
+        let state = ValidationState::new();
+        let block = block_with(vec![coinbase(50)]);
+        assert_eq!(block.vtx[0].get_coinbase_height(), Some(0));
+        let map_block_index = BlockMap::new();
+        assert!(contextual_check_block(
+            &block,
+            &state,
+            None,
+            &map_block_index
+        ));
+        let pindex_prev = block_index(1, None);
+        assert!(!contextual_check_block(
+            &block,
+            &state,
+            Some(&pindex_prev),
+            &map_block_index
+        ));
+        assert!(!transaction().is_coin_base());
+
+        assert_eq!(get_block_subsidy(0), MAX_BLOCK_SUBSIDY);
+        assert_eq!(
+            get_block_subsidy(SUBSIDY_HALVING_INTERVAL),
+            MAX_BLOCK_SUBSIDY / 2
+        );
+        assert_eq!(get_block_subsidy(SUBSIDY_HALVING_INTERVAL * 64), 0);
+    }
+
+    #[test]
+    fn check_tx_inputs_enforces_maturity_and_values() {
+        let state = ValidationState::new();
+        let mut view = CoinViewCache::new();
+        let cb = coinbase(2000);
+        update_coins(&cb, &mut view, 10);
+
+        let mut mtx = MutableTransaction::from(&transaction());
+        mtx.vin[0].prevout = SaplingOutPoint {
+            hash: cb.hash(),
+            n: 0,
+        };
+        let tx = Transaction::from(mtx.clone());
+        assert_eq!(view.get_value_in(&tx), 2000);
+        assert_eq!(tx.get_value_out(), 1250);
+
+        let n_maturity = COINBASE_MATURITY as i32;
+        assert_eq!(
+            check_tx_inputs(&tx, &state, &mut view, 10 + n_maturity - 1),
+            None
+        );
+        assert_eq!(
+            check_tx_inputs(&tx, &state, &mut view, 10 + n_maturity),
+            Some(750)
+        );
+        assert!(contextual_check_inputs(
+            &tx,
+            &state,
+            &mut view,
+            10 + n_maturity,
+            true,
+            STANDARD_SCRIPT_VERIFY_FLAGS
+        ));
+
+        // Outputs can't exceed the inputs
+        let mut tx2 = mtx.clone();
+        tx2.vout[0].n_value = 1751;
+        let tx2 = Transaction::from(tx2);
+        assert_eq!(
+            check_tx_inputs(&tx2, &state, &mut view, 10 + n_maturity),
+            None
+        );
+
+        // Missing inputs are rejected too
+        mtx.vin[0].prevout.n = 1;
+        let tx = Transaction::from(mtx);
+        assert_eq!(
+            check_tx_inputs(&tx, &state, &mut view, 10 + n_maturity),
+            None
+        );
+    }
+
+    #[test]
+    fn verification_jobs_check_input_scripts() {
+        let txin = TxIn {
+            prevout: SaplingOutPoint {
+                hash: coinbase(1000).hash(),
+                n: 0,
+            },
+            script_sig: Script::new(),
+        };
+        let txout = TxOut {
+            n_value: 1000,
+            script_pub_key: Script::new(),
+        };
+        let tx = Transaction::new(vec![txin], vec![txout], vec![], vec![], 0, [0u8; 64]);
+        let block = block_with(vec![coinbase(50), tx]);
+        let spent_outputs = |script_pub_key: Script| {
+            vec![
+                vec![],
+                vec![TxOut {
+                    n_value: 1000,
+                    script_pub_key,
+                }],
+            ]
+        };
+
+        let jobs = verification_jobs(&block, spent_outputs(Script(vec![OP_1])), 2);
+        assert_eq!(jobs.len(), 1);
+        assert!(jobs.into_iter().all(|job| job()));
+
+        // An empty script leaves nothing true on the stack
+        let jobs = verification_jobs(&block, spent_outputs(Script::new()), 2);
+        assert!(!jobs.into_iter().all(|job| job()));
+
+        // Without transparent inputs or shielded parts there is nothing to check
+        let block = block_with(vec![coinbase(50)]);
+        assert!(verification_jobs(&block, vec![vec![]], 2).is_empty());
+    }
+
+    // A transparent transaction spending output n of parent, anyone can spend its output.
+    fn spending(parent: &Transaction, n: usize, n_value: i64) -> Transaction {
+        let txin = TxIn {
+            prevout: SaplingOutPoint {
+                hash: parent.hash(),
+                n,
+            },
+            script_sig: Script::new(),
+        };
+        let txout = TxOut {
+            n_value,
+            script_pub_key: Script(vec![OP_1]),
+        };
+        Transaction::new(vec![txin], vec![txout], vec![], vec![], 0, [0u8; 64])
+    }
+
+    // An empty mempool on top of a chain with a mature coinbase paying n_value.
+    fn mempool_on_chain(n_value: i64) -> (TxMemPool, CoinViewCache, Chain, Transaction) {
+        let chain_active = Chain {
+            v_chain: branch(None, 1, COINBASE_MATURITY as u64 + 1),
+        };
+        let mut pcoins_tip = CoinViewCache::new();
+        let cb = coinbase(n_value);
+        update_coins(&cb, &mut pcoins_tip, 0);
+        let mempool = TxMemPool::new(DEFAULT_MAX_MEMPOOL_SIZE * 1000000);
+        (mempool, pcoins_tip, chain_active, cb)
+    }
+
+    #[test]
+    fn mempool_accepts_children_of_mempool_transactions() {
+        let (mempool, mut pcoins_tip, chain_active, cb) = mempool_on_chain(10000);
+        let state = ValidationState::new();
+        let mut accept = |tx: &Transaction| {
+            let ptx = Arc::new(tx.clone());
+            accept_to_mem_pool(&mempool, &state, ptx, &mut pcoins_tip, &chain_active)
+        };
+        let parent = spending(&cb, 0, 9000);
+        let child = spending(&parent, 0, 8500);
+        let grandchild = spending(&child, 0, 8000);
+
+        // The parent has to come first
+        assert!(!accept(&child));
+        assert!(accept(&parent));
+        assert!(accept(&child));
+        assert!(accept(&grandchild));
+        // Spending an output twice is a conflict
+        assert!(!accept(&spending(&parent, 0, 8000)));
+
+        let pool = mempool.lock();
+        assert_eq!(pool.size(), 3);
+        let entry = &pool.mapTx[&grandchild.hash()];
+        assert_eq!(entry.get_fee(), 500);
+        assert_eq!(entry.get_count_with_ancestors(), 3);
+        assert!(!entry.was_clear_at_entry());
+        assert_eq!(pool.mapTx[&parent.hash()].get_count_with_descendants(), 3);
+        drop(pool);
+
+        // The mempool outputs don't reach the tip
+        assert!(!pcoins_tip.have_coins(parent.hash()));
+        assert!(pcoins_tip.have_coins(cb.hash()));
+    }
+
+    #[test]
+    fn mempool_rejects_chains_over_the_package_limits() {
+        let (mempool, mut pcoins_tip, chain_active, cb) = mempool_on_chain(10000);
+        mempool.lock().set_package_limits(PackageLimits {
+            n_ancestor_count: 2,
+            ..PackageLimits::default()
+        });
+        let state = ValidationState::new();
+        let mut accept = |tx: &Transaction| {
+            let ptx = Arc::new(tx.clone());
+            accept_to_mem_pool(&mempool, &state, ptx, &mut pcoins_tip, &chain_active)
+        };
+        let parent = spending(&cb, 0, 9000);
+        let child = spending(&parent, 0, 8500);
+        let grandchild = spending(&child, 0, 8000);
+
+        assert!(accept(&parent));
+        assert!(accept(&child));
+        assert!(!accept(&grandchild));
+        assert!(!mempool.exists(grandchild.hash()));
+        assert_eq!(mempool.size(), 2);
+    }
+
+    #[test]
+    fn check_transaction_rejects_out_of_range_amounts() {
+        let state = ValidationState::new();
+        assert!(check_transaction(&transaction(), &state));
+
+        let mut tx = MutableTransaction::from(&transaction());
+        tx.vout[0].n_value = -1;
+        assert!(!check_transaction(&Transaction::from(tx), &state));
+
+        let mut tx = MutableTransaction::from(&transaction());
+        tx.vout[0].n_value = MAX_MONEY;
+        tx.vout.push(tx.vout[0].clone());
+        assert!(!check_transaction(&Transaction::from(tx), &state));
+
+        let mut tx = MutableTransaction::from(&transaction());
+        tx.balancing_value = -MAX_MONEY - 1;
+        assert!(!check_transaction(&Transaction::from(tx), &state));
+
+        let mut tx = MutableTransaction::from(&coinbase(50));
+        tx.balancing_value = 1;
+        assert!(!check_transaction(&Transaction::from(tx), &state));
+    }
+
+    #[test]
+    fn lock_time_and_expiry_height() {
+        let mut mtx = MutableTransaction::from(&transaction());
+        let tx = Transaction::from(mtx.clone());
+        assert!(is_final_tx(&tx, 0, 0));
+        assert!(!is_expired_tx(&tx, i32::max_value()));
+
+        // A block height lock
+        mtx.lock_time = 10;
+        let tx = Transaction::from(mtx.clone());
+        assert!(!is_final_tx(&tx, 10, 0));
+        assert!(is_final_tx(&tx, 11, 0));
+
+        // A timestamp lock
+        mtx.lock_time = LOCKTIME_THRESHOLD + 100;
+        let tx = Transaction::from(mtx.clone());
+        assert!(!is_final_tx(&tx, 1000, (LOCKTIME_THRESHOLD + 100) as i64));
+        assert!(is_final_tx(&tx, 0, (LOCKTIME_THRESHOLD + 101) as i64));
+
+        mtx.expiry_height = 20;
+        let tx = Transaction::from(mtx.clone());
+        assert!(!is_expired_tx(&tx, 20));
+        assert!(is_expired_tx(&tx, 21));
+        assert!(!is_expiring_soon_tx(&tx, 17));
+        assert!(is_expiring_soon_tx(&tx, 18));
+        assert!(contextual_check_tx_expiry(&tx, 20));
+        assert!(!contextual_check_tx_expiry(&tx, 21));
+
+        mtx.expiry_height = TX_EXPIRY_HEIGHT_THRESHOLD;
+        let tx = Transaction::from(mtx);
+        assert!(!contextual_check_tx_expiry(&tx, 0));
+
+        // Non-final transactions can't be mined
+        let state = ValidationState::new();
+        let map_block_index = BlockMap::new();
+        let block = block_with(vec![coinbase(50), transaction()]);
+        assert!(contextual_check_block(
+            &block,
+            &state,
+            None,
+            &map_block_index
+        ));
+        let mut mtx = MutableTransaction::from(&transaction());
+        mtx.lock_time = 5;
+        let block = block_with(vec![coinbase(50), Transaction::from(mtx.clone())]);
+        assert!(!contextual_check_block(
+            &block,
+            &state,
+            None,
+            &map_block_index
+        ));
+        mtx.lock_time = 0;
+        mtx.expiry_height = TX_EXPIRY_HEIGHT_THRESHOLD;
+        let block = block_with(vec![coinbase(50), Transaction::from(mtx)]);
+        assert!(!contextual_check_block(
+            &block,
+            &state,
+            None,
+            &map_block_index
+        ));
+    }
+
+    // The chain state of a running node, blocks go through process_new_block.
+    struct Node {
+        chain_active: Chain,
+        map_block_index: BlockMap,
+        pcoins_tip: CoinViewCache,
+        wallet: Wallet,
+        mempool: TxMemPool,
+    }
+
+    impl Node {
+        fn new() -> Self {
+            open_temp_block_files();
+            Node {
+                chain_active: Chain::new(),
+                map_block_index: BlockMap::new(),
+                pcoins_tip: CoinViewCache::new(),
+                wallet: Wallet::new(),
+                mempool: TxMemPool::new(DEFAULT_MAX_MEMPOOL_SIZE * 1000000),
+            }
+        }
+
+        fn process(&mut self, block: &Block) -> bool {
+            let state = ValidationState::new();
+            process_new_block(
+                block,
+                &state,
+                &mut self.chain_active,
+                &mut self.map_block_index,
+                &mut self.pcoins_tip,
+                &mut self.wallet,
+                &self.mempool,
+            )
+        }
+
+        // A mined block on top of hash_prev whose coinbase pays n_value to a script anyone
+        // can spend.
+        fn next_block(
+            &self,
+            hash_prev: Option<U256>,
+            n_value: i64,
+            vtx: Vec<Transaction>,
+        ) -> Block {
+            let pindex_prev = hash_prev.map(|hash| &self.map_block_index[&hash]);
+            let n_height = pindex_prev.map_or(0, |pindex| pindex.nHeight + 1);
+            let txout = TxOut {
+                n_value,
+                script_pub_key: Script(vec![OP_1]),
+            };
+            let mut block = block_with(
+                Some(Transaction::new_coinbase(n_height, vec![txout]))
+                    .into_iter()
+                    .chain(vtx)
+                    .collect(),
+            );
+            block.header.hash_prev_block = hash_prev.unwrap_or_default();
+            block.header.n_time = 1000 + n_height as u32;
+            block.header.n_bits = get_next_work_required(pindex_prev);
+            block.header = mine(block.header);
+            block
+        }
+    }
+
+    #[test]
+    fn invalid_fork_block_reconnects_the_old_branch() {
+        let mut node = Node::new();
+        let genesis = node.next_block(None, 10, vec![]);
+        assert!(node.process(&genesis));
+        let hash_genesis = genesis.header.get_hash();
+
+        let a1 = node.next_block(Some(hash_genesis), 10, vec![]);
+        assert!(node.process(&a1));
+        let a2 = node.next_block(Some(a1.header.get_hash()), 10, vec![]);
+        assert!(node.process(&a2));
+        assert_eq!(node.chain_active.height(), 2);
+
+        // A heavier fork whose second block pays itself too much.
+        let b1 = node.next_block(Some(hash_genesis), 20, vec![]);
+        assert!(node.process(&b1));
+        let b2 = node.next_block(Some(b1.header.get_hash()), MAX_BLOCK_SUBSIDY + 1, vec![]);
+        assert!(node.process(&b2));
+        assert_eq!(
+            node.chain_active.tip().unwrap().get_block_hash(),
+            a2.header.get_hash()
+        );
+        let b3 = node.next_block(Some(b2.header.get_hash()), 20, vec![]);
+        assert!(node.process(&b3));
+
+        let hash_a2 = a2.header.get_hash();
+        assert_eq!(node.chain_active.height(), 2);
+        assert_eq!(node.chain_active.tip().unwrap().get_block_hash(), hash_a2);
+        assert_eq!(
+            node.chain_active.get(1).unwrap().get_block_hash(),
+            a1.header.get_hash()
+        );
+        assert_eq!(node.pcoins_tip.get_best_block(), hash_a2);
+        assert!(node.pcoins_tip.have_coins(a1.vtx[0].hash()));
+        assert!(node.pcoins_tip.have_coins(a2.vtx[0].hash()));
+        assert!(!node.pcoins_tip.have_coins(b1.vtx[0].hash()));
+        assert!(!node.pcoins_tip.have_coins(b2.vtx[0].hash()));
+
+        let status = |block: &Block| node.map_block_index[&block.header.get_hash()].n_status;
+        assert!(status(&b1) & (BLOCK_FAILED_VALID | BLOCK_FAILED_CHILD) == 0);
+        assert!(status(&b2) & BLOCK_FAILED_VALID != 0);
+        assert!(status(&b3) & BLOCK_FAILED_CHILD != 0);
+
+        // The restored branch can still be extended.
+        let a3 = node.next_block(Some(hash_a2), 10, vec![]);
+        assert!(node.process(&a3));
+        assert_eq!(node.chain_active.height(), 3);
+        assert_eq!(node.pcoins_tip.get_best_block(), a3.header.get_hash());
+    }
+
+    #[test]
+    fn connect_block_checks_anchors_and_nullifiers() {
+        let mut node = Node::new();
+        let genesis = node.next_block(None, 10, vec![]);
+        assert!(node.process(&genesis));
+        let hash_genesis = genesis.header.get_hash();
+
+        // The test spends carry no valid proofs, so the reject reason tells the failures apart.
+        let connect = |node: &mut Node, tx: &MutableTransaction| {
+            let block =
+                node.next_block(Some(hash_genesis), 10, vec![Transaction::from(tx.clone())]);
+            let mut pindex = BlockIndex::from_header(&block.header);
+            pindex.nHeight = 1;
+            let state = ValidationState::new();
+            assert!(!connect_block(
+                &block,
+                &state,
+                &mut pindex,
+                &mut node.pcoins_tip,
+                true
+            ));
+            assert_eq!(node.pcoins_tip.get_best_block(), hash_genesis);
+            state.get_reject_reason()
+        };
+        let not_met = "bad-txns-joinsplit-requirements-not-met";
+
+        // A shielded spend paying for the transparent output
+        let mut tx = MutableTransaction::from(&transaction());
+        tx.vin.clear();
+        tx.v_shielded_output.clear();
+        tx.balancing_value = tx.vout[0].n_value;
+        // Its anchor is no commitment tree root
+        assert_eq!(connect(&mut node, &tx), not_met);
+
+        // Only the signatures and proofs fail
+        tx.v_shielded_spend[0].anchor = SaplingMerkleTree::empty_root().0;
+        assert_eq!(connect(&mut node, &tx), "");
+
+        // The nullifier was revealed in an earlier block
+        node.pcoins_tip.set_nullifiers(&transaction(), true);
+        assert_eq!(connect(&mut node, &tx), not_met);
+    }
+
+    #[test]
+    fn connected_blocks_commit_to_the_final_sapling_root() {
+        let mut node = Node::new();
+        let genesis = node.next_block(None, 10, vec![]);
+        assert!(node.process(&genesis));
+        let hash_genesis = genesis.header.get_hash();
+
+        // The block adds no commitments, so the root of the tree doesn't change
+        let mut block = node.next_block(Some(hash_genesis), 10, vec![]);
+        let mut sapling_tree = SaplingMerkleTree::new();
+        sapling_tree
+            .append(FrHash(transaction().v_shielded_output[0].cmu))
+            .unwrap();
+        block.header.hash_final_sapling_root = sapling_tree.root();
+        block.header = mine(block.header);
+        node.process(&block);
+        assert_eq!(node.chain_active.height(), 0);
+        assert_eq!(node.pcoins_tip.get_best_block(), hash_genesis);
+        let hash = block.header.get_hash();
+        assert!(node.map_block_index[&hash].n_status & BLOCK_FAILED_VALID != 0);
+
+        let block = node.next_block(Some(hash_genesis), 10, vec![]);
+        assert!(node.process(&block));
+        assert_eq!(node.chain_active.height(), 1);
+    }
+
+    #[test]
+    fn disconnected_block_returns_parent_and_child_to_the_mempool() {
+        let mut node = Node::new();
+        let genesis = node.next_block(None, 10000, vec![]);
+        assert!(node.process(&genesis));
+        let mut hash_tip = genesis.header.get_hash();
+        // Let the genesis coinbase mature
+        for _ in 0..COINBASE_MATURITY {
+            let block = node.next_block(Some(hash_tip), 10, vec![]);
+            assert!(node.process(&block));
+            hash_tip = block.header.get_hash();
+        }
+
+        let parent = spending(&genesis.vtx[0], 0, 9000);
+        let child = spending(&parent, 0, 8000);
+        let block = node.next_block(Some(hash_tip), 10, vec![parent.clone(), child.clone()]);
+        assert!(node.process(&block));
+        assert_eq!(node.chain_active.height(), COINBASE_MATURITY as i32 + 1);
+        assert!(node.pcoins_tip.have_coins(child.hash()));
+        assert!(!node.pcoins_tip.have_coins(genesis.vtx[0].hash()));
+
+        let state = ValidationState::new();
+        assert!(disconnect_tip(
+            &mut node.chain_active,
+            &mut node.pcoins_tip,
+            &mut node.wallet,
+            &node.mempool,
+            &state,
+            false
+        ));
+        assert_eq!(node.chain_active.height(), COINBASE_MATURITY as i32);
+        assert_eq!(node.pcoins_tip.get_best_block(), hash_tip);
+        assert!(node.pcoins_tip.have_coins(genesis.vtx[0].hash()));
+        assert!(!node.pcoins_tip.have_coins(parent.hash()));
+        assert!(!node.pcoins_tip.have_coins(child.hash()));
+
+        // Both are back in the mempool, the child on top of its parent
+        let pool = node.mempool.lock();
+        assert_eq!(pool.size(), 2);
+        assert!(pool.exists(parent.hash()));
+        assert_eq!(pool.mapTx[&child.hash()].get_count_with_ancestors(), 2);
+        assert_eq!(pool.mapTx[&parent.hash()].get_fees_with_descendants(), 2000);
+    }
 }
@@ -1,5 +1,20 @@
+use crate::amount::COIN;
+use crate::sendmany::CAmount;
+
 pub const ZC_MEMO_SIZE: usize = 512;
 
 pub const COINBASE_MATURITY: usize = 100;
 pub const MAX_REORG_LENGTH: usize = COINBASE_MATURITY - 1;
 pub const WITNESS_CACHE_SIZE: usize = MAX_REORG_LENGTH + 1;
+
+/** The maximum allowed size for a serialized block, in bytes (network rule) */
+pub const MAX_BLOCK_SIZE: usize = 2000000;
+
+/** The block subsidy before the first halving */
+pub const MAX_BLOCK_SUBSIDY: CAmount = 125 * COIN / 10;
+pub const SUBSIDY_HALVING_INTERVAL: i32 = 840000;
+
+/** The default number of blocks after which a built transaction expires */
+pub const DEFAULT_TX_EXPIRY_DELTA: i32 = 20;
+/** The mempool rejects transactions that expire within this many blocks of the next one */
+pub const TX_EXPIRING_SOON_THRESHOLD: i32 = 3;
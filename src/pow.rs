@@ -0,0 +1,117 @@
+//! Compact difficulty targets and proof-of-work checks.
+
+use ethereum_types::U256;
+
+use crate::block_chain::BlockIndex;
+
+// The easiest target a block may have, in compact form
+pub const POW_LIMIT_BITS: u32 = 0x207fffff;
+
+//arith_uint256& arith_uint256::SetCompact(uint32_t nCompact, bool* pfNegative, bool* pfOverflow)
+//Returns None for a negative, zero or overflowing target.
+pub fn get_target(n_compact: u32) -> Option<U256> {
+    let n_size = (n_compact >> 24) as usize;
+    let mut n_word = n_compact & 0x007fffff;
+    let target = if n_size <= 3 {
+        n_word >>= 8 * (3 - n_size);
+        U256::from(n_word)
+    } else {
+        if n_word != 0
+            && (n_size > 34 || (n_word > 0xff && n_size > 33) || (n_word > 0xffff && n_size > 32))
+        {
+            return None;
+        }
+        U256::from(n_word) << (8 * (n_size - 3))
+    };
+    let f_negative = n_word != 0 && (n_compact & 0x00800000) != 0;
+    if f_negative || target.is_zero() {
+        return None;
+    }
+    Some(target)
+}
+
+//uint32_t arith_uint256::GetCompact(bool fNegative) const
+pub fn get_compact(target: U256) -> u32 {
+    let mut n_size = (target.bits() + 7) / 8;
+    let mut n_compact = if n_size <= 3 {
+        (target.low_u64() << (8 * (3 - n_size))) as u32
+    } else {
+        (target >> (8 * (n_size - 3))).low_u64() as u32
+    };
+    // The 0x00800000 bit denotes the sign, so if it is already set, divide the
+    // mantissa by 256 and increase the exponent.
+    if n_compact & 0x00800000 != 0 {
+        n_compact >>= 8;
+        n_size += 1;
+    }
+    n_compact | ((n_size as u32) << 24)
+}
+
+pub fn pow_limit() -> U256 {
+    get_target(POW_LIMIT_BITS).expect("the pow limit should be a valid target")
+}
+
+//bool CheckProofOfWork(uint256 hash, unsigned int nBits, const Consensus::Params& params)
+pub fn check_proof_of_work(hash: U256, n_bits: u32) -> bool {
+    // Check range
+    let target = match get_target(n_bits) {
+        Some(target) if target <= pow_limit() => target,
+        _ => return false,
+    };
+    // Check proof of work matches claimed amount
+    hash <= target
+}
+
+//arith_uint256 GetBlockProof(const CBlockIndex& block)
+pub fn get_block_proof(n_bits: u32) -> U256 {
+    match get_target(n_bits) {
+        // We need to compute 2**256 / (target+1), but we can't represent 2**256
+        // as it's too large for a U256. However, as 2**256 is at least as large
+        // as target+1, it is equal to ((2**256 - target - 1) / (target+1)) + 1,
+        // or ~target / (target+1) + 1.
+        Some(target) => (!target / (target + U256::one())) + U256::one(),
+        None => U256::zero(),
+    }
+}
+
+//unsigned int GetNextWorkRequired(const CBlockIndex* pindexLast, const CBlockHeader *pblock, const Consensus::Params& params)
+//TODO: difficulty adjustment, every block has to use the difficulty of its parent for now
+pub fn get_next_work_required(pindex_last: Option<&BlockIndex>) -> u32 {
+    match pindex_last {
+        Some(pindex) => pindex.n_bits,
+        // Genesis block
+        None => POW_LIMIT_BITS,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn compact_round_trip() {
+        assert_eq!(get_target(0x01003456), None);
+        assert_eq!(get_target(0x01123456), Some(U256::from(0x12)));
+        assert_eq!(get_target(0x04923456), None);
+        assert_eq!(get_target(0x05009234), Some(U256::from(0x92340000u64)));
+        assert_eq!(get_target(0xff123456), None);
+
+        for &n_bits in [POW_LIMIT_BITS, 0x1d00ffff, 0x05009234, 0x01120000].iter() {
+            assert_eq!(get_compact(get_target(n_bits).unwrap()), n_bits);
+        }
+    }
+
+    #[test]
+    fn proof_of_work_and_block_proof() {
+        let limit = pow_limit();
+        assert!(check_proof_of_work(limit, POW_LIMIT_BITS));
+        assert!(!check_proof_of_work(limit + U256::one(), POW_LIMIT_BITS));
+        // A target above the limit is never valid.
+        assert!(!check_proof_of_work(U256::zero(), 0x2100ffff));
+
+        // Halving the target doubles the work.
+        let harder = get_compact(limit >> 1);
+        assert_eq!(get_block_proof(POW_LIMIT_BITS), U256::from(2));
+        assert_eq!(get_block_proof(harder), U256::from(4));
+    }
+}
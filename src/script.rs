@@ -1,12 +0,0 @@
-#[derive(Copy, Clone)]
-pub struct Script {}
-
-impl Script {
-    //TODO
-    pub fn is_unspendable(&self) -> bool {
-        false
-    }
-
-    //TODO
-    pub fn clear(&self) {}
-}
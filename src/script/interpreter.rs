@@ -0,0 +1,517 @@
+use pairing::bls12_381::Bls12;
+use sapling_crypto::jubjub::FixedGenerators;
+use sapling_crypto::redjubjub::{PublicKey, Signature};
+use zcash_primitives::JUBJUB;
+
+use super::*;
+use crate::main_impl::{signature_hash, SIGHASH_ALL, SIGHASH_ANYONECANPAY, SIGHASH_SINGLE};
+use crate::sendmany::CAmount;
+use crate::transaction::Transaction;
+
+/** Script verification flags */
+pub const SCRIPT_VERIFY_NONE: u32 = 0;
+// Passing a signature with an undefined hashtype or a malformed encoding to a checksig
+// operation causes script failure, as does a public key that is not a valid point.
+pub const SCRIPT_VERIFY_STRICTENC: u32 = 1 << 1;
+// scriptSig must only contain push operations
+pub const SCRIPT_VERIFY_SIGPUSHONLY: u32 = 1 << 5;
+// Require minimal encodings for all push operations
+pub const SCRIPT_VERIFY_MINIMALDATA: u32 = 1 << 6;
+// Require that only a single stack element remains after evaluation
+pub const SCRIPT_VERIFY_CLEANSTACK: u32 = 1 << 8;
+
+/** Mandatory script verification flags that all new blocks must comply with for
+ * them to be valid. (but old blocks may not comply with) */
+pub const MANDATORY_SCRIPT_VERIFY_FLAGS: u32 = SCRIPT_VERIFY_STRICTENC;
+
+/** Standard script verification flags that standard transactions will comply
+ * with. However scripts violating these flags may still be present in valid
+ * blocks and we must accept those blocks. */
+pub const STANDARD_SCRIPT_VERIFY_FLAGS: u32 = MANDATORY_SCRIPT_VERIFY_FLAGS
+    | SCRIPT_VERIFY_SIGPUSHONLY
+    | SCRIPT_VERIFY_MINIMALDATA
+    | SCRIPT_VERIFY_CLEANSTACK;
+
+// Transparent keys sign with the same generator as Sapling spend authorization keys.
+pub const TRANSPARENT_KEY_GENERATOR: FixedGenerators = FixedGenerators::SpendingKeyGenerator;
+
+// An encoded key is a compressed Jubjub point, a signature is a RedJubjub signature
+// followed by the hash type.
+pub const PUBLIC_KEY_SIZE: usize = 32;
+pub const SIGNATURE_SIZE: usize = 64 + 1;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ScriptError {
+    EvalFalse,
+    OpReturn,
+    ScriptSize,
+    PushSize,
+    OpCount,
+    StackSize,
+    BadOpcode,
+    InvalidStackOperation,
+    Verify,
+    EqualVerify,
+    CheckSigVerify,
+    SigHashType,
+    SigEncoding,
+    PubKeyType,
+    SigPushOnly,
+    MinimalData,
+    CleanStack,
+}
+
+//Checks signatures against the sighash of one transparent input.
+pub struct TransactionSignatureChecker<'a> {
+    tx: &'a Transaction,
+    n_in: usize,
+    amount: CAmount,
+}
+
+impl<'a> TransactionSignatureChecker<'a> {
+    pub fn new(tx: &'a Transaction, n_in: usize, amount: CAmount) -> Self {
+        TransactionSignatureChecker { tx, n_in, amount }
+    }
+
+    //bool TransactionSignatureChecker::CheckSig(const vector<unsigned char>& vchSigIn,
+    // const vector<unsigned char>& vchPubKey, const CScript& scriptCode, uint32_t consensusBranchId) const
+    pub fn check_sig(&self, vch_sig: &[u8], vch_pub_key: &[u8], script_code: &Script) -> bool {
+        if vch_sig.len() != SIGNATURE_SIZE || vch_pub_key.len() != PUBLIC_KEY_SIZE {
+            return false;
+        }
+        let pubkey = match PublicKey::<Bls12>::read(vch_pub_key, &JUBJUB) {
+            Ok(pubkey) => pubkey,
+            Err(_) => return false,
+        };
+        let sig = match Signature::read(&vch_sig[..64]) {
+            Ok(sig) => sig,
+            Err(_) => return false,
+        };
+        let n_hash_type = vch_sig[64] as u32;
+        let sighash = signature_hash(script_code, self.tx, self.n_in, n_hash_type, self.amount);
+        pubkey.verify(&sighash, &sig, TRANSPARENT_KEY_GENERATOR, &JUBJUB)
+    }
+}
+
+fn cast_to_bool(vch: &[u8]) -> bool {
+    for (i, b) in vch.iter().enumerate() {
+        if *b != 0 {
+            // Can be negative zero
+            if i == vch.len() - 1 && *b == 0x80 {
+                return false;
+            }
+            return true;
+        }
+    }
+    false
+}
+
+fn encode_bool(f: bool) -> Vec<u8> {
+    if f {
+        vec![1]
+    } else {
+        vec![]
+    }
+}
+
+//bool CheckMinimalPush(const valtype& data, opcodetype opcode)
+fn check_minimal_push(data: &[u8], opcode: u8) -> bool {
+    if data.is_empty() {
+        // Could have used OP_0.
+        return opcode == OP_0;
+    } else if data.len() == 1 && data[0] >= 1 && data[0] <= 16 {
+        // Could have used OP_1 .. OP_16.
+        return false;
+    } else if data.len() == 1 && data[0] == 0x81 {
+        // Could have used OP_1NEGATE.
+        return false;
+    } else if data.len() <= 75 {
+        // Could have used a direct push (opcode indicating number of bytes pushed + those bytes).
+        return opcode as usize == data.len();
+    } else if data.len() <= 255 {
+        // Could have used OP_PUSHDATA.
+        return opcode == OP_PUSHDATA1;
+    } else if data.len() <= 65535 {
+        // Could have used OP_PUSHDATA2.
+        return opcode == OP_PUSHDATA2;
+    }
+    true
+}
+
+fn is_defined_hashtype_signature(vch_sig: &[u8]) -> bool {
+    match vch_sig.last() {
+        Some(n_hash_type) => {
+            let base_type = *n_hash_type as u32 & !SIGHASH_ANYONECANPAY;
+            base_type >= SIGHASH_ALL && base_type <= SIGHASH_SINGLE
+        }
+        None => false,
+    }
+}
+
+fn check_signature_encoding(vch_sig: &[u8], flags: u32) -> Result<(), ScriptError> {
+    // Empty signature. Not strictly DER encoded, but allowed to provide a
+    // compact way to provide an invalid signature for use with CHECK(MULTI)SIG
+    if vch_sig.is_empty() || flags & SCRIPT_VERIFY_STRICTENC == 0 {
+        return Ok(());
+    }
+    if vch_sig.len() != SIGNATURE_SIZE || Signature::read(&vch_sig[..64]).is_err() {
+        return Err(ScriptError::SigEncoding);
+    }
+    if !is_defined_hashtype_signature(vch_sig) {
+        return Err(ScriptError::SigHashType);
+    }
+    Ok(())
+}
+
+fn check_pub_key_encoding(vch_pub_key: &[u8], flags: u32) -> Result<(), ScriptError> {
+    if flags & SCRIPT_VERIFY_STRICTENC != 0
+        && (vch_pub_key.len() != PUBLIC_KEY_SIZE
+            || PublicKey::<Bls12>::read(vch_pub_key, &JUBJUB).is_err())
+    {
+        return Err(ScriptError::PubKeyType);
+    }
+    Ok(())
+}
+
+//bool EvalScript(vector<vector<unsigned char> >& stack, const CScript& script, unsigned int flags,
+// const BaseSignatureChecker& checker, uint32_t consensusBranchId, ScriptError* serror)
+pub fn eval_script(
+    stack: &mut Vec<Vec<u8>>,
+    script: &Script,
+    flags: u32,
+    checker: &TransactionSignatureChecker,
+) -> Result<(), ScriptError> {
+    if script.len() > MAX_SCRIPT_SIZE {
+        return Err(ScriptError::ScriptSize);
+    }
+
+    let mut pc = 0;
+    let mut n_op_count = 0;
+    while pc < script.len() {
+        // Read instruction
+        let (opcode, data) = script.get_op(&mut pc).ok_or(ScriptError::BadOpcode)?;
+        if data.len() > MAX_SCRIPT_ELEMENT_SIZE {
+            return Err(ScriptError::PushSize);
+        }
+
+        // Pushes and OP_1NEGATE..OP_16 do not count towards the opcode limit.
+        if opcode > OP_16 {
+            n_op_count += 1;
+            if n_op_count > MAX_OPS_PER_SCRIPT {
+                return Err(ScriptError::OpCount);
+            }
+        }
+
+        if opcode <= OP_PUSHDATA4 {
+            if flags & SCRIPT_VERIFY_MINIMALDATA != 0 && !check_minimal_push(data, opcode) {
+                return Err(ScriptError::MinimalData);
+            }
+            stack.push(data.to_vec());
+        } else {
+            match opcode {
+                //
+                // Push value
+                //
+                OP_1NEGATE => stack.push(vec![0x81]),
+                OP_1..=OP_16 => stack.push(vec![opcode - (OP_1 - 1)]),
+
+                //
+                // Control
+                //
+                OP_NOP => {}
+                OP_VERIFY => {
+                    let vch = stack.pop().ok_or(ScriptError::InvalidStackOperation)?;
+                    if !cast_to_bool(&vch) {
+                        return Err(ScriptError::Verify);
+                    }
+                }
+                OP_RETURN => return Err(ScriptError::OpReturn),
+
+                //
+                // Stack ops
+                //
+                OP_DROP => {
+                    stack.pop().ok_or(ScriptError::InvalidStackOperation)?;
+                }
+                OP_DUP => {
+                    let vch = stack
+                        .last()
+                        .cloned()
+                        .ok_or(ScriptError::InvalidStackOperation)?;
+                    stack.push(vch);
+                }
+
+                //
+                // Bitwise logic
+                //
+                OP_EQUAL | OP_EQUALVERIFY => {
+                    if stack.len() < 2 {
+                        return Err(ScriptError::InvalidStackOperation);
+                    }
+                    let vch2 = stack.pop().unwrap();
+                    let vch1 = stack.pop().unwrap();
+                    let f_equal = vch1 == vch2;
+                    if opcode == OP_EQUALVERIFY {
+                        if !f_equal {
+                            return Err(ScriptError::EqualVerify);
+                        }
+                    } else {
+                        stack.push(encode_bool(f_equal));
+                    }
+                }
+
+                //
+                // Crypto
+                //
+                OP_HASH160 => {
+                    let vch = stack.pop().ok_or(ScriptError::InvalidStackOperation)?;
+                    stack.push(hash160(&vch).to_vec());
+                }
+                OP_CHECKSIG | OP_CHECKSIGVERIFY => {
+                    if stack.len() < 2 {
+                        return Err(ScriptError::InvalidStackOperation);
+                    }
+                    let vch_pub_key = stack.pop().unwrap();
+                    let vch_sig = stack.pop().unwrap();
+                    check_signature_encoding(&vch_sig, flags)?;
+                    check_pub_key_encoding(&vch_pub_key, flags)?;
+
+                    let f_success = checker.check_sig(&vch_sig, &vch_pub_key, script);
+                    if opcode == OP_CHECKSIGVERIFY {
+                        if !f_success {
+                            return Err(ScriptError::CheckSigVerify);
+                        }
+                    } else {
+                        stack.push(encode_bool(f_success));
+                    }
+                }
+
+                _ => return Err(ScriptError::BadOpcode),
+            }
+        }
+
+        // Size limits
+        if stack.len() > MAX_STACK_SIZE {
+            return Err(ScriptError::StackSize);
+        }
+    }
+    Ok(())
+}
+
+//bool VerifyScript(const CScript& scriptSig, const CScript& scriptPubKey, unsigned int flags,
+// const BaseSignatureChecker& checker, uint32_t consensusBranchId, ScriptError* serror)
+//Checks that input n_in of tx, spending amount, satisfies script_pub_key.
+pub fn verify_script(
+    script_sig: &Script,
+    script_pub_key: &Script,
+    flags: u32,
+    tx: &Transaction,
+    n_in: usize,
+    amount: CAmount,
+) -> Result<(), ScriptError> {
+    if flags & SCRIPT_VERIFY_SIGPUSHONLY != 0 && !script_sig.is_push_only() {
+        return Err(ScriptError::SigPushOnly);
+    }
+
+    let checker = TransactionSignatureChecker::new(tx, n_in, amount);
+    let mut stack = Vec::new();
+    eval_script(&mut stack, script_sig, flags, &checker)?;
+    eval_script(&mut stack, script_pub_key, flags, &checker)?;
+    match stack.last() {
+        Some(vch) if cast_to_bool(vch) => {}
+        _ => return Err(ScriptError::EvalFalse),
+    }
+
+    // The CLEANSTACK check is only performed after potential P2SH evaluation,
+    // as the non-P2SH evaluation of a P2SH script will obviously not result in
+    // a clean stack (the P2SH inputs remain).
+    if flags & SCRIPT_VERIFY_CLEANSTACK != 0 && stack.len() != 1 {
+        return Err(ScriptError::CleanStack);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::script::standard::{get_key_id, get_script_for_destination};
+    use crate::sendmany::SaplingOutPoint;
+    use crate::transaction::tests::transaction;
+    use crate::transaction::MutableTransaction;
+    use rand::{OsRng, Rand};
+    use sapling_crypto::jubjub::fs::Fs;
+    use sapling_crypto::redjubjub::PrivateKey;
+
+    fn keypair() -> (PrivateKey<Bls12>, Vec<u8>) {
+        let mut rng = OsRng::new().unwrap();
+        let sk = PrivateKey::<Bls12>(Fs::rand(&mut rng));
+        let pk = PublicKey::from_private(&sk, TRANSPARENT_KEY_GENERATOR, &JUBJUB);
+        let mut vch_pub_key = Vec::new();
+        pk.write(&mut vch_pub_key).unwrap();
+        (sk, vch_pub_key)
+    }
+
+    fn sign(
+        sk: &PrivateKey<Bls12>,
+        tx: &MutableTransaction,
+        script_code: &Script,
+        amount: i64,
+    ) -> Vec<u8> {
+        let mut rng = OsRng::new().unwrap();
+        let sighash = signature_hash(script_code, tx, 0, SIGHASH_ALL, amount);
+        let sig = sk.sign(&sighash, &mut rng, TRANSPARENT_KEY_GENERATOR, &JUBJUB);
+        let mut vch_sig = Vec::new();
+        sig.write(&mut vch_sig).unwrap();
+        vch_sig.push(SIGHASH_ALL as u8);
+        vch_sig
+    }
+
+    #[test]
+    fn pay_to_pubkey_hash() {
+        let (sk, vch_pub_key) = keypair();
+        let pk = PublicKey::<Bls12>::read(&vch_pub_key[..], &JUBJUB).unwrap();
+        let script_pub_key = get_script_for_destination(&get_key_id(&pk));
+
+        let mut tx = MutableTransaction::from(&transaction());
+        let vch_sig = sign(&sk, &tx, &script_pub_key, 1000);
+        tx.vin[0].script_sig = Script::new();
+        tx.vin[0]
+            .script_sig
+            .push_slice(&vch_sig)
+            .push_slice(&vch_pub_key);
+        let verify = |tx: &MutableTransaction, amount, flags| {
+            let tx = Transaction::from(tx.clone());
+            verify_script(
+                &tx.vin[0].script_sig,
+                &script_pub_key,
+                flags,
+                &tx,
+                0,
+                amount,
+            )
+        };
+        assert_eq!(verify(&tx, 1000, STANDARD_SCRIPT_VERIFY_FLAGS), Ok(()));
+
+        // The signature commits to the amount and to the spent outpoint
+        assert_eq!(
+            verify(&tx, 999, STANDARD_SCRIPT_VERIFY_FLAGS),
+            Err(ScriptError::EvalFalse)
+        );
+        let mut tx2 = tx.clone();
+        tx2.vin[0].prevout = SaplingOutPoint {
+            hash: tx.compute_hash(),
+            n: 0,
+        };
+        assert_eq!(
+            verify(&tx2, 1000, STANDARD_SCRIPT_VERIFY_FLAGS),
+            Err(ScriptError::EvalFalse)
+        );
+
+        // Another key doesn't match the hash
+        let (_, other_pub_key) = keypair();
+        tx2 = tx.clone();
+        tx2.vin[0].script_sig = Script::new();
+        tx2.vin[0]
+            .script_sig
+            .push_slice(&vch_sig)
+            .push_slice(&other_pub_key);
+        assert_eq!(
+            verify(&tx2, 1000, STANDARD_SCRIPT_VERIFY_FLAGS),
+            Err(ScriptError::EqualVerify)
+        );
+    }
+
+    #[test]
+    fn standard_flags_are_stricter() {
+        let (sk, vch_pub_key) = keypair();
+        let pk = PublicKey::<Bls12>::read(&vch_pub_key[..], &JUBJUB).unwrap();
+        let script_pub_key = get_script_for_destination(&get_key_id(&pk));
+        let mut tx = MutableTransaction::from(&transaction());
+        let vch_sig = sign(&sk, &tx, &script_pub_key, 1000);
+
+        // An extra element left on the stack
+        tx.vin[0].script_sig = Script::new();
+        tx.vin[0]
+            .script_sig
+            .push_opcode(OP_1)
+            .push_slice(&vch_sig)
+            .push_slice(&vch_pub_key);
+        let verify = |tx: &MutableTransaction, flags| {
+            let tx = Transaction::from(tx.clone());
+            verify_script(&tx.vin[0].script_sig, &script_pub_key, flags, &tx, 0, 1000)
+        };
+        assert_eq!(verify(&tx, MANDATORY_SCRIPT_VERIFY_FLAGS), Ok(()));
+        assert_eq!(
+            verify(&tx, STANDARD_SCRIPT_VERIFY_FLAGS),
+            Err(ScriptError::CleanStack)
+        );
+
+        // A non-push operation in the scriptSig
+        tx.vin[0].script_sig = Script::new();
+        tx.vin[0]
+            .script_sig
+            .push_slice(&vch_sig)
+            .push_slice(&vch_pub_key)
+            .push_opcode(OP_NOP);
+        assert_eq!(verify(&tx, MANDATORY_SCRIPT_VERIFY_FLAGS), Ok(()));
+        assert_eq!(
+            verify(&tx, STANDARD_SCRIPT_VERIFY_FLAGS),
+            Err(ScriptError::SigPushOnly)
+        );
+
+        // An undefined hash type
+        let mut bad_sig = vch_sig.clone();
+        bad_sig[64] = 0x7f;
+        tx.vin[0].script_sig = Script::new();
+        tx.vin[0]
+            .script_sig
+            .push_slice(&bad_sig)
+            .push_slice(&vch_pub_key);
+        assert_eq!(
+            verify(&tx, MANDATORY_SCRIPT_VERIFY_FLAGS),
+            Err(ScriptError::SigHashType)
+        );
+    }
+
+    #[test]
+    fn op_return_fails_evaluation() {
+        let tx = transaction();
+        let mut script_pub_key = Script::new();
+        script_pub_key.push_opcode(OP_RETURN).push_slice(b"data");
+        assert_eq!(
+            verify_script(
+                &Script::new(),
+                &script_pub_key,
+                SCRIPT_VERIFY_NONE,
+                &tx,
+                0,
+                0
+            ),
+            Err(ScriptError::OpReturn)
+        );
+    }
+
+    #[test]
+    fn stack_size_and_op_count_are_limited() {
+        let tx = transaction();
+        let checker = TransactionSignatureChecker::new(&tx, 0, 0);
+        let eval =
+            |script: &Script| eval_script(&mut Vec::new(), script, SCRIPT_VERIFY_NONE, &checker);
+
+        let mut script = Script::new();
+        for _ in 0..MAX_STACK_SIZE {
+            script.push_opcode(OP_1);
+        }
+        assert_eq!(eval(&script), Ok(()));
+        script.push_opcode(OP_1);
+        assert_eq!(eval(&script), Err(ScriptError::StackSize));
+
+        let mut script = Script::new();
+        for _ in 0..MAX_OPS_PER_SCRIPT {
+            script.push_opcode(OP_NOP);
+        }
+        assert_eq!(eval(&script), Ok(()));
+        script.push_opcode(OP_NOP);
+        assert_eq!(eval(&script), Err(ScriptError::OpCount));
+    }
+}
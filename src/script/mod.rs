@@ -0,0 +1,216 @@
+//! Transparent scripts: the byte representation with its opcodes, the interpreter that checks
+//! a `script_sig` against the `script_pub_key` it spends, and the standard script templates.
+//!
+//! There is no secp256k1 in the tree, so transparent keys are RedJubjub keys (see
+//! `interpreter::TRANSPARENT_KEY_GENERATOR`) and a `TxDestination` is the BLAKE2b-160 hash of
+//! the encoded key.
+
+use blake2_rfc::blake2b::Blake2b;
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use ethereum_types::H160;
+use std::io::{self, Read, Write};
+
+use crate::incremental_tree::serialize::Vector;
+
+pub mod interpreter;
+pub mod standard;
+
+pub use self::interpreter::{
+    verify_script, ScriptError, MANDATORY_SCRIPT_VERIFY_FLAGS, STANDARD_SCRIPT_VERIFY_FLAGS,
+};
+
+// Maximum number of bytes pushable to the stack
+pub const MAX_SCRIPT_ELEMENT_SIZE: usize = 520;
+
+// Maximum script length in bytes
+pub const MAX_SCRIPT_SIZE: usize = 10000;
+
+// Maximum number of non-push operations per script
+pub const MAX_OPS_PER_SCRIPT: usize = 201;
+
+// Maximum number of elements on the stack
+pub const MAX_STACK_SIZE: usize = 1000;
+
+// Threshold for nLockTime: below this value it is interpreted as block number,
+// otherwise as UNIX timestamp.
+pub const LOCKTIME_THRESHOLD: u32 = 500000000; // Tue Nov  5 00:53:20 1985 UTC
+
+/** Script opcodes */
+// push value
+pub const OP_0: u8 = 0x00;
+pub const OP_PUSHDATA1: u8 = 0x4c;
+pub const OP_PUSHDATA2: u8 = 0x4d;
+pub const OP_PUSHDATA4: u8 = 0x4e;
+pub const OP_1NEGATE: u8 = 0x4f;
+pub const OP_1: u8 = 0x51;
+pub const OP_16: u8 = 0x60;
+
+// control
+pub const OP_NOP: u8 = 0x61;
+pub const OP_VERIFY: u8 = 0x69;
+pub const OP_RETURN: u8 = 0x6a;
+
+// stack ops
+pub const OP_DROP: u8 = 0x75;
+pub const OP_DUP: u8 = 0x76;
+
+// bit logic
+pub const OP_EQUAL: u8 = 0x87;
+pub const OP_EQUALVERIFY: u8 = 0x88;
+
+// crypto
+pub const OP_HASH160: u8 = 0xa9;
+pub const OP_CHECKSIG: u8 = 0xac;
+pub const OP_CHECKSIGVERIFY: u8 = 0xad;
+
+const HASH160_PERSONALIZATION: &[u8; 16] = b"ice_Hash160_____";
+
+//uint160 Hash160(const T1 pbegin, const T1 pend)
+pub fn hash160(data: &[u8]) -> H160 {
+    let mut h = Blake2b::with_params(20, &[], &[], HASH160_PERSONALIZATION);
+    h.update(data);
+    H160::from_slice(h.finalize().as_bytes())
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Script(pub Vec<u8>);
+
+impl Script {
+    pub fn new() -> Self {
+        Script(Vec::new())
+    }
+
+    pub fn read<R: Read>(reader: R) -> io::Result<Self> {
+        Ok(Script(Vector::read(reader, |r| r.read_u8())?))
+    }
+
+    pub fn write<W: Write>(&self, writer: W) -> io::Result<()> {
+        Vector::write(writer, &self.0, |w, b| w.write_u8(*b))
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    pub fn push_opcode(&mut self, opcode: u8) -> &mut Self {
+        self.0.push(opcode);
+        self
+    }
+
+    //CScript& operator<<(const std::vector<unsigned char>& b)
+    pub fn push_slice(&mut self, data: &[u8]) -> &mut Self {
+        if data.len() < OP_PUSHDATA1 as usize {
+            self.0.push(data.len() as u8);
+        } else if data.len() <= 0xff {
+            self.0.push(OP_PUSHDATA1);
+            self.0.push(data.len() as u8);
+        } else if data.len() <= 0xffff {
+            self.0.push(OP_PUSHDATA2);
+            self.0.write_u16::<LittleEndian>(data.len() as u16).unwrap();
+        } else {
+            self.0.push(OP_PUSHDATA4);
+            self.0.write_u32::<LittleEndian>(data.len() as u32).unwrap();
+        }
+        self.0.extend_from_slice(data);
+        self
+    }
+
+    //bool GetScriptOp(CScriptBase::const_iterator& pc, CScriptBase::const_iterator end,
+    // opcodetype& opcodeRet, std::vector<unsigned char>* pvchRet)
+    //Reads the operation at pc and advances pc past it. Returns the opcode and the pushed data,
+    //which is empty for other opcodes, or None if the script ends in the middle of a push.
+    pub fn get_op(&self, pc: &mut usize) -> Option<(u8, &[u8])> {
+        let opcode = *self.0.get(*pc)?;
+        *pc += 1;
+        if opcode > OP_PUSHDATA4 {
+            return Some((opcode, &[]));
+        }
+
+        let n_size = if opcode < OP_PUSHDATA1 {
+            opcode as usize
+        } else {
+            let n_len_bytes = match opcode {
+                OP_PUSHDATA1 => 1,
+                OP_PUSHDATA2 => 2,
+                _ => 4,
+            };
+            let mut len_bytes = self.0.get(*pc..*pc + n_len_bytes)?;
+            *pc += n_len_bytes;
+            len_bytes.read_uint::<LittleEndian>(n_len_bytes).ok()? as usize
+        };
+        let data = self.0.get(*pc..pc.checked_add(n_size)?)?;
+        *pc += n_size;
+        Some((opcode, data))
+    }
+
+    //bool CScript::IsPushOnly() const
+    pub fn is_push_only(&self) -> bool {
+        let mut pc = 0;
+        while pc < self.0.len() {
+            match self.get_op(&mut pc) {
+                Some((opcode, _)) if opcode <= OP_16 => {}
+                _ => return false,
+            }
+        }
+        true
+    }
+
+    // Returns whether the script is guaranteed to fail at execution, regardless of the
+    // initial stack. This allows outputs to be pruned instantly when entering the UTXO set.
+    pub fn is_unspendable(&self) -> bool {
+        (!self.0.is_empty() && self.0[0] == OP_RETURN) || self.0.len() > MAX_SCRIPT_SIZE
+    }
+
+    pub fn clear(&mut self) {
+        self.0.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pushes_round_trip_through_get_op() {
+        let mut script = Script::new();
+        script
+            .push_opcode(OP_DUP)
+            .push_slice(&[7u8; 20])
+            .push_slice(&[8u8; 300])
+            .push_slice(&[]);
+
+        let mut pc = 0;
+        assert_eq!(script.get_op(&mut pc), Some((OP_DUP, &[][..])));
+        assert_eq!(script.get_op(&mut pc), Some((20, &[7u8; 20][..])));
+        assert_eq!(
+            script.get_op(&mut pc),
+            Some((OP_PUSHDATA2, &[8u8; 300][..]))
+        );
+        assert_eq!(script.get_op(&mut pc), Some((OP_0, &[][..])));
+        assert_eq!(pc, script.len());
+        assert_eq!(script.get_op(&mut pc), None);
+        assert!(!script.is_push_only());
+
+        // A push running past the end of the script
+        let truncated = Script(vec![OP_PUSHDATA1, 5, 1, 2]);
+        assert_eq!(truncated.get_op(&mut 0), None);
+        assert!(!truncated.is_push_only());
+
+        let mut data = Vec::new();
+        script.write(&mut data).unwrap();
+        assert_eq!(Script::read(&data[..]).unwrap(), script);
+    }
+
+    #[test]
+    fn op_return_is_unspendable() {
+        let mut script = Script::new();
+        script.push_opcode(OP_RETURN).push_slice(b"data");
+        assert!(script.is_unspendable());
+        script.clear();
+        assert!(!script.is_unspendable());
+    }
+}
@@ -0,0 +1,86 @@
+use pairing::bls12_381::Bls12;
+use sapling_crypto::redjubjub::PublicKey;
+
+use super::*;
+use crate::key::key_store::TxDestination;
+
+// Default setting for nMaxDatacarrierBytes. 80 bytes of data, +1 for OP_RETURN,
+// +2 for the pushdata opcodes.
+pub const MAX_OP_RETURN_RELAY: usize = 83;
+
+//CKeyID CPubKey::GetID() const
+pub fn get_key_id(pubkey: &PublicKey<Bls12>) -> TxDestination {
+    let mut vch_pub_key = Vec::new();
+    pubkey
+        .write(&mut vch_pub_key)
+        .expect("public key should serialize into a Vec");
+    hash160(&vch_pub_key)
+}
+
+//CScript GetScriptForDestination(const CTxDestination& dest)
+//Pay to public key hash: OP_DUP OP_HASH160 <hash> OP_EQUALVERIFY OP_CHECKSIG
+pub fn get_script_for_destination(dest: &TxDestination) -> Script {
+    let mut script = Script::new();
+    script
+        .push_opcode(OP_DUP)
+        .push_opcode(OP_HASH160)
+        .push_slice(&dest[..])
+        .push_opcode(OP_EQUALVERIFY)
+        .push_opcode(OP_CHECKSIG);
+    script
+}
+
+//A provably unspendable output carrying data.
+pub fn get_script_for_data(data: &[u8]) -> Script {
+    let mut script = Script::new();
+    script.push_opcode(OP_RETURN).push_slice(data);
+    script
+}
+
+//bool ExtractDestination(const CScript& scriptPubKey, CTxDestination& addressRet)
+pub fn extract_destination(script_pub_key: &Script) -> Option<TxDestination> {
+    let s = &script_pub_key.0;
+    if s.len() == 25
+        && s[0] == OP_DUP
+        && s[1] == OP_HASH160
+        && s[2] == 20
+        && s[23] == OP_EQUALVERIFY
+        && s[24] == OP_CHECKSIG
+    {
+        Some(TxDestination::from_slice(&s[3..23]))
+    } else {
+        None
+    }
+}
+
+//bool IsStandard(const CScript& scriptPubKey, txnouttype& whichType)
+pub fn is_standard(script_pub_key: &Script) -> bool {
+    if extract_destination(script_pub_key).is_some() {
+        return true;
+    }
+    // OP_RETURN followed by pushes only
+    script_pub_key.is_unspendable()
+        && script_pub_key.len() <= MAX_OP_RETURN_RELAY
+        && Script(script_pub_key.0[1..].to_vec()).is_push_only()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn destination_round_trip() {
+        let dest = TxDestination::from_slice(&[7u8; 20]);
+        let script = get_script_for_destination(&dest);
+        assert_eq!(extract_destination(&script), Some(dest));
+        assert!(is_standard(&script));
+        assert!(!script.is_unspendable());
+
+        let data = get_script_for_data(&[1u8; 80]);
+        assert_eq!(extract_destination(&data), None);
+        assert!(data.is_unspendable());
+        assert!(is_standard(&data));
+        assert!(!is_standard(&get_script_for_data(&[1u8; 81])));
+        assert!(!is_standard(&Script(vec![OP_DUP])));
+    }
+}
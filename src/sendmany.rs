@@ -1,25 +1,30 @@
 extern crate pairing;
 extern crate zip32;
 
-use pairing::bls12_381::{Bls12, Fr, FrRepr};
+use pairing::bls12_381::{Bls12, FrRepr};
 
 use ff::PrimeField;
 
-use sapling_crypto::primitives::{Note, PaymentAddress};
+use sapling_crypto::jubjub::fs::Fs;
+use sapling_crypto::primitives::{Diversifier, Note, PaymentAddress};
+use zcash_primitives::note_encryption::Memo;
 
 use ethereum_types::U256;
 
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
 use std::cmp::Eq;
-use std::collections::LinkedList;
+use std::collections::{HashSet, LinkedList};
 use std::hash::{Hash, Hasher};
+use std::io::{self, Read, Write};
 
+use crate::block_chain::Chain;
+use crate::incremental_tree::merkle_tree::Hashable;
 use crate::incremental_tree::tree::SaplingWitness;
-use crate::my::constants::ZC_MEMO_SIZE;
 use crate::wallet::Wallet;
 
 use crate::key::key_management::{
     FrHash, SaplingExpandedSpendingKey, SaplingExtendedSpendingKey, SaplingIncomingViewingKey,
-    SaplingNote,
+    SaplingNote, SaplingOutgoingViewingKey, SaplingPaymentAddress,
 };
 
 use crate::key::key_store::{KeyStore, TxDestination};
@@ -34,23 +39,39 @@ use crate::key::key_store::{decode_destination, decode_payment_address};
 
 pub struct SpendDescriptionInfo {
     pub expsk: SaplingExpandedSpendingKey,
+    pub diversifier: Diversifier,
     pub note: SaplingNote,
-    pub alpha: Fr,
-    pub ahchor: Fr,
+    pub alpha: Fs,
+    pub anchor: FrHash,
+    pub witness: SaplingWitness,
 }
 
 pub struct OutputDescriptionInfo {
-    pub ovk: U256,
+    pub ovk: SaplingOutgoingViewingKey,
+    pub to: SaplingPaymentAddress,
     pub note: SaplingNote,
-    pub memo: [char; ZC_MEMO_SIZE],
+    pub memo: Memo,
 }
 
-#[derive(PartialEq, Eq, Hash, Clone, Copy)]
+#[derive(PartialEq, Eq, Hash, Clone, Copy, Debug)]
 pub struct SaplingOutPoint {
     pub hash: FrHash, //U256,
     pub n: usize,
 }
 
+impl SaplingOutPoint {
+    pub fn read<R: Read>(mut reader: R) -> io::Result<Self> {
+        let hash = FrHash::read(&mut reader)?;
+        let n = reader.read_u32::<LittleEndian>()? as usize;
+        Ok(SaplingOutPoint { hash, n })
+    }
+
+    pub fn write<W: Write>(&self, mut writer: W) -> io::Result<()> {
+        self.hash.write(&mut writer)?;
+        writer.write_u32::<LittleEndian>(self.n as u32)
+    }
+}
+
 pub struct SaplingNoteData {
     /*
     std::list<SaplingWitness> witnesses;
@@ -68,6 +89,15 @@ pub struct SaplingNoteData {
 }
 
 impl SaplingNoteData {
+    pub fn new(ivk: SaplingIncomingViewingKey) -> Self {
+        SaplingNoteData {
+            witnesses: LinkedList::new(),
+            witnessHeight: -1,
+            ivk,
+            nullifier: None,
+        }
+    }
+
     pub fn push_front(&mut self, witness: SaplingWitness) {
         self.witnesses.push_front(witness);
     }
@@ -77,21 +107,40 @@ impl SaplingNoteData {
     }
 
     pub fn front(&self) -> Option<SaplingWitness> {
-        None
+        self.witnesses.front().cloned()
     }
 }
 
 pub struct SaplingNoteEntry {
-    op: SaplingOutPoint,
+    pub op: SaplingOutPoint,
     //PaymentAddress not sure is Bls12
-    address: PaymentAddress<Bls12>,
-    note: Note<Bls12>,
-    memo: [char; ZC_MEMO_SIZE],
-    confirmation: i32,
+    pub address: PaymentAddress<Bls12>,
+    pub note: Note<Bls12>,
+    pub memo: Memo,
+    pub confirmations: i32,
+}
+
+// A note we sent, recovered from out_ciphertext with one of our outgoing viewing keys.
+#[derive(Clone)]
+pub struct SaplingOutgoingNote {
+    pub address: PaymentAddress<Bls12>,
+    pub note: Note<Bls12>,
+    pub memo: Memo,
 }
 
 pub type CAmount = u64;
 
+// An empty memo means the default (no memo) value.
+pub fn get_memo_from_hex_string(s: &str) -> Option<Memo> {
+    if s.is_empty() {
+        return Some(Memo::default());
+    }
+    match hex::decode(s) {
+        Ok(bytes) => Memo::from_bytes(&bytes),
+        Err(_) => None,
+    }
+}
+
 pub type SendManyRecipient = (String, CAmount, String);
 
 pub fn show() {
@@ -101,7 +150,8 @@ pub fn show() {
 }
 
 pub struct SendMany<'a> {
-    pub main_wallet: &'a Wallet<'a>,
+    pub main_wallet: &'a Wallet,
+    pub chain_active: &'a Chain,
     //pub address_management: AddressManagement,
     pub key_store: KeyStore,
     pub sanity_checker: SanityChecker,
@@ -152,8 +202,7 @@ impl<'a> SendMany<'a> {
         //let context_info = vec![];
 
         //int nextBlockHeight = chainActive.Height() + 1;
-        //TODO
-        let next_block_height = 0;
+        let next_block_height = self.chain_active.height() + 1;
 
         let builder = TransactionBuilder::new(next_block_height, self.main_wallet);
 
@@ -162,8 +211,9 @@ impl<'a> SendMany<'a> {
         let expsk: Option<SaplingExpandedSpendingKey> = spending_key_option
             .and_then(|spending_key: SaplingExtendedSpendingKey| Some(spending_key.expsk));
 
-        let sendmany_operation = SendManyOperation::new(
+        let mut sendmany_operation = SendManyOperation::new(
             builder,
+            self.chain_active,
             fromaddress.clone(),
             taddrRecipients,
             zaddrRecipients,
@@ -194,6 +244,7 @@ pub struct SendManyOperation<'a> {
     t_outputs_: Vec<SendManyRecipient>,
     z_outputs_: Vec<SendManyRecipient>,
     transaction_builder_: TransactionBuilder<'a>,
+    chain_active: &'a Chain,
 
     spendingkey_: SaplingExpandedSpendingKey,
 
@@ -221,6 +272,7 @@ impl<'a> SendManyOperation<'a> {
 
     fn new(
         builder: TransactionBuilder<'a>,
+        chain_active: &'a Chain,
         /*contextualTx: MutableTransaction,*/
         fromaddress: String,
         t_outputs: Vec<SendManyRecipient>,
@@ -232,6 +284,7 @@ impl<'a> SendManyOperation<'a> {
     ) -> Self {
         SendManyOperation {
             transaction_builder_: builder,
+            chain_active,
             fromaddress_: fromaddress,
             t_outputs_: t_outputs,
             z_outputs_: z_outputs,
@@ -244,24 +297,61 @@ impl<'a> SendManyOperation<'a> {
         }
     }
 
-    pub fn main_impl(&self) {
-        let wallet = &self.transaction_builder_.wallet;
-        let mut target_amount = 100;
-        let result = self
-            .z_inputs_
-            .iter()
-            .try_fold((vec![], vec![], 0), |(mut a, mut b, s), t| {
-                if s < target_amount {
-                    a.push(&t.op);
-                    b.push(&t.note);
-                    Some((a, b, s + t.note.value))
-                } else {
-                    None
-                }
-            });
-        assert_eq!(result.is_none(), false);
+    //bool AsyncRPCOperation_sendmany::find_unspent_notes()
+    fn find_unspent_notes(&mut self) -> bool {
+        let mut filter_addresses = HashSet::new();
+        match decode_payment_address(&self.fromaddress_) {
+            Some(address) => {
+                filter_addresses.insert(address);
+            }
+            None => return false,
+        }
 
-        let (ops, notes, _) = result.unwrap();
+        self.z_inputs_ = self.transaction_builder_.wallet.get_filtered_notes(
+            self.chain_active,
+            &filter_addresses,
+            self.mindepth,
+            i32::max_value(),
+            true,
+            true,
+        );
+        // Spend the largest notes first
+        self.z_inputs_
+            .sort_by(|a, b| b.note.value.cmp(&a.note.value));
+        self.z_inputs_.len() > 0
+    }
+
+    pub fn main_impl(&mut self) {
+        if !self.find_unspent_notes() {
+            error!("SendManyOperation::main_impl(): no unspent notes found");
+            return;
+        }
+
+        let wallet = self.transaction_builder_.wallet;
+        let target_amount = self
+            .z_outputs_
+            .iter()
+            .chain(self.t_outputs_.iter())
+            .fold(self.fee_, |sum, (_, value, _)| sum + value);
+
+        let mut ops = vec![];
+        let mut entries = vec![];
+        let mut total = 0;
+        for t in self.z_inputs_.iter() {
+            if total >= target_amount {
+                break;
+            }
+            ops.push(&t.op);
+            entries.push(t);
+            total += t.note.value;
+        }
+        if total < target_amount {
+            error!(
+                "SendManyOperation::main_impl(): insufficient funds, have {}, need {}",
+                total, target_amount
+            );
+            return;
+        }
 
         let (witnesses, anchor) = wallet.get_sapling_note_witnesses(ops);
 
@@ -273,12 +363,16 @@ impl<'a> SendManyOperation<'a> {
                 }
                 Some(witness) => {
                     let t_ancher = anchor.clone();
-                    self.transaction_builder_.add_sapling_spend(
+                    if let Err(e) = self.transaction_builder_.add_sapling_spend(
                         &self.spendingkey_,
-                        notes[i],
+                        entries[i].address.diversifier,
+                        &entries[i].note,
                         t_ancher.unwrap(),
-                        witness,
-                    );
+                        (*witness).clone(),
+                    ) {
+                        error!("SendManyOperation::main_impl(): {}", e);
+                        return;
+                    }
                 }
             }
         }
@@ -298,12 +392,24 @@ impl<'a> SendManyOperation<'a> {
         //            builder_.AddSaplingOutput(ovk, to, value, memo);
         //        }
 
-        let ovk = &self.spendingkey_.ovk;
+        let ovk = self.spendingkey_.ovk;
 
         for (address, value, memo) in self.z_outputs_.iter() {
             let to = decode_payment_address(address);
-            self.transaction_builder_
-                .add_sapling_output(ovk, to.unwrap(), value, memo);
+            let memo = match get_memo_from_hex_string(memo) {
+                Some(memo) => memo,
+                None => {
+                    error!("SendManyOperation::main_impl(): invalid memo {}", memo);
+                    return;
+                }
+            };
+            if let Err(e) =
+                self.transaction_builder_
+                    .add_sapling_output(ovk, to.unwrap(), *value, memo)
+            {
+                error!("SendManyOperation::main_impl(): {}", e);
+                return;
+            }
         }
 
         //// Add transparent outputs
@@ -317,10 +423,17 @@ impl<'a> SendManyOperation<'a> {
         for (address, amount, memo) in self.t_outputs_.iter() {
             let addr = decode_destination(address);
             self.transaction_builder_
-                .add_transparent_output(addr.unwrap(), amount);
+                .add_transparent_output(addr.unwrap(), *amount);
         }
 
-        self.transaction_builder_.build();
+        self.transaction_builder_.set_fee(self.fee_);
+        let _tx = match self.transaction_builder_.build() {
+            Ok(tx) => tx,
+            Err(e) => {
+                error!("SendManyOperation::main_impl(): {}", e);
+                return;
+            }
+        };
 
         //TODO, Send out transaction
 
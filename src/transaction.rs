@@ -1,19 +1,35 @@
+use crate::block_chain::Chain;
+use crate::incremental_tree::serialize::Vector;
 use crate::key::key_management::{FrHash, SaplingOutputDescription, SaplingSpendDescription};
 use crate::script::Script;
 use crate::sendmany::CAmount;
 use crate::sendmany::SaplingNoteData;
 use crate::sendmany::SaplingOutPoint;
+use crate::sendmany::SaplingOutgoingNote;
 use crate::wallet::Wallet;
+use blake2_rfc::blake2b::Blake2b;
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
 use ethereum_types::U256;
+use ff::{Field, PrimeField, PrimeFieldRepr};
+use pairing::bls12_381::{Fr, FrRepr};
 use std::collections::HashMap;
+use std::io::{self, Read, Write};
+use std::ops::Deref;
+use std::sync::Arc;
 
 pub type NoteDataMap = HashMap<SaplingOutPoint, SaplingNoteData>;
 
+pub type OutgoingNoteMap = HashMap<SaplingOutPoint, SaplingOutgoingNote>;
+
 //Program cache
 pub struct WalletTransaction {
     //std::map<SaplingOutPoint, SaplingNoteData> mapSaplingData;
     pub mapSaplingData: NoteDataMap,
+    //Outputs of this transaction that we sent, see Wallet::find_my_sapling_outgoing_notes
+    pub mapSaplingOutgoing: OutgoingNoteMap,
     pub tx: Transaction,
+    //Height of the block containing the transaction, None while it is unconfirmed
+    pub block_height: Option<i32>,
     //pub p_wallet: &'a Wallet,
 }
 
@@ -21,23 +37,34 @@ impl WalletTransaction {
     pub fn new(tx: Transaction) -> Self {
         WalletTransaction {
             mapSaplingData: NoteDataMap::new(),
+            mapSaplingOutgoing: OutgoingNoteMap::new(),
             tx,
+            block_height: None,
             //p_wallet
         }
     }
 
+    //int CMerkleTx::GetDepthInMainChain() const
+    pub fn get_depth_in_main_chain(&self, chain_active: &Chain) -> i32 {
+        match self.block_height {
+            Some(height) => chain_active.height() - height + 1,
+            None => 0,
+        }
+    }
+
     //TODO
     pub fn bind_wallet(&self, p_wallet_in: &Wallet) {}
 }
 
 /** An inpoint - a combination of a transaction and an index n into its vin */
-pub struct SaplingInPoint<'a> {
-    pub ptx: &'a Transaction,
+#[derive(Clone)]
+pub struct SaplingInPoint {
+    pub ptx: Arc<Transaction>,
     pub n: usize,
 }
 
-impl<'a> SaplingInPoint<'a> {
-    pub fn new(tx: &'a Transaction, index: usize) -> Self {
+impl SaplingInPoint {
+    pub fn new(tx: Arc<Transaction>, index: usize) -> Self {
         SaplingInPoint { ptx: tx, n: index }
     }
 }
@@ -48,13 +75,51 @@ pub struct TxIn {
     pub script_sig: Script,
 }
 
-#[derive(Copy, Clone)]
+impl TxIn {
+    pub fn read<R: Read>(mut reader: R) -> io::Result<Self> {
+        let prevout = SaplingOutPoint::read(&mut reader)?;
+        let script_sig = Script::read(&mut reader)?;
+        Ok(TxIn {
+            prevout,
+            script_sig,
+        })
+    }
+
+    pub fn write<W: Write>(&self, mut writer: W) -> io::Result<()> {
+        self.prevout.write(&mut writer)?;
+        self.script_sig.write(&mut writer)
+    }
+}
+
+#[derive(Clone)]
 pub struct TxOut {
     pub n_value: i64,
     pub script_pub_key: Script,
 }
 
 impl TxOut {
+    pub fn read<R: Read>(mut reader: R) -> io::Result<Self> {
+        let n_value = reader.read_i64::<LittleEndian>()?;
+        let script_pub_key = Script::read(&mut reader)?;
+        Ok(TxOut {
+            n_value,
+            script_pub_key,
+        })
+    }
+
+    pub fn write<W: Write>(&self, mut writer: W) -> io::Result<()> {
+        writer.write_i64::<LittleEndian>(self.n_value)?;
+        self.script_pub_key.write(&mut writer)
+    }
+
+    // A spent output, as left in the vout of a Coins
+    pub fn null() -> Self {
+        TxOut {
+            n_value: -1,
+            script_pub_key: Script::new(),
+        }
+    }
+
     pub fn is_null(&self) -> bool {
         self.n_value == -1
     }
@@ -63,23 +128,255 @@ impl TxOut {
         self.script_pub_key.clear();
     }
 }
-//In DB and network
-#[derive(Clone)]
-pub struct Transaction {
-    pub hash: FrHash, //U256,
 
+pub const TX_VERSION: u32 = 1;
+
+// Expiry heights must be below this value, like lock_time block heights
+pub const TX_EXPIRY_HEIGHT_THRESHOLD: u32 = 500000000;
+
+const TXID_PERSONALIZATION: &[u8; 16] = b"ice_TxIdHash____";
+
+//CMutableTransaction. The contents of a transaction, which can be changed freely. They only
+//get a txid once they are turned into a Transaction.
+#[derive(Clone)]
+pub struct MutableTransaction {
+    pub n_version: u32,
     pub vin: Vec<TxIn>,
     pub vout: Vec<TxOut>,
+    //The block height (below LOCKTIME_THRESHOLD) or the time from which the transaction can be
+    //mined, 0 if it can be mined right away
+    pub lock_time: u32,
+    //The last block height the transaction can be mined at, 0 if it never expires
+    pub expiry_height: u32,
     pub v_shielded_spend: Vec<SaplingSpendDescription>,
     pub v_shielded_output: Vec<SaplingOutputDescription>,
     pub balancing_value: i64,
     pub binding_sig: [u8; 64],
 }
 
+impl MutableTransaction {
+    pub fn new(
+        vin: Vec<TxIn>,
+        vout: Vec<TxOut>,
+        v_shielded_spend: Vec<SaplingSpendDescription>,
+        v_shielded_output: Vec<SaplingOutputDescription>,
+        balancing_value: i64,
+        binding_sig: [u8; 64],
+    ) -> Self {
+        MutableTransaction {
+            n_version: TX_VERSION,
+            vin,
+            vout,
+            lock_time: 0,
+            expiry_height: 0,
+            v_shielded_spend,
+            v_shielded_output,
+            balancing_value,
+            binding_sig,
+        }
+    }
+
+    pub fn has_shielded(&self) -> bool {
+        !self.v_shielded_spend.is_empty() || !self.v_shielded_output.is_empty()
+    }
+
+    // The txid is a personalized BLAKE2b-256 digest of the serialized transaction. The
+    // top two bits are cleared so that the digest always fits in the scalar field.
+    pub fn compute_hash(&self) -> FrHash {
+        let mut data = Vec::new();
+        self.write(&mut data)
+            .expect("transaction should serialize into a Vec");
+
+        let mut h = Blake2b::with_params(32, &[], &[], TXID_PERSONALIZATION);
+        h.update(&data);
+        let mut repr = FrRepr::default();
+        repr.read_le(h.finalize().as_bytes())
+            .expect("digest is 32 bytes");
+        repr.as_mut()[3] &= 0x3fff_ffff_ffff_ffff;
+        FrHash(Fr::from_repr(repr).expect("reduced digest should be in the field"))
+    }
+
+    pub fn write<W: Write>(&self, mut writer: W) -> io::Result<()> {
+        writer.write_u32::<LittleEndian>(self.n_version)?;
+        Vector::write(&mut writer, &self.vin, |w, e| e.write(w))?;
+        Vector::write(&mut writer, &self.vout, |w, e| e.write(w))?;
+        writer.write_u32::<LittleEndian>(self.lock_time)?;
+        writer.write_u32::<LittleEndian>(self.expiry_height)?;
+        Vector::write(&mut writer, &self.v_shielded_spend, |w, e| e.write(w))?;
+        Vector::write(&mut writer, &self.v_shielded_output, |w, e| e.write(w))?;
+        writer.write_i64::<LittleEndian>(self.balancing_value)?;
+        // The binding signature is only present for transactions with shielded parts.
+        if self.has_shielded() {
+            writer.write_all(&self.binding_sig)?;
+        }
+        Ok(())
+    }
+}
+
+impl<'a> From<&'a Transaction> for MutableTransaction {
+    fn from(tx: &'a Transaction) -> Self {
+        tx.tx.clone()
+    }
+}
+
+//In DB and network. Its contents can only be read, so the txid always matches them.
+#[derive(Clone)]
+pub struct Transaction {
+    //Derived from the serialized transaction, see compute_hash
+    hash: FrHash, //U256,
+    tx: MutableTransaction,
+}
+
+impl From<MutableTransaction> for Transaction {
+    fn from(tx: MutableTransaction) -> Self {
+        Transaction {
+            hash: tx.compute_hash(),
+            tx,
+        }
+    }
+}
+
+impl Deref for Transaction {
+    type Target = MutableTransaction;
+
+    fn deref(&self) -> &MutableTransaction {
+        &self.tx
+    }
+}
+
 impl Transaction {
-    //TODO
+    pub fn new(
+        vin: Vec<TxIn>,
+        vout: Vec<TxOut>,
+        v_shielded_spend: Vec<SaplingSpendDescription>,
+        v_shielded_output: Vec<SaplingOutputDescription>,
+        balancing_value: i64,
+        binding_sig: [u8; 64],
+    ) -> Self {
+        Transaction::from(MutableTransaction::new(
+            vin,
+            vout,
+            v_shielded_spend,
+            v_shielded_output,
+            balancing_value,
+            binding_sig,
+        ))
+    }
+
+    // Scripts carry no data yet, so the coinbase commits to the height of its block (BIP34)
+    // through the index of its null prevout. This keeps coinbase txids unique.
+    pub fn new_coinbase(n_height: i32, vout: Vec<TxOut>) -> Self {
+        let txin = TxIn {
+            prevout: SaplingOutPoint {
+                hash: FrHash(Fr::zero()),
+                n: n_height as usize,
+            },
+            script_sig: Script::new(),
+        };
+        Transaction::new(vec![txin], vout, vec![], vec![], 0, [0u8; 64])
+    }
+
+    // The coinbase is the only transaction with a single input that spends nothing.
     pub fn is_coin_base(&self) -> bool {
-        false
+        self.vin.len() == 1 && self.vin[0].prevout.hash.0.is_zero()
+    }
+
+    pub fn get_coinbase_height(&self) -> Option<i32> {
+        if self.is_coin_base() {
+            Some(self.vin[0].prevout.n as i32)
+        } else {
+            None
+        }
+    }
+
+    //CAmount CTransaction::GetValueOut() const
+    //Value leaving the transparent pool, a negative balancing value moves value into the
+    //shielded pool.
+    pub fn get_value_out(&self) -> CAmount {
+        let n_value_out: CAmount = self.vout.iter().map(|out| out.n_value).sum();
+        if self.balancing_value < 0 {
+            n_value_out - self.balancing_value
+        } else {
+            n_value_out
+        }
+    }
+
+    //CAmount CTransaction::GetShieldedValueIn() const
+    pub fn get_shielded_value_in(&self) -> CAmount {
+        if self.balancing_value > 0 {
+            self.balancing_value
+        } else {
+            0
+        }
+    }
+
+    pub fn get_serialize_size(&self) -> usize {
+        let mut data = Vec::new();
+        self.write(&mut data)
+            .expect("transaction should serialize into a Vec");
+        data.len()
+    }
+
+    //unsigned int CTransaction::CalculateModifiedSize(unsigned int nTxSize) const
+    //The size without the part of each input that is needed to spend it, so that spending
+    //an output isn't penalized by the priority.
+    pub fn calculate_modified_size(&self, n_tx_size: usize) -> usize {
+        let mut n_tx_size = n_tx_size;
+        for txin in self.vin.iter() {
+            // 41 bytes for the prevout and the script length, 110 for a typical script_sig
+            let offset = 41 + txin.script_sig.len().min(110);
+            if n_tx_size > offset {
+                n_tx_size -= offset;
+            }
+        }
+        n_tx_size
+    }
+
+    //double CTransaction::ComputePriority(double dPriorityInputs, unsigned int nTxSize) const
+    pub fn compute_priority(&self, d_priority_inputs: f64, n_tx_size: usize) -> f64 {
+        let n_tx_size = self.calculate_modified_size(n_tx_size);
+        if n_tx_size == 0 {
+            return 0.0;
+        }
+        d_priority_inputs / n_tx_size as f64
+    }
+
+    pub fn hash(&self) -> FrHash {
+        self.hash
+    }
+
+    pub fn read<R: Read>(mut reader: R) -> io::Result<Self> {
+        let n_version = reader.read_u32::<LittleEndian>()?;
+        if n_version != TX_VERSION {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "unknown transaction version",
+            ));
+        }
+        let vin = Vector::read(&mut reader, |r| TxIn::read(r))?;
+        let vout = Vector::read(&mut reader, |r| TxOut::read(r))?;
+        let lock_time = reader.read_u32::<LittleEndian>()?;
+        let expiry_height = reader.read_u32::<LittleEndian>()?;
+        let v_shielded_spend = Vector::read(&mut reader, |r| SaplingSpendDescription::read(r))?;
+        let v_shielded_output = Vector::read(&mut reader, |r| SaplingOutputDescription::read(r))?;
+        let balancing_value = reader.read_i64::<LittleEndian>()?;
+
+        let mut binding_sig = [0u8; 64];
+        if !v_shielded_spend.is_empty() || !v_shielded_output.is_empty() {
+            reader.read_exact(&mut binding_sig)?;
+        }
+
+        Ok(Transaction::from(MutableTransaction {
+            n_version,
+            vin,
+            vout,
+            lock_time,
+            expiry_height,
+            v_shielded_spend,
+            v_shielded_output,
+            balancing_value,
+            binding_sig,
+        }))
     }
 }
 
@@ -89,3 +386,120 @@ pub struct TxUndo {
 }
 
 pub struct TxInUndo {}*/
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::block_chain::{Block, BlockHeader};
+
+    // Identity point, zero field element and zeroed proof / signature bytes.
+    pub(crate) fn spend_description() -> SaplingSpendDescription {
+        let mut data = vec![0u8; 32 * 4 + 192 + 64];
+        data[0] = 1;
+        data[68] = 7; // nullifier
+        data[96] = 1;
+        SaplingSpendDescription::read(&mut &data[..]).unwrap()
+    }
+
+    fn output_description() -> SaplingOutputDescription {
+        let mut data = vec![0u8; 32 * 3 + 580 + 80 + 192];
+        data[0] = 1;
+        data[64] = 1;
+        data[100] = 9; // enc_ciphertext
+        SaplingOutputDescription::read(&mut &data[..]).unwrap()
+    }
+
+    pub(crate) fn transaction() -> Transaction {
+        let txin = TxIn {
+            prevout: SaplingOutPoint {
+                hash: FrHash(Fr::one()),
+                n: 3,
+            },
+            script_sig: Script::new(),
+        };
+        let txout = TxOut {
+            n_value: 1000,
+            script_pub_key: Script::new(),
+        };
+        Transaction::new(
+            vec![txin],
+            vec![txout],
+            vec![spend_description()],
+            vec![output_description()],
+            -250,
+            [5u8; 64],
+        )
+    }
+
+    fn serialize(tx: &Transaction) -> Vec<u8> {
+        let mut data = Vec::new();
+        tx.write(&mut data).unwrap();
+        data
+    }
+
+    #[test]
+    fn transaction_round_trip() {
+        let mut mtx = MutableTransaction::from(&transaction());
+        mtx.lock_time = 17;
+        mtx.expiry_height = 40;
+        let tx = Transaction::from(mtx);
+        let data = serialize(&tx);
+
+        let tx2 = Transaction::read(&data[..]).unwrap();
+        assert_eq!(serialize(&tx2), data);
+        assert_eq!(tx2.hash(), tx.hash());
+        assert_eq!(tx2.lock_time, 17);
+        assert_eq!(tx2.expiry_height, 40);
+        assert_eq!(tx2.vin[0].prevout.n, 3);
+        assert_eq!(tx2.vout[0].n_value, 1000);
+        assert_eq!(tx2.v_shielded_spend[0].nullifier[4], 7);
+        assert_eq!(tx2.balancing_value, -250);
+        assert_eq!(&tx2.binding_sig[..], &[5u8; 64][..]);
+
+        // Missing bytes are rejected. Reading stops at the end of the transaction, so any
+        // bytes after it are left for the next item of the stream, e.g. in a block.
+        assert!(Transaction::read(&data[..data.len() - 1]).is_err());
+        let mut stream = data.clone();
+        stream.push(0xab);
+        let mut reader = &stream[..];
+        assert_eq!(Transaction::read(&mut reader).unwrap().hash(), tx.hash());
+        assert_eq!(reader, &[0xab][..]);
+    }
+
+    #[test]
+    fn transparent_transaction_has_no_binding_sig() {
+        let mut mtx = MutableTransaction::from(&transaction());
+        mtx.v_shielded_spend.clear();
+        mtx.v_shielded_output.clear();
+        let tx = Transaction::from(mtx);
+        let data = serialize(&tx);
+
+        let tx2 = Transaction::read(&data[..]).unwrap();
+        assert_eq!(tx2.hash(), tx.hash());
+        assert_eq!(&tx2.binding_sig[..], &[0u8; 64][..]);
+    }
+
+    #[test]
+    fn txid_commits_to_contents() {
+        let tx = transaction();
+        let mut mtx = MutableTransaction::from(&tx);
+        mtx.vout[0].n_value += 1;
+        let tx2 = Transaction::from(mtx);
+        assert!(tx.hash() != tx2.hash());
+        assert_eq!(tx.compute_hash(), tx.hash());
+    }
+
+    #[test]
+    fn block_round_trip() {
+        let block = Block {
+            header: BlockHeader::new(),
+            vtx: vec![transaction(), transaction()],
+        };
+        let mut data = Vec::new();
+        block.write(&mut data).unwrap();
+
+        let block2 = Block::read(&data[..]).unwrap();
+        assert_eq!(block2.vtx.len(), 2);
+        assert_eq!(block2.vtx[1].hash(), block.vtx[1].hash());
+    }
+}
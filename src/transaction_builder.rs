@@ -1,26 +1,94 @@
-use ethereum_types::U256;
+use bellman::groth16::create_random_proof;
+use ff::Field;
+use pairing::bls12_381::Bls12;
+use rand::{OsRng, Rng};
+use sapling_crypto::circuit::sapling::{Output, Spend};
+use sapling_crypto::jubjub::{fs::Fs, FixedGenerators};
+use sapling_crypto::primitives::{Diversifier, ValueCommitment};
+use sapling_crypto::redjubjub::{PrivateKey, PublicKey};
+use std::fmt;
+use zcash_primitives::note_encryption::{Memo, SaplingNoteEncryption};
+use zcash_primitives::JUBJUB;
 
 use crate::sendmany::{CAmount, OutputDescriptionInfo, SpendDescriptionInfo};
 
 use crate::key::key_management::{
-    FrHash, SaplingExpandedSpendingKey, SaplingIncomingViewingKey, SaplingNote,
-    SaplingOutgoingViewingKey, SaplingPaymentAddress,
+    FrHash, SaplingExpandedSpendingKey, SaplingNote, SaplingOutgoingViewingKey,
+    SaplingOutputDescription, SaplingPaymentAddress, SaplingSpendDescription,
 };
 
 use crate::key::key_store::TxDestination;
 
+use crate::incremental_tree::sapling::{binding_sig, spend_sig};
 use crate::incremental_tree::tree::SaplingWitness;
 
+use crate::main_impl::{signature_hash, NOT_AN_INPUT, SIGHASH_ALL};
+use crate::my::constants::DEFAULT_TX_EXPIRY_DELTA;
+use crate::script::standard::get_script_for_destination;
+use crate::script::Script;
+use crate::transaction::{MutableTransaction, Transaction, TxOut};
 use crate::wallet::Wallet;
+use crate::zkp::{OUTPUT_PARAM, SPEND_PARAM};
+
+const GROTH_PROOF_SIZE: usize = 48 + 96 + 48;
+
+pub const DEFAULT_FEE: CAmount = 10000;
+
+#[derive(Debug, PartialEq)]
+pub enum Error {
+    AnchorMismatch,
+    ChangeIsNegative(i64),
+    InvalidAddress,
+    NoChangeAddress,
+    MissingWitnessPath,
+    SpendProof,
+    OutputProof,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::AnchorMismatch => {
+                write!(f, "Anchor does not match previously-added Sapling spends.")
+            }
+            Error::ChangeIsNegative(change) => write!(f, "Change is negative: {}", change),
+            Error::InvalidAddress => write!(f, "Invalid payment address"),
+            Error::NoChangeAddress => write!(f, "Could not determine change address"),
+            Error::MissingWitnessPath => write!(f, "Sapling witness has no authentication path"),
+            Error::SpendProof => write!(f, "Failed to create Sapling spend proof"),
+            Error::OutputProof => write!(f, "Failed to create Sapling output proof"),
+        }
+    }
+}
 
 pub struct TransactionBuilder<'a> {
     pub spends: Vec<SpendDescriptionInfo>,
     pub outputs: Vec<OutputDescriptionInfo>,
-    pub wallet: &'a Wallet<'a>,
+    pub vout: Vec<TxOut>,
+    pub wallet: &'a Wallet,
     pub next_block_height: i32,
+    pub fee: CAmount,
+    // Sum of the spent note values minus the sum of the output note values.
+    pub value_balance: i64,
 }
 
 impl<'a> TransactionBuilder<'a> {
+    pub fn new(height: i32, wallet: &'a Wallet) -> Self {
+        TransactionBuilder {
+            spends: Vec::new(),
+            outputs: Vec::new(),
+            vout: Vec::new(),
+            next_block_height: height,
+            wallet: wallet,
+            fee: DEFAULT_FEE,
+            value_balance: 0,
+        }
+    }
+
+    pub fn set_fee(&mut self, fee: CAmount) {
+        self.fee = fee;
+    }
+
     /*
     void TransactionBuilder::AddSaplingSpend(
         libzcash::SaplingExpandedSpendingKey expsk,
@@ -42,23 +110,31 @@ impl<'a> TransactionBuilder<'a> {
         mtx.valueBalance += note.value();
     }
     */
-    pub fn new(height: i32, wallet: &'a Wallet) -> Self {
-        TransactionBuilder {
-            spends: Vec::new(),
-            outputs: Vec::new(),
-            next_block_height: height,
-            wallet: wallet,
-        }
-    }
-
+    //The diversifier of the address that received the note is needed to prove the spend.
     pub fn add_sapling_spend(
-        &self,
+        &mut self,
         expsk: &SaplingExpandedSpendingKey,
+        diversifier: Diversifier,
         note: &SaplingNote,
         anchor: FrHash,
-        witness: &&SaplingWitness,
-    ) {
+        witness: SaplingWitness,
+    ) -> Result<(), Error> {
+        // Consistency check: all anchors must equal the first one
+        if self.spends.len() > 0 && self.spends[0].anchor != anchor {
+            return Err(Error::AnchorMismatch);
+        }
 
+        let mut rng = OsRng::new().expect("should be able to construct RNG");
+        self.spends.push(SpendDescriptionInfo {
+            expsk: expsk.clone(),
+            diversifier,
+            note: note.clone(),
+            alpha: rng.gen(),
+            anchor,
+            witness,
+        });
+        self.value_balance += note.value as i64;
+        Ok(())
     }
 
     //void TransactionBuilder::AddSaplingOutput(
@@ -67,19 +143,322 @@ impl<'a> TransactionBuilder<'a> {
     //    CAmount value,
     //    std::array<unsigned char, ZC_MEMO_SIZE> memo)
     //{
-
+    //    auto note = libzcash::SaplingNote(to, value);
+    //    outputs.emplace_back(ovk, note, memo);
+    //    mtx.valueBalance -= value;
+    //}
     pub fn add_sapling_output(
-        &self,
-        ovk: &SaplingOutgoingViewingKey,
+        &mut self,
+        ovk: SaplingOutgoingViewingKey,
         to: SaplingPaymentAddress,
-        value: &CAmount,
-        meme: &String,
+        value: CAmount,
+        memo: Memo,
+    ) -> Result<(), Error> {
+        self.outputs.push(output_info(ovk, to, value, memo)?);
+        self.value_balance -= value as i64;
+        Ok(())
+    }
+
+    //AddTransparentOutput
+    pub fn add_transparent_output(&mut self, address: TxDestination, amount: CAmount) {
+        self.vout.push(TxOut {
+            n_value: amount as i64,
+            script_pub_key: get_script_for_destination(&address),
+        });
+    }
+
+    //boost::optional<CTransaction> TransactionBuilder::Build()
+    //The change output only exists in the built transaction, the builder is left unchanged.
+    pub fn build(&self) -> Result<Transaction, Error> {
+        //
+        // Consistency checks
+        //
+
+        // Valid change
+        let transparent_out: i64 = self.vout.iter().map(|txout| txout.n_value).sum();
+        let change = self.value_balance - self.fee as i64 - transparent_out;
+        if change < 0 {
+            return Err(Error::ChangeIsNegative(change));
+        }
+
+        //
+        // Change output
+        //
+
+        // Send change back to the address of the first Sapling spend.
+        let mut change_output = None;
+        if change > 0 {
+            let (ovk, change_addr) = match self.spends.first() {
+                Some(spend) => {
+                    let proof_generation_key = spend.expsk.proof_generation_key(&JUBJUB);
+                    let addr = proof_generation_key
+                        .into_viewing_key(&JUBJUB)
+                        .into_payment_address(spend.diversifier, &JUBJUB);
+                    match addr {
+                        Some(addr) => (spend.expsk.ovk, addr),
+                        None => return Err(Error::NoChangeAddress),
+                    }
+                }
+                None => return Err(Error::NoChangeAddress),
+            };
+            change_output = Some(output_info(
+                ovk,
+                change_addr,
+                change as CAmount,
+                Memo::default(),
+            )?);
+        }
+        let outputs: Vec<&OutputDescriptionInfo> =
+            self.outputs.iter().chain(change_output.iter()).collect();
+        let value_balance = self.value_balance - change;
+
+        let mut rng = OsRng::new().expect("should be able to construct RNG");
+
+        // Sum of the value commitment randomness, used as the binding signing key.
+        let mut bsk = Fs::zero();
+
+        //
+        // Sapling spends and outputs
+        //
+
+        let mut v_shielded_spend = Vec::with_capacity(self.spends.len());
+        for spend in &self.spends {
+            let proof_generation_key = spend.expsk.proof_generation_key(&JUBJUB);
+            let viewing_key = proof_generation_key.into_viewing_key(&JUBJUB);
+            let payment_address = match viewing_key.into_payment_address(spend.diversifier, &JUBJUB)
+            {
+                Some(addr) => addr,
+                None => return Err(Error::InvalidAddress),
+            };
+            let path = match spend.witness.path() {
+                Some(path) => path,
+                None => return Err(Error::MissingWitnessPath),
+            };
+
+            let mut nullifier = [0u8; 32];
+            nullifier.copy_from_slice(&spend.note.nf(&viewing_key, path.position, &JUBJUB));
+
+            let rcv: Fs = rng.gen();
+            bsk.add_assign(&rcv);
+            let value_commitment = ValueCommitment::<Bls12> {
+                value: spend.note.value,
+                randomness: rcv,
+            };
+            let cv = value_commitment.cm(&JUBJUB).into();
+
+            let rk = PublicKey::<Bls12>(proof_generation_key.ak.clone().into()).randomize(
+                spend.alpha,
+                FixedGenerators::SpendingKeyGenerator,
+                &JUBJUB,
+            );
+
+            let instance = Spend {
+                params: &*JUBJUB,
+                value_commitment: Some(value_commitment),
+                proof_generation_key: Some(proof_generation_key),
+                payment_address: Some(payment_address),
+                commitment_randomness: Some(spend.note.r),
+                ar: Some(spend.alpha),
+                auth_path: path
+                    .auth_path
+                    .iter()
+                    .map(|n| n.map(|(node, b)| (node.0, b)))
+                    .collect(),
+                anchor: Some(spend.anchor.0),
+            };
+            let proof = match create_random_proof(instance, &*SPEND_PARAM, &mut rng) {
+                Ok(proof) => proof,
+                Err(_) => return Err(Error::SpendProof),
+            };
+            let mut zkproof = [0u8; GROTH_PROOF_SIZE];
+            proof
+                .write(&mut zkproof[..])
+                .expect("should be able to serialize a proof");
+
+            v_shielded_spend.push(SaplingSpendDescription {
+                cv,
+                anchor: spend.anchor.0,
+                nullifier,
+                rk,
+                zkproof,
+                spend_auth_sig: None,
+            });
+        }
+
+        let mut v_shielded_output = Vec::with_capacity(outputs.len());
+        for output in outputs {
+            let encryptor = SaplingNoteEncryption::new(
+                output.ovk,
+                output.note.clone(),
+                output.to.clone(),
+                output.memo.clone(),
+            );
+
+            let rcv: Fs = rng.gen();
+            bsk.sub_assign(&rcv);
+            let value_commitment = ValueCommitment::<Bls12> {
+                value: output.note.value,
+                randomness: rcv,
+            };
+            let cv = value_commitment.cm(&JUBJUB).into();
+
+            let instance = Output {
+                params: &*JUBJUB,
+                value_commitment: Some(value_commitment),
+                payment_address: Some(output.to.clone()),
+                commitment_randomness: Some(output.note.r),
+                esk: Some(encryptor.esk().clone()),
+            };
+            let proof = match create_random_proof(instance, &*OUTPUT_PARAM, &mut rng) {
+                Ok(proof) => proof,
+                Err(_) => return Err(Error::OutputProof),
+            };
+            let mut zkproof = [0u8; GROTH_PROOF_SIZE];
+            proof
+                .write(&mut zkproof[..])
+                .expect("should be able to serialize a proof");
+
+            let cmu = output.note.cm(&JUBJUB);
+            let enc_ciphertext = encryptor.encrypt_note_plaintext();
+            let out_ciphertext = encryptor.encrypt_outgoing_plaintext(&cv, &cmu);
+
+            v_shielded_output.push(SaplingOutputDescription {
+                cv,
+                cmu,
+                ephemeral_key: encryptor.epk().clone().into(),
+                enc_ciphertext,
+                out_ciphertext,
+                zkproof,
+            });
+        }
+
+        let mut mtx = MutableTransaction::new(
+            Vec::new(),
+            self.vout.clone(),
+            v_shielded_spend,
+            v_shielded_output,
+            value_balance,
+            [0u8; 64],
+        );
+        mtx.expiry_height = (self.next_block_height + DEFAULT_TX_EXPIRY_DELTA) as u32;
+
+        //
+        // Signatures
+        //
+
+        let sighash = signature_hash(&Script::new(), &mtx, NOT_AN_INPUT, SIGHASH_ALL, 0);
+
+        // Create Sapling spendAuth and binding signatures
+        for (spend, desc) in self.spends.iter().zip(mtx.v_shielded_spend.iter_mut()) {
+            desc.spend_auth_sig = Some(spend_sig(
+                PrivateKey(spend.expsk.ask),
+                spend.alpha,
+                &sighash,
+                &JUBJUB,
+            ));
+        }
+        if mtx.has_shielded() {
+            binding_sig(bsk, &sighash, &JUBJUB)
+                .write(&mut mtx.binding_sig[..])
+                .expect("binding signature should be 64 bytes");
+        }
+
+        Ok(Transaction::from(mtx))
+    }
+}
+
+fn output_info(
+    ovk: SaplingOutgoingViewingKey,
+    to: SaplingPaymentAddress,
+    value: CAmount,
+    memo: Memo,
+) -> Result<OutputDescriptionInfo, Error> {
+    let mut rng = OsRng::new().expect("should be able to construct RNG");
+    let note = match to.create_note(value, rng.gen(), &JUBJUB) {
+        Some(note) => note,
+        None => return Err(Error::InvalidAddress),
+    };
+    Ok(OutputDescriptionInfo {
+        ovk,
+        to,
+        note,
+        memo,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::incremental_tree::tree::SaplingMerkleTree;
+    use crate::key::key_management::{SaplingExtendedFullViewingKey, SaplingExtendedSpendingKey};
+
+    fn spend_inputs(
+        value: u64,
+    ) -> (
+        SaplingExpandedSpendingKey,
+        SaplingPaymentAddress,
+        SaplingNote,
     ) {
+        let extsk = SaplingExtendedSpendingKey::master(&[7u8; 32]);
+        let (_, address) = SaplingExtendedFullViewingKey::from(&extsk)
+            .default_address()
+            .unwrap();
+        let mut rng = OsRng::new().unwrap();
+        let note = address.create_note(value, rng.gen(), &JUBJUB).unwrap();
+        (extsk.expsk, address, note)
+    }
+
+    #[test]
+    fn spends_must_share_an_anchor() {
+        let wallet = Wallet::new();
+        let mut builder = TransactionBuilder::new(1, &wallet);
 
+        let (expsk, address, note) = spend_inputs(50000);
+        let mut tree = SaplingMerkleTree::new();
+        tree.append(FrHash(note.cm(&JUBJUB))).unwrap();
+        let witness = tree.witness();
+
+        assert!(builder
+            .add_sapling_spend(
+                &expsk,
+                address.diversifier,
+                &note,
+                tree.root(),
+                witness.clone()
+            )
+            .is_ok());
+        assert_eq!(
+            builder.add_sapling_spend(
+                &expsk,
+                address.diversifier,
+                &note,
+                SaplingMerkleTree::empty_root(),
+                witness
+            ),
+            Err(Error::AnchorMismatch)
+        );
+        assert_eq!(builder.value_balance, 50000);
     }
 
-    //AddTransparentOutput
-    pub fn add_transparent_output(&self, address: TxDestination, amount: &CAmount) {}
+    #[test]
+    fn change_cannot_be_negative() {
+        let wallet = Wallet::new();
+        let mut builder = TransactionBuilder::new(1, &wallet);
 
-    pub fn build(&self) {}
+        let (expsk, address, _) = spend_inputs(0);
+        builder
+            .add_sapling_output(expsk.ovk, address, 1000, Memo::default())
+            .unwrap();
+        assert_eq!(builder.value_balance, -1000);
+        assert_eq!(
+            builder.build().err(),
+            Some(Error::ChangeIsNegative(-1000 - DEFAULT_FEE as i64))
+        );
+
+        // Without spends there is no address to send change to.
+        let mut builder = TransactionBuilder::new(1, &wallet);
+        builder.add_transparent_output(TxDestination::zero(), 0);
+        builder.value_balance = DEFAULT_FEE as i64 + 1;
+        assert_eq!(builder.build().err(), Some(Error::NoChangeAddress));
+    }
 }
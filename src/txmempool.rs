@@ -1,50 +1,451 @@
 use crate::amount::FeeRate;
-use crate::key::key_management::FrHash;
-use crate::sendmany::SaplingOutPoint;
+use crate::block_chain::Chain;
+use crate::coins::{CoinViewCache, Coins, CoinsView};
+use crate::incremental_tree::tree::SaplingMerkleTree;
+use crate::key::key_management::{FrHash, SaplingOutputDescription, SaplingSpendDescription};
+use crate::main_impl::{check_final_tx, is_expired_tx};
+use crate::my::constants::COINBASE_MATURITY;
+use crate::sendmany::{CAmount, SaplingOutPoint};
 use crate::transaction::SaplingInPoint;
-use crate::transaction::Transaction;
+use crate::transaction::{Transaction, TxIn, TxOut};
 use ethereum_types::U256;
-use std::collections::{HashMap, VecDeque};
-
-pub struct TxMemPoolEntry<'a> {
-    tx: &'a Transaction,
-    n_fee: u32,
-    n_mode_size: u32,
-    n_usage_size: u32,
-    fee_rate: FeeRate,
-    n_time: i64,
-    d_priority: f64,
-    n_height: u32,
-    had_no_dependencies: bool,
-    spends_coinbase: bool,
+use ff::PrimeField;
+use std::cmp::Ordering;
+use std::collections::{btree_set, BTreeSet, HashMap, HashSet, VecDeque};
+use std::iter::{Peekable, Rev};
+use std::mem;
+use std::sync::{Arc, Mutex, MutexGuard};
+
+/** Fake height value used in CCoins to signify they are only in the memory pool */
+pub const MEMPOOL_HEIGHT: i32 = 0x7FFFFFFF;
+/** Default for -maxmempool, maximum megabytes of mempool memory usage */
+pub const DEFAULT_MAX_MEMPOOL_SIZE: usize = 300;
+/** Default for -mempoolreplacement, whether conflicting transactions may be replaced */
+pub const DEFAULT_ENABLE_REPLACEMENT: bool = false;
+/** Maximum number of transactions a replacement may evict, descendants included */
+pub const MAX_REPLACEMENT_CANDIDATES: usize = 100;
+/** Default for -incrementalrelayfee, the fee rate a replacement pays on top of what it evicts */
+pub const DEFAULT_INCREMENTAL_RELAY_FEE: CAmount = 1000;
+/** Default for -limitancestorcount, max number of in-mempool ancestors */
+pub const DEFAULT_ANCESTOR_LIMIT: usize = 25;
+/** Default for -limitancestorsize, maximum kilobytes of tx + all in-mempool ancestors */
+pub const DEFAULT_ANCESTOR_SIZE_LIMIT: usize = 101;
+/** Default for -limitdescendantcount, max number of in-mempool descendants */
+pub const DEFAULT_DESCENDANT_LIMIT: usize = 25;
+/** Default for -limitdescendantsize, maximum kilobytes of in-mempool descendants */
+pub const DEFAULT_DESCENDANT_SIZE_LIMIT: usize = 101;
+
+//The limits on unconfirmed chains a transaction may join, the counts and sizes include the
+//transaction itself.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PackageLimits {
+    pub n_ancestor_count: usize,
+    pub n_ancestor_size: usize,
+    pub n_descendant_count: usize,
+    pub n_descendant_size: usize,
+}
+
+impl Default for PackageLimits {
+    fn default() -> Self {
+        PackageLimits {
+            n_ancestor_count: DEFAULT_ANCESTOR_LIMIT,
+            n_ancestor_size: DEFAULT_ANCESTOR_SIZE_LIMIT * 1000,
+            n_descendant_count: DEFAULT_DESCENDANT_LIMIT,
+            n_descendant_size: DEFAULT_DESCENDANT_SIZE_LIMIT * 1000,
+        }
+    }
+}
+
+//size_t RecursiveDynamicUsage(const CTransaction& tx)
+//An estimate of the memory a transaction and everything it owns takes up.
+pub fn recursive_dynamic_usage(tx: &Transaction) -> usize {
+    let vin: usize = tx
+        .vin
+        .iter()
+        .map(|txin| mem::size_of::<TxIn>() + txin.script_sig.len())
+        .sum();
+    let vout: usize = tx
+        .vout
+        .iter()
+        .map(|txout| mem::size_of::<TxOut>() + txout.script_pub_key.len())
+        .sum();
+    mem::size_of::<Transaction>()
+        + vin
+        + vout
+        + tx.v_shielded_spend.len() * mem::size_of::<SaplingSpendDescription>()
+        + tx.v_shielded_output.len() * mem::size_of::<SaplingOutputDescription>()
+}
+
+//Orders n_fee_a / n_size_a against n_fee_b / n_size_b without rounding them to a FeeRate.
+fn compare_fee_rate(
+    n_fee_a: CAmount,
+    n_size_a: usize,
+    n_fee_b: CAmount,
+    n_size_b: usize,
+) -> Ordering {
+    (n_fee_a as i128 * n_size_b as i128).cmp(&(n_fee_b as i128 * n_size_a as i128))
+}
+
+//A key of the fee rate indexes of the mempool, ordered by n_fees / n_size as in
+//compare_fee_rate. Ties are broken by the txid, so every entry has a key of its own.
+#[derive(Clone, Copy, Debug)]
+struct FeeRateKey {
+    n_fees: CAmount,
+    n_size: usize,
+    hash: FrHash,
+}
+
+impl Ord for FeeRateKey {
+    fn cmp(&self, other: &Self) -> Ordering {
+        compare_fee_rate(self.n_fees, self.n_size, other.n_fees, other.n_size)
+            .then_with(|| self.hash.0.into_repr().cmp(&other.hash.0.into_repr()))
+    }
+}
+
+impl PartialOrd for FeeRateKey {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for FeeRateKey {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for FeeRateKey {}
+
+pub struct TxMemPoolEntry {
+    tx: Arc<Transaction>,
+    n_fee: CAmount,            // Cached to avoid expensive parent-transaction lookups
+    n_tx_size: usize,          // ... and avoid recomputing tx size
+    n_mode_size: usize,        // ... and modified size for priority
+    n_usage_size: usize,       // ... and total memory usage
+    fee_rate: FeeRate,         // ... and fee per kB
+    n_time: i64,               // Local time when entering the mempool
+    d_priority: f64,           // Priority when entering the mempool
+    n_height: u32,             // Chain height when entering the mempool
+    had_no_dependencies: bool, // Not dependent on any other txs when it entered the mempool
+    spends_coinbase: bool,     // Keep track of transactions that spend a coinbase
     n_branch_id: u32,
+
+    // Information about descendants of this transaction that are in the
+    // mempool; if we remove this transaction we must remove all of these
+    // descendants as well. These include the transaction itself.
+    n_count_with_descendants: usize,
+    n_size_with_descendants: usize,
+    n_fees_with_descendants: CAmount,
+
+    // Analogous statistics for ancestor transactions
+    n_count_with_ancestors: usize,
+    n_size_with_ancestors: usize,
+    n_fees_with_ancestors: CAmount,
 }
 
-impl<'a> TxMemPoolEntry<'a> {
-    pub fn new(tx: &'a Transaction) -> Self {
+impl TxMemPoolEntry {
+    pub fn new(
+        tx: Arc<Transaction>,
+        n_fee: CAmount,
+        n_time: i64,
+        d_priority: f64,
+        n_height: u32,
+        had_no_dependencies: bool,
+        spends_coinbase: bool,
+    ) -> Self {
+        let n_tx_size = tx.get_serialize_size();
+        let n_mode_size = tx.calculate_modified_size(n_tx_size);
+        let n_usage_size = recursive_dynamic_usage(&tx);
         TxMemPoolEntry {
-            tx: tx,
-            n_fee: 0,
-            n_mode_size: 0,
-            n_usage_size: 0,
-            fee_rate: FeeRate::new(),
-            n_time: 0,
-            d_priority: 0.0,
-            n_height: 0,
-            had_no_dependencies: false,
-            spends_coinbase: false,
+            tx,
+            n_fee,
+            n_tx_size,
+            n_mode_size,
+            n_usage_size,
+            fee_rate: FeeRate::from_fee(n_fee, n_tx_size),
+            n_time,
+            d_priority,
+            n_height,
+            had_no_dependencies,
+            spends_coinbase,
             n_branch_id: 0,
+            n_count_with_descendants: 1,
+            n_size_with_descendants: n_tx_size,
+            n_fees_with_descendants: n_fee,
+            n_count_with_ancestors: 1,
+            n_size_with_ancestors: n_tx_size,
+            n_fees_with_ancestors: n_fee,
         }
     }
+
+    pub fn get_tx(&self) -> &Arc<Transaction> {
+        &self.tx
+    }
+
+    pub fn get_fee(&self) -> CAmount {
+        self.n_fee
+    }
+
+    pub fn get_tx_size(&self) -> usize {
+        self.n_tx_size
+    }
+
+    pub fn get_fee_rate(&self) -> FeeRate {
+        self.fee_rate
+    }
+
+    pub fn get_time(&self) -> i64 {
+        self.n_time
+    }
+
+    pub fn get_priority(&self) -> f64 {
+        self.d_priority
+    }
+
+    pub fn get_height(&self) -> u32 {
+        self.n_height
+    }
+
+    pub fn was_clear_at_entry(&self) -> bool {
+        self.had_no_dependencies
+    }
+
+    pub fn get_spends_coinbase(&self) -> bool {
+        self.spends_coinbase
+    }
+
+    pub fn dynamic_memory_usage(&self) -> usize {
+        self.n_usage_size
+    }
+
+    pub fn get_count_with_descendants(&self) -> usize {
+        self.n_count_with_descendants
+    }
+
+    pub fn get_size_with_descendants(&self) -> usize {
+        self.n_size_with_descendants
+    }
+
+    pub fn get_fees_with_descendants(&self) -> CAmount {
+        self.n_fees_with_descendants
+    }
+
+    pub fn get_descendant_fee_rate(&self) -> FeeRate {
+        FeeRate::from_fee(self.n_fees_with_descendants, self.n_size_with_descendants)
+    }
+
+    pub fn get_count_with_ancestors(&self) -> usize {
+        self.n_count_with_ancestors
+    }
+
+    pub fn get_size_with_ancestors(&self) -> usize {
+        self.n_size_with_ancestors
+    }
+
+    pub fn get_fees_with_ancestors(&self) -> CAmount {
+        self.n_fees_with_ancestors
+    }
+
+    //The key of the entry in TxMemPoolInner::ancestor_score
+    fn ancestor_score_key(&self) -> FeeRateKey {
+        FeeRateKey {
+            n_fees: self.n_fees_with_ancestors,
+            n_size: self.n_size_with_ancestors,
+            hash: self.tx.hash(),
+        }
+    }
+
+    //The key of the entry in TxMemPoolInner::descendant_score
+    fn descendant_score_key(&self) -> FeeRateKey {
+        FeeRateKey {
+            n_fees: self.n_fees_with_descendants,
+            n_size: self.n_size_with_descendants,
+            hash: self.tx.hash(),
+        }
+    }
+
+    //A copy of the entry without any relatives, to add it again
+    fn detached(&self) -> Self {
+        TxMemPoolEntry::new(
+            self.tx.clone(),
+            self.n_fee,
+            self.n_time,
+            self.d_priority,
+            self.n_height,
+            self.had_no_dependencies,
+            self.spends_coinbase,
+        )
+    }
+
+    //void CTxMemPoolEntry::UpdateDescendantState(int64_t modifySize, CAmount modifyFee,
+    // int64_t modifyCount)
+    //Adds (f_add) or subtracts the transaction of another entry to the descendant state.
+    fn update_descendant_state(&mut self, other: (usize, CAmount), f_add: bool) {
+        let (n_size, n_fee) = other;
+        if f_add {
+            self.n_count_with_descendants += 1;
+            self.n_size_with_descendants += n_size;
+            self.n_fees_with_descendants += n_fee;
+        } else {
+            self.n_count_with_descendants -= 1;
+            self.n_size_with_descendants -= n_size;
+            self.n_fees_with_descendants -= n_fee;
+        }
+    }
+
+    //void CTxMemPoolEntry::UpdateAncestorState(int64_t modifySize, CAmount modifyFee,
+    // int64_t modifyCount)
+    fn update_ancestor_state(&mut self, other: (usize, CAmount), f_add: bool) {
+        let (n_size, n_fee) = other;
+        if f_add {
+            self.n_count_with_ancestors += 1;
+            self.n_size_with_ancestors += n_size;
+            self.n_fees_with_ancestors += n_fee;
+        } else {
+            self.n_count_with_ancestors -= 1;
+            self.n_size_with_ancestors -= n_size;
+            self.n_fees_with_ancestors -= n_fee;
+        }
+    }
+}
+
+//The in-mempool parents and children of an entry
+#[derive(Default)]
+struct TxLinks {
+    parents: HashSet<FrHash>,
+    children: HashSet<FrHash>,
+}
+
+//The transaction memory pool, shared by everything that submits or mines transactions. All
+//access goes through lock(), like holding mempool.cs in zcashd.
+pub struct TxMemPool {
+    cs: Mutex<TxMemPoolInner>,
+}
+
+impl TxMemPool {
+    pub fn new(n_size_limit: usize) -> Self {
+        TxMemPool {
+            cs: Mutex::new(TxMemPoolInner::new(n_size_limit)),
+        }
+    }
+
+    //Keep the guard only as long as needed, every other user of the pool waits for it.
+    pub fn lock(&self) -> MutexGuard<TxMemPoolInner> {
+        self.cs.lock().unwrap()
+    }
+
+    pub fn exists(&self, hash: FrHash) -> bool {
+        self.lock().exists(hash)
+    }
+
+    pub fn size(&self) -> usize {
+        self.lock().size()
+    }
+
+    pub fn get(&self, hash: &FrHash) -> Option<Arc<Transaction>> {
+        self.lock().mapTx.get(hash).map(|entry| entry.tx.clone())
+    }
+
+    //A snapshot of the mempool in the order block templates take the transactions, see
+    //AncestorScoreIter.
+    pub fn get_ancestor_score_order(&self) -> Vec<Arc<Transaction>> {
+        self.lock().iter_by_ancestor_score().collect()
+    }
 }
 
-pub struct TxMemPool<'a> {
-    pub mapTx: HashMap<FrHash, TxMemPoolEntry<'a>>,
-    pub mapNextTx: HashMap<SaplingOutPoint, SaplingInPoint<'a>>,
-    pub map_sapling_nullifier: HashMap<U256, &'a Transaction>,
+pub struct TxMemPoolInner {
+    pub mapTx: HashMap<FrHash, TxMemPoolEntry>,
+    pub mapNextTx: HashMap<SaplingOutPoint, SaplingInPoint>,
+    pub map_sapling_nullifier: HashMap<U256, Arc<Transaction>>,
+    // Kept in sync with mapTx
+    map_links: HashMap<FrHash, TxLinks>,
+    // The entries sorted by the fee rate of their package with their ancestors, which is the
+    // order they are mined in, and with their descendants, the reverse order of eviction
+    ancestor_score: BTreeSet<FeeRateKey>,
+    descendant_score: BTreeSet<FeeRateKey>,
+    // Sum of the serialized sizes of all transactions
+    total_tx_size: usize,
+    // Sum of the dynamic memory usage of all entries
+    cached_inner_usage: usize,
+    // Memory usage in bytes above which transactions are evicted
+    n_size_limit: usize,
+    package_limits: PackageLimits,
+    // Whether transactions paying more may replace conflicting ones, see check_replacement
+    f_enable_replacement: bool,
+    incremental_relay_fee: FeeRate,
 }
 
-impl<'a> TxMemPool<'a> {
+impl TxMemPoolInner {
+    pub fn new(n_size_limit: usize) -> Self {
+        TxMemPoolInner {
+            mapTx: HashMap::new(),
+            mapNextTx: HashMap::new(),
+            map_sapling_nullifier: HashMap::new(),
+            map_links: HashMap::new(),
+            ancestor_score: BTreeSet::new(),
+            descendant_score: BTreeSet::new(),
+            total_tx_size: 0,
+            cached_inner_usage: 0,
+            n_size_limit,
+            package_limits: PackageLimits::default(),
+            f_enable_replacement: DEFAULT_ENABLE_REPLACEMENT,
+            incremental_relay_fee: FeeRate::from_per_k(DEFAULT_INCREMENTAL_RELAY_FEE),
+        }
+    }
+
+    pub fn size(&self) -> usize {
+        self.mapTx.len()
+    }
+
+    pub fn get_total_tx_size(&self) -> usize {
+        self.total_tx_size
+    }
+
+    pub fn get_size_limit(&self) -> usize {
+        self.n_size_limit
+    }
+
+    pub fn set_size_limit(&mut self, n_size_limit: usize) {
+        self.n_size_limit = n_size_limit;
+    }
+
+    pub fn get_package_limits(&self) -> PackageLimits {
+        self.package_limits
+    }
+
+    pub fn set_package_limits(&mut self, package_limits: PackageLimits) {
+        self.package_limits = package_limits;
+    }
+
+    pub fn is_replacement_enabled(&self) -> bool {
+        self.f_enable_replacement
+    }
+
+    pub fn set_replacement_enabled(&mut self, f_enable_replacement: bool) {
+        self.f_enable_replacement = f_enable_replacement;
+    }
+
+    pub fn get_incremental_relay_fee(&self) -> FeeRate {
+        self.incremental_relay_fee
+    }
+
+    pub fn set_incremental_relay_fee(&mut self, incremental_relay_fee: FeeRate) {
+        self.incremental_relay_fee = incremental_relay_fee;
+    }
+
+    //size_t CTxMemPool::DynamicMemoryUsage() const
+    //Counts the map entries rather than their capacity so that removing transactions always
+    //lowers the usage.
+    pub fn dynamic_memory_usage(&self) -> usize {
+        self.cached_inner_usage
+            + self.mapTx.len() * mem::size_of::<(FrHash, TxMemPoolEntry)>()
+            + self.mapNextTx.len() * mem::size_of::<(SaplingOutPoint, SaplingInPoint)>()
+            + self.map_sapling_nullifier.len() * mem::size_of::<(U256, Arc<Transaction>)>()
+            + self.map_links.len() * mem::size_of::<(FrHash, TxLinks)>()
+            + (self.ancestor_score.len() + self.descendant_score.len())
+                * mem::size_of::<FeeRateKey>()
+    }
+
     pub fn exists(&self, hash: FrHash) -> bool {
         self.mapTx.contains_key(&hash)
     }
@@ -53,36 +454,465 @@ impl<'a> TxMemPool<'a> {
         self.map_sapling_nullifier.contains_key(&nullifier)
     }
 
-    pub fn add_unchecked(&mut self, hash: FrHash, entry: TxMemPoolEntry<'a>) {
-        let tx = entry.tx;
+    //bool CTxMemPool::HasNoInputsOf(const CTransaction &tx) const
+    pub fn has_no_inputs_of(&self, tx: &Transaction) -> bool {
+        tx.vin.iter().all(|txin| !self.exists(txin.prevout.hash))
+    }
+
+    //The size and fee of an entry, as added to the package state of its relatives.
+    fn get_size_and_fee(&self, hash: &FrHash) -> (usize, CAmount) {
+        let entry = &self.mapTx[hash];
+        (entry.n_tx_size, entry.n_fee)
+    }
+
+    //Updates the descendant state of the entry with this hash, see
+    //TxMemPoolEntry::update_descendant_state, and its place in descendant_score.
+    fn update_descendant_state(&mut self, hash: &FrHash, other: (usize, CAmount), f_add: bool) {
+        let entry = self.mapTx.get_mut(hash).unwrap();
+        self.descendant_score.remove(&entry.descendant_score_key());
+        entry.update_descendant_state(other, f_add);
+        self.descendant_score.insert(entry.descendant_score_key());
+    }
+
+    //Updates the ancestor state of the entry with this hash and its place in ancestor_score.
+    fn update_ancestor_state(&mut self, hash: &FrHash, other: (usize, CAmount), f_add: bool) {
+        let entry = self.mapTx.get_mut(hash).unwrap();
+        self.ancestor_score.remove(&entry.ancestor_score_key());
+        entry.update_ancestor_state(other, f_add);
+        self.ancestor_score.insert(entry.ancestor_score_key());
+    }
+
+    //bool CTxMemPool::addUnchecked(const uint256& hash, const CTxMemPoolEntry &entry,
+    // bool fCurrentEstimate)
+    //Adds an entry without checking the package limits.
+    pub fn add_unchecked(&mut self, hash: FrHash, entry: TxMemPoolEntry) {
+        let mut set_ancestors = HashSet::new();
+        self.calculate_mem_pool_ancestors(&entry, &mut set_ancestors, None);
+        self.add_unchecked_with_ancestors(hash, entry, &set_ancestors);
+    }
+
+    //bool CTxMemPool::addUnchecked(const uint256& hash, const CTxMemPoolEntry &entry,
+    // setEntries &setAncestors, bool fCurrentEstimate)
+    //set_ancestors must be the in-mempool ancestors of the entry, see
+    //calculate_mem_pool_ancestors.
+    pub fn add_unchecked_with_ancestors(
+        &mut self,
+        hash: FrHash,
+        mut entry: TxMemPoolEntry,
+        set_ancestors: &HashSet<FrHash>,
+    ) {
+        let tx = entry.tx.clone();
         for i in 0..tx.vin.len() {
             //self.mapNextTx[&tx.vin[i].prevout] = SaplingInPoint::new(&tx, i);
             self.mapNextTx
-                .insert(tx.vin[i].prevout, SaplingInPoint::new(&tx, i));
+                .insert(tx.vin[i].prevout, SaplingInPoint::new(tx.clone(), i));
         }
         for spend_desciption in tx.v_shielded_spend.iter() {
             //self.map_sapling_nullifier[&U256::from(spend_desciption.nullifier)] = tx;
             self.map_sapling_nullifier
-                .insert(U256::from(spend_desciption.nullifier), tx);
+                .insert(U256::from(spend_desciption.nullifier), tx.clone());
+        }
+
+        // Update ancestors with information about this tx
+        let parents: HashSet<FrHash> = tx
+            .vin
+            .iter()
+            .map(|txin| txin.prevout.hash)
+            .filter(|parent| self.exists(*parent))
+            .collect();
+        for parent in parents.iter() {
+            self.map_links
+                .get_mut(parent)
+                .unwrap()
+                .children
+                .insert(hash);
+        }
+        for ancestor in set_ancestors.iter() {
+            let ancestor_size_and_fee = self.get_size_and_fee(ancestor);
+            entry.update_ancestor_state(ancestor_size_and_fee, true);
+            self.update_descendant_state(ancestor, (entry.n_tx_size, entry.n_fee), true);
         }
+        self.map_links.insert(
+            hash,
+            TxLinks {
+                parents,
+                children: HashSet::new(),
+            },
+        );
 
+        self.total_tx_size += entry.n_tx_size;
+        self.cached_inner_usage += entry.n_usage_size;
+        self.ancestor_score.insert(entry.ancestor_score_key());
+        self.descendant_score.insert(entry.descendant_score_key());
         self.mapTx.insert(hash, entry);
     }
 
+    //void CTxMemPool::removeUnchecked(txiter it)
+    //Removes a single entry from all the maps and unlinks it from its relatives, whose
+    //package state must have been updated already, see remove_staged.
+    fn remove_unchecked(&mut self, hash: &FrHash) -> Option<Arc<Transaction>> {
+        let entry = self.mapTx.remove(hash)?;
+        self.ancestor_score.remove(&entry.ancestor_score_key());
+        self.descendant_score.remove(&entry.descendant_score_key());
+        let tx = entry.tx;
+        for txin in tx.vin.iter() {
+            self.mapNextTx.remove(&txin.prevout);
+        }
+        for spend_description in tx.v_shielded_spend.iter() {
+            self.map_sapling_nullifier
+                .remove(&U256::from(spend_description.nullifier));
+        }
+        let links = self.map_links.remove(hash).unwrap_or_default();
+        for parent in links.parents.iter() {
+            if let Some(parent_links) = self.map_links.get_mut(parent) {
+                parent_links.children.remove(hash);
+            }
+        }
+        for child in links.children.iter() {
+            if let Some(child_links) = self.map_links.get_mut(child) {
+                child_links.parents.remove(hash);
+            }
+        }
+        self.total_tx_size -= entry.n_tx_size;
+        self.cached_inner_usage -= entry.n_usage_size;
+        Some(tx)
+    }
+
+    //void CTxMemPool::RemoveStaged(setEntries &stage, bool updateDescendants)
+    //Removes a set of entries. With f_update_descendants, the ancestor state of the
+    //descendants staying in the mempool is updated, which is needed unless all of them are
+    //removed as well.
+    fn remove_staged(
+        &mut self,
+        stage: &HashSet<FrHash>,
+        f_update_descendants: bool,
+        removed: &mut VecDeque<Arc<Transaction>>,
+    ) {
+        for hash in stage.iter() {
+            let size_and_fee = self.get_size_and_fee(hash);
+            if f_update_descendants {
+                let mut set_descendants = HashSet::new();
+                self.calculate_descendants(hash, &mut set_descendants);
+                for descendant in set_descendants.difference(stage) {
+                    self.update_ancestor_state(descendant, size_and_fee, false);
+                }
+            }
+            let mut set_ancestors = HashSet::new();
+            self.calculate_ancestors(hash, &mut set_ancestors);
+            for ancestor in set_ancestors.iter() {
+                self.update_descendant_state(ancestor, size_and_fee, false);
+            }
+        }
+        for hash in stage.iter() {
+            if let Some(tx) = self.remove_unchecked(hash) {
+                removed.push_back(tx);
+            }
+        }
+    }
+
+    //bool CTxMemPool::CalculateMemPoolAncestors(const CTxMemPoolEntry &entry,
+    // setEntries &setAncestors, uint64_t limitAncestorCount, uint64_t limitAncestorSize,
+    // uint64_t limitDescendantCount, uint64_t limitDescendantSize, std::string &errString,
+    // bool fSearchForParents)
+    //Collects the in-mempool ancestors of an entry that is not in the mempool yet. Returns
+    //false if adding it would break the package limits, which are not checked if None.
+    pub fn calculate_mem_pool_ancestors(
+        &self,
+        entry: &TxMemPoolEntry,
+        set_ancestors: &mut HashSet<FrHash>,
+        limits: Option<&PackageLimits>,
+    ) -> bool {
+        let mut parent_hashes: HashSet<FrHash> = entry
+            .tx
+            .vin
+            .iter()
+            .map(|txin| txin.prevout.hash)
+            .filter(|parent| self.exists(*parent))
+            .collect();
+        if let Some(limits) = limits {
+            if parent_hashes.len() + 1 > limits.n_ancestor_count {
+                error!(
+                    "CalculateMemPoolAncestors(): too many unconfirmed parents [limit: {}]",
+                    limits.n_ancestor_count
+                );
+                return false;
+            }
+        }
+
+        let mut n_total_size_with_ancestors = entry.n_tx_size;
+        while let Some(stage) = parent_hashes.iter().next().cloned() {
+            parent_hashes.remove(&stage);
+            set_ancestors.insert(stage);
+            let stage_entry = &self.mapTx[&stage];
+            n_total_size_with_ancestors += stage_entry.n_tx_size;
+
+            if let Some(limits) = limits {
+                if stage_entry.n_size_with_descendants + entry.n_tx_size > limits.n_descendant_size
+                {
+                    error!(
+                        "CalculateMemPoolAncestors(): exceeds descendant size limit for tx {:?} \
+                         [limit: {}]",
+                        stage, limits.n_descendant_size
+                    );
+                    return false;
+                } else if stage_entry.n_count_with_descendants + 1 > limits.n_descendant_count {
+                    error!(
+                        "CalculateMemPoolAncestors(): too many descendants for tx {:?} \
+                         [limit: {}]",
+                        stage, limits.n_descendant_count
+                    );
+                    return false;
+                } else if n_total_size_with_ancestors > limits.n_ancestor_size {
+                    error!(
+                        "CalculateMemPoolAncestors(): exceeds ancestor size limit [limit: {}]",
+                        limits.n_ancestor_size
+                    );
+                    return false;
+                }
+            }
+
+            for parent in self.map_links[&stage].parents.iter() {
+                if !set_ancestors.contains(parent) {
+                    parent_hashes.insert(*parent);
+                }
+            }
+            if let Some(limits) = limits {
+                if parent_hashes.len() + set_ancestors.len() + 1 > limits.n_ancestor_count {
+                    error!(
+                        "CalculateMemPoolAncestors(): too many unconfirmed ancestors \
+                         [limit: {}]",
+                        limits.n_ancestor_count
+                    );
+                    return false;
+                }
+            }
+        }
+        true
+    }
+
+    //Adds the in-mempool ancestors of the transaction with this hash to set_ancestors.
+    pub fn calculate_ancestors(&self, hash: &FrHash, set_ancestors: &mut HashSet<FrHash>) {
+        let mut stage: Vec<FrHash> = match self.map_links.get(hash) {
+            Some(links) => links.parents.iter().cloned().collect(),
+            None => return,
+        };
+        while let Some(parent) = stage.pop() {
+            if set_ancestors.insert(parent) {
+                stage.extend(self.map_links[&parent].parents.iter());
+            }
+        }
+    }
+
+    //void CTxMemPool::CalculateDescendants(txiter entryit, setEntries &setDescendants)
+    //Adds the transaction with this hash and everything in the mempool spending it to
+    //set_descendants.
+    pub fn calculate_descendants(&self, hash: &FrHash, set_descendants: &mut HashSet<FrHash>) {
+        let mut stage = vec![*hash];
+        while let Some(hash) = stage.pop() {
+            if self.exists(hash) && set_descendants.insert(hash) {
+                stage.extend(self.map_links[&hash].children.iter());
+            }
+        }
+    }
+
+    //The mempool transactions spending an input or a nullifier of tx.
+    pub fn get_conflicts(&self, tx: &Transaction) -> HashSet<FrHash> {
+        let mut set_conflicts = HashSet::new();
+        for txin in tx.vin.iter() {
+            if let Some(inpoint) = self.mapNextTx.get(&txin.prevout) {
+                set_conflicts.insert(inpoint.ptx.hash());
+            }
+        }
+        for spend_description in tx.v_shielded_spend.iter() {
+            let nullifier = U256::from(spend_description.nullifier);
+            if let Some(ptx_conflicting) = self.map_sapling_nullifier.get(&nullifier) {
+                set_conflicts.insert(ptx_conflicting.hash());
+            }
+        }
+        set_conflicts.remove(&tx.hash());
+        set_conflicts
+    }
+
+    //The replace-by-fee rules of AcceptToMemoryPool() for an entry conflicting with the
+    //transactions in set_conflicts. It must pay a higher fee rate than each of them, and the
+    //fees of everything it evicts, which includes their descendants, plus the incremental
+    //relay fee for its own size.
+    pub fn check_replacement(
+        &self,
+        entry: &TxMemPoolEntry,
+        set_conflicts: &HashSet<FrHash>,
+        set_ancestors: &HashSet<FrHash>,
+    ) -> bool {
+        let hash = entry.tx.hash();
+        if !self.f_enable_replacement {
+            error!(
+                "AcceptToMemoryPool(): {:?} conflicts with the mempool",
+                hash
+            );
+            return false;
+        }
+
+        // The replacement must be more attractive to mine than each transaction it
+        // replaces directly, otherwise a miner would rather keep those.
+        for conflict in set_conflicts.iter() {
+            let old_entry = &self.mapTx[conflict];
+            if compare_fee_rate(
+                entry.n_fee,
+                entry.n_tx_size,
+                old_entry.n_fee,
+                old_entry.n_tx_size,
+            ) != Ordering::Greater
+            {
+                error!(
+                    "AcceptToMemoryPool(): rejecting replacement {:?}; new feerate {:?} <= old \
+                     feerate {:?}",
+                    hash, entry.fee_rate, old_entry.fee_rate
+                );
+                return false;
+            }
+        }
+
+        let mut set_all_conflicts = HashSet::new();
+        for conflict in set_conflicts.iter() {
+            self.calculate_descendants(conflict, &mut set_all_conflicts);
+        }
+        if set_all_conflicts.len() > MAX_REPLACEMENT_CANDIDATES {
+            error!(
+                "AcceptToMemoryPool(): rejecting replacement {:?}; too many potential \
+                 replacements ({} > {})",
+                hash,
+                set_all_conflicts.len(),
+                MAX_REPLACEMENT_CANDIDATES
+            );
+            return false;
+        }
+        if let Some(ancestor) = set_ancestors.intersection(&set_all_conflicts).next() {
+            error!(
+                "AcceptToMemoryPool(): {:?} spends conflicting transaction {:?}",
+                hash, ancestor
+            );
+            return false;
+        }
+
+        // The replacement must pay for the fees it takes away from miners
+        let n_conflicting_fees: CAmount = set_all_conflicts
+            .iter()
+            .map(|conflict| self.mapTx[conflict].n_fee)
+            .sum();
+        if entry.n_fee < n_conflicting_fees {
+            error!(
+                "AcceptToMemoryPool(): rejecting replacement {:?}, less fees than conflicting \
+                 txs; {} < {}",
+                hash, entry.n_fee, n_conflicting_fees
+            );
+            return false;
+        }
+
+        // The replacement must also pay for its own bandwidth, otherwise transactions could
+        // be relayed over and over for a negligible fee
+        let n_delta_fees = entry.n_fee - n_conflicting_fees;
+        let n_relay_fee = self.incremental_relay_fee.get_fee(entry.n_tx_size);
+        if n_delta_fees < n_relay_fee {
+            error!(
+                "AcceptToMemoryPool(): rejecting replacement {:?}, not enough additional fees \
+                 to relay; {} < {}",
+                hash, n_delta_fees, n_relay_fee
+            );
+            return false;
+        }
+        true
+    }
+
+    //Adds an entry in place of the transactions in set_conflicts and their descendants, the
+    //replacement rules must have been checked already, and trims the mempool to its size
+    //limit. If the entry itself gets trimmed, the replaced transactions are put back and
+    //false is returned. Otherwise replaced gets the evicted transactions.
+    pub fn add_replacing(
+        &mut self,
+        hash: FrHash,
+        entry: TxMemPoolEntry,
+        set_ancestors: &HashSet<FrHash>,
+        set_conflicts: &HashSet<FrHash>,
+        replaced: &mut VecDeque<Arc<Transaction>>,
+    ) -> bool {
+        let mut set_all_conflicts = HashSet::new();
+        for conflict in set_conflicts.iter() {
+            self.calculate_descendants(conflict, &mut set_all_conflicts);
+        }
+        // Copies of the evicted entries with their in-mempool parents, parents first
+        let mut saved: Vec<(usize, TxMemPoolEntry, HashSet<FrHash>)> = set_all_conflicts
+            .iter()
+            .map(|conflict| {
+                let old_entry = &self.mapTx[conflict];
+                (
+                    old_entry.n_count_with_ancestors,
+                    old_entry.detached(),
+                    self.map_links[conflict].parents.clone(),
+                )
+            })
+            .collect();
+        saved.sort_by_key(|(n_count_with_ancestors, _, _)| *n_count_with_ancestors);
+        self.remove_staged(&set_all_conflicts, false, replaced);
+
+        self.add_unchecked_with_ancestors(hash, entry, set_ancestors);
+        let mut removed = VecDeque::new();
+        self.trim_to_size(self.n_size_limit, &mut removed);
+        if self.exists(hash) {
+            return true;
+        }
+
+        // Nothing was replaced after all, unless trimming took the parents as well
+        for (_, old_entry, parents) in saved {
+            if parents.iter().all(|parent| self.exists(*parent)) {
+                self.add_unchecked(old_entry.tx.hash(), old_entry);
+            }
+        }
+        replaced.clear();
+        false
+    }
+
+    //void CTxMemPool::TrimToSize(size_t sizelimit, std::vector<uint256>* pvNoSpendsRemaining)
+    //Evicts the packages with the lowest fee rate, each being a transaction with everything
+    //spending it, until the memory usage is at most n_size_limit.
+    pub fn trim_to_size(&mut self, n_size_limit: usize, removed: &mut VecDeque<Arc<Transaction>>) {
+        while !self.mapTx.is_empty() && self.dynamic_memory_usage() > n_size_limit {
+            // The lowest descendant score comes first
+            let worst = *self.descendant_score.iter().next().unwrap();
+
+            let mut set_descendants = HashSet::new();
+            self.calculate_descendants(&worst.hash, &mut set_descendants);
+            debug!(
+                "TrimToSize(): evicting {} transactions at {:?}",
+                set_descendants.len(),
+                FeeRate::from_fee(worst.n_fees, worst.n_size)
+            );
+            self.remove_staged(&set_descendants, false, removed);
+        }
+    }
+
+    //The transactions in the order block templates take them, see AncestorScoreIter.
+    pub fn iter_by_ancestor_score(&self) -> AncestorScoreIter {
+        AncestorScoreIter {
+            pool: self,
+            iter: self.ancestor_score.iter().rev().peekable(),
+            set_returned: HashSet::new(),
+            map_modified: HashMap::new(),
+            modified_score: BTreeSet::new(),
+            package: VecDeque::new(),
+        }
+    }
+
+    //void CTxMemPool::removeForBlock(const std::vector<CTransaction>& vtx,
+    // unsigned int nBlockHeight, std::list<CTransaction>& conflicts, bool fCurrentEstimate)
+    //Removes the transactions of a connected block and everything conflicting with them. The
+    //descendants of the mined transactions stay, with fewer ancestors.
     pub fn remove_for_block(
         &mut self,
         vtx: &Vec<Transaction>,
         n_block_height: u32,
-        conflicts: &mut VecDeque<&'a Transaction>,
+        conflicts: &mut VecDeque<Arc<Transaction>>,
         f_current_estimeate: bool,
     ) {
-        let mut entries = Vec::new();
-        for tx in vtx.iter() {
-            let hash = &tx.hash;
-            if self.mapTx.contains_key(hash) {
-                entries.push(self.mapTx.get(hash).unwrap());
-            }
-        }
         for tx in vtx.iter() {
             let mut dummy = VecDeque::new();
             self.remove(tx, &mut dummy, false);
@@ -90,59 +920,140 @@ impl<'a> TxMemPool<'a> {
         }
     }
 
+    //void CTxMemPool::remove(const CTransaction &origTx, std::list<CTransaction>& removed,
+    // bool fRecursive)
+    //Removes orig_tx, and with f_recursive everything in the mempool spending it, even if
+    //orig_tx itself isn't in the mempool.
     pub fn remove(
         &mut self,
         orig_tx: &Transaction,
-        removed: &mut VecDeque<&'a Transaction>,
+        removed: &mut VecDeque<Arc<Transaction>>,
         f_recursive: bool,
     ) {
-        let mut tx_to_remove = VecDeque::new();
-        tx_to_remove.push_back(orig_tx.hash);
-        for i in 0..orig_tx.vout.len() {
-            let outpoint = SaplingOutPoint {
-                hash: orig_tx.hash,
-                n: i,
-            };
-            let tx = self.mapNextTx.get(&outpoint);
-            if !tx.is_none() {
-                tx_to_remove.push_back(tx.unwrap().ptx.hash);
-            }
-        }
-        while !tx_to_remove.is_empty() {
-            let hash = tx_to_remove.pop_front().unwrap();
-            if !self.mapTx.contains_key(&hash) {
-                continue;
-            }
-            let tx = self.mapTx.get(&hash).unwrap().tx;
-            if f_recursive {
-                for i in 0..orig_tx.vout.len() {
-                    let outpoint = SaplingOutPoint {
-                        hash: orig_tx.hash,
-                        n: i,
+        let mut tx_to_remove = Vec::new();
+        if self.exists(orig_tx.hash()) {
+            tx_to_remove.push(orig_tx.hash());
+        } else if f_recursive {
+            // If recursively removing but orig_tx isn't in the mempool
+            // be sure to remove any children that are in the pool. This can
+            // happen during chain re-orgs if orig_tx isn't re-accepted into
+            // the mempool for any reason.
+            for i in 0..orig_tx.vout.len() {
+                let outpoint = SaplingOutPoint {
+                    hash: orig_tx.hash(),
+                    n: i,
+                };
+                if let Some(inpoint) = self.mapNextTx.get(&outpoint) {
+                    tx_to_remove.push(inpoint.ptx.hash());
+                }
+            }
+        }
+
+        let mut set_all_removes = HashSet::new();
+        if f_recursive {
+            for hash in tx_to_remove.iter() {
+                self.calculate_descendants(hash, &mut set_all_removes);
+            }
+        } else {
+            set_all_removes.extend(tx_to_remove);
+        }
+        self.remove_staged(&set_all_removes, !f_recursive, removed);
+    }
+
+    //void CTxMemPool::removeWithAnchor(const uint256 &invalidRoot, ShieldedType type)
+    //If a block is disconnected from the tip, and the root changed, we must invalidate
+    //transactions from the mempool which spend from that root -- almost as though they were
+    //spending coinbases which are no longer valid to spend due to coinbase maturity.
+    pub fn remove_with_anchor(
+        &mut self,
+        invalid_root: FrHash,
+        removed: &mut VecDeque<Arc<Transaction>>,
+    ) {
+        let transactions_to_remove: Vec<Arc<Transaction>> = self
+            .mapTx
+            .values()
+            .map(|entry| entry.tx.clone())
+            .filter(|tx| {
+                tx.v_shielded_spend
+                    .iter()
+                    .any(|spend_description| FrHash(spend_description.anchor) == invalid_root)
+            })
+            .collect();
+        for tx in transactions_to_remove {
+            self.remove(&tx, removed, true);
+        }
+    }
+
+    //void CTxMemPool::removeForReorg(const CCoinsViewCache *pcoins, unsigned int nMemPoolHeight,
+    // int flags)
+    //Removes the transactions that are no longer final or spend a coinbase that is immature
+    //again, after blocks were disconnected from chain_active.
+    pub fn remove_for_reorg(
+        &mut self,
+        pcoins: &mut CoinViewCache,
+        chain_active: &Chain,
+        removed: &mut VecDeque<Arc<Transaction>>,
+    ) {
+        let n_mem_pool_height = chain_active.height() + 1;
+        let mut transactions_to_remove = Vec::new();
+        for entry in self.mapTx.values() {
+            let tx = &entry.tx;
+            if !check_final_tx(tx, chain_active) {
+                transactions_to_remove.push(tx.clone());
+            } else if entry.spends_coinbase {
+                for txin in tx.vin.iter() {
+                    if self.exists(txin.prevout.hash) {
+                        continue;
+                    }
+                    let f_immature = match pcoins.access_coins(txin.prevout.hash) {
+                        Some(coins) => {
+                            coins.f_coin_base
+                                && n_mem_pool_height - coins.n_height < COINBASE_MATURITY as i32
+                        }
+                        None => true,
                     };
-                    let tx = self.mapNextTx.get(&outpoint);
-                    if !tx.is_none() {
-                        tx_to_remove.push_back(tx.unwrap().ptx.hash);
+                    if f_immature {
+                        transactions_to_remove.push(tx.clone());
+                        break;
                     }
                 }
             }
-            for txin in tx.vin.iter() {
-                self.mapNextTx.remove(&txin.prevout);
-            }
+        }
+        for tx in transactions_to_remove {
+            self.remove(&tx, removed, true);
+        }
+    }
 
-            removed.push_back(tx);
+    //void CTxMemPool::removeExpired(unsigned int nBlockHeight)
+    //Removes the transactions that can't be mined at n_block_height any more, with everything
+    //spending them.
+    pub fn remove_expired(
+        &mut self,
+        n_block_height: i32,
+        removed: &mut VecDeque<Arc<Transaction>>,
+    ) {
+        let transactions_to_remove: Vec<Arc<Transaction>> = self
+            .mapTx
+            .values()
+            .map(|entry| entry.tx.clone())
+            .filter(|tx| is_expired_tx(tx, n_block_height))
+            .collect();
+        for tx in transactions_to_remove {
+            self.remove(&tx, removed, true);
         }
     }
 
-    pub fn remove_conflicts(&mut self, tx: &Transaction, removed: &mut VecDeque<&'a Transaction>) {
+    //void CTxMemPool::removeConflicts(const CTransaction &tx, std::list<CTransaction>& removed)
+    //Removes the transactions spending an input or a nullifier of tx, with their descendants.
+    pub fn remove_conflicts(&mut self, tx: &Transaction, removed: &mut VecDeque<Arc<Transaction>>) {
         //let result = VecDeque::new();
         for txin in tx.vin.iter() {
             let op = self.mapNextTx.get(&txin.prevout);
             if !op.is_none() {
                 let inpoint = op.unwrap();
-                let tx_conflict = inpoint.ptx;
-                if tx_conflict.hash != tx.hash {
-                    self.remove(tx_conflict, removed, true);
+                let tx_conflict = inpoint.ptx.clone();
+                if tx_conflict.hash() != tx.hash() {
+                    self.remove(&tx_conflict, removed, true);
                 }
             }
         }
@@ -152,11 +1063,625 @@ impl<'a> TxMemPool<'a> {
                 .map_sapling_nullifier
                 .get(&U256::from(spend_description.nullifier));
             if !op.is_none() {
-                let tx_conflict = op.unwrap();
-                if tx_conflict.hash != tx.hash {
-                    self.remove(tx_conflict, removed, true);
+                let tx_conflict = op.unwrap().clone();
+                if tx_conflict.hash() != tx.hash() {
+                    self.remove(&tx_conflict, removed, true);
                 }
             }
         }
     }
 }
+
+//class CCoinsViewMemPool
+//The coins of base together with the outputs of the mempool transactions, at MEMPOOL_HEIGHT,
+//so that a transaction spending a mempool transaction can be checked against its parent.
+//Use it as the base of a scratch CoinViewCache.
+pub struct CoinViewMemPool<'a> {
+    base: &'a mut CoinViewCache,
+    mempool: &'a TxMemPoolInner,
+}
+
+impl<'a> CoinViewMemPool<'a> {
+    pub fn new(base: &'a mut CoinViewCache, mempool: &'a TxMemPoolInner) -> Self {
+        CoinViewMemPool { base, mempool }
+    }
+}
+
+impl<'a> CoinsView for CoinViewMemPool<'a> {
+    fn get_best_anchor(&self) -> Option<FrHash> {
+        self.base.get_best_anchor()
+    }
+
+    fn get_best_block(&self) -> U256 {
+        self.base.get_best_block()
+    }
+
+    fn get_sapling_anchor_at(&mut self, rt: FrHash) -> Option<SaplingMerkleTree> {
+        self.base.get_sapling_anchor_at(rt)
+    }
+
+    // Nullifiers spent in the mempool are conflicts rather than spent, they may still be
+    // replaced, see TxMemPoolInner::get_conflicts.
+    fn get_nullifier(&mut self, nullifier: U256) -> bool {
+        self.base.get_nullifier(nullifier)
+    }
+
+    fn set_best_block(&mut self, block_hash: U256) {
+        self.base.set_best_block(block_hash);
+    }
+
+    //bool CCoinsViewMemPool::GetCoins(const uint256 &txid, CCoins &coins) const
+    fn get_coins(&mut self, txid: FrHash) -> Option<Coins> {
+        // If an entry in the mempool exists, always return that one, as it's guaranteed to never
+        // conflict with the underlying cache, and it cannot have pruned entries (as it contains
+        // full transactions). First checking the underlying cache risks returning a pruned entry
+        // instead.
+        if let Some(entry) = self.mempool.mapTx.get(&txid) {
+            let mut coins = Coins::new();
+            coins.from_tx(&entry.tx, MEMPOOL_HEIGHT);
+            return Some(coins);
+        }
+        self.base.get_coins(txid).filter(|coins| !coins.is_pruned())
+    }
+
+    fn have_coins(&mut self, txid: FrHash) -> bool {
+        self.mempool.exists(txid) || self.base.have_coins(txid)
+    }
+}
+
+//Yields the mempool transactions in the order CreateNewBlock's addPackageTxs() mines them:
+//next is the transaction with the highest fee rate counted together with its ancestors that
+//were not returned yet, preceded by those ancestors with parents before children.
+pub struct AncestorScoreIter<'p> {
+    pool: &'p TxMemPoolInner,
+    // The mempool entries by ancestor score, highest first
+    iter: Peekable<Rev<btree_set::Iter<'p, FeeRateKey>>>,
+    set_returned: HashSet<FrHash>,
+    // The transactions with returned ancestors, which no longer count towards their
+    // packages, by their remaining ancestor size and fees. These are taken from
+    // modified_score rather than iter, like mapModifiedTx in addPackageTxs().
+    map_modified: HashMap<FrHash, FeeRateKey>,
+    modified_score: BTreeSet<FeeRateKey>,
+    // The rest of the package being returned
+    package: VecDeque<Arc<Transaction>>,
+}
+
+impl<'p> AncestorScoreIter<'p> {
+    fn get_modified_key(&self, hash: &FrHash) -> FeeRateKey {
+        self.map_modified
+            .get(hash)
+            .cloned()
+            .unwrap_or_else(|| self.pool.mapTx[hash].ancestor_score_key())
+    }
+}
+
+impl<'p> Iterator for AncestorScoreIter<'p> {
+    type Item = Arc<Transaction>;
+
+    fn next(&mut self) -> Option<Arc<Transaction>> {
+        if let Some(tx) = self.package.pop_front() {
+            return Some(tx);
+        }
+
+        // Skip what was returned already or is in modified_score with a lower score
+        loop {
+            match self.iter.peek() {
+                Some(key)
+                    if self.set_returned.contains(&key.hash)
+                        || self.map_modified.contains_key(&key.hash) =>
+                {
+                    self.iter.next();
+                }
+                _ => break,
+            }
+        }
+        let best = match (self.iter.peek(), self.modified_score.iter().next_back()) {
+            (Some(key), Some(modified)) if modified > *key => *modified,
+            (Some(key), _) => **key,
+            (None, Some(modified)) => *modified,
+            (None, None) => return None,
+        };
+
+        let mut package = HashSet::new();
+        self.pool.calculate_ancestors(&best.hash, &mut package);
+        package.retain(|ancestor| !self.set_returned.contains(ancestor));
+        package.insert(best.hash);
+        for hash in package.iter() {
+            if let Some(key) = self.map_modified.remove(hash) {
+                self.modified_score.remove(&key);
+            }
+        }
+
+        // The remaining descendants of the package no longer pay for it
+        for hash in package.iter() {
+            let (n_tx_size, n_fee) = self.pool.get_size_and_fee(hash);
+            let mut set_descendants = HashSet::new();
+            self.pool.calculate_descendants(hash, &mut set_descendants);
+            for descendant in set_descendants.difference(&package) {
+                let key = self.get_modified_key(descendant);
+                self.modified_score.remove(&key);
+                let key = FeeRateKey {
+                    n_fees: key.n_fees - n_fee,
+                    n_size: key.n_size - n_tx_size,
+                    ..key
+                };
+                self.modified_score.insert(key);
+                self.map_modified.insert(*descendant, key);
+            }
+        }
+
+        // A transaction has more ancestors than any of its parents, so sorting by the number
+        // of ancestors puts parents first.
+        let pool = self.pool;
+        let mut sorted: Vec<&TxMemPoolEntry> =
+            package.iter().map(|hash| &pool.mapTx[hash]).collect();
+        sorted.sort_by_key(|entry| entry.n_count_with_ancestors);
+
+        self.package
+            .extend(sorted.into_iter().map(|entry| entry.tx.clone()));
+        self.set_returned.extend(package);
+        self.package.pop_front()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::block_chain::tests::branch;
+    use crate::main_impl::update_coins;
+    use crate::script::Script;
+    use crate::transaction::tests::transaction;
+    use crate::transaction::MutableTransaction;
+    use ff::Field;
+    use pairing::bls12_381::Fr;
+    use std::thread;
+
+    // A transparent transaction spending output n of parent
+    fn spending(parent: &FrHash, n: usize) -> Transaction {
+        let mut tx = MutableTransaction::from(&transaction());
+        tx.vin[0].prevout = SaplingOutPoint { hash: *parent, n };
+        tx.v_shielded_spend.clear();
+        Transaction::from(tx)
+    }
+
+    fn add(pool: &mut TxMemPoolInner, tx: &Transaction, n_fee: CAmount) {
+        let f_no_inputs = pool.has_no_inputs_of(tx);
+        let entry = TxMemPoolEntry::new(Arc::new(tx.clone()), n_fee, 0, 0.0, 1, f_no_inputs, false);
+        pool.add_unchecked(tx.hash(), entry);
+    }
+
+    // The fee rate indexes hold exactly the current keys of the entries
+    fn assert_indexes_in_sync(pool: &TxMemPoolInner) {
+        assert_eq!(pool.ancestor_score.len(), pool.size());
+        assert_eq!(pool.descendant_score.len(), pool.size());
+        for entry in pool.mapTx.values() {
+            for (index, key) in vec![
+                (&pool.ancestor_score, entry.ancestor_score_key()),
+                (&pool.descendant_score, entry.descendant_score_key()),
+            ] {
+                let stored = index.get(&key).unwrap();
+                assert_eq!((stored.n_fees, stored.n_size), (key.n_fees, key.n_size));
+            }
+        }
+    }
+
+    fn confirmed(n: u8) -> FrHash {
+        let mut tx = MutableTransaction::from(&transaction());
+        tx.binding_sig = [n; 64];
+        Transaction::from(tx).hash()
+    }
+
+    #[test]
+    fn remove_expired_sweeps_expired_entries() {
+        let mut expiring = MutableTransaction::from(&spending(&confirmed(1), 0));
+        expiring.expiry_height = 10;
+        let expiring = Transaction::from(expiring);
+        let never_expiring = spending(&confirmed(2), 0);
+
+        let mut pool = TxMemPoolInner::new(DEFAULT_MAX_MEMPOOL_SIZE * 1000000);
+        add(&mut pool, &expiring, 0);
+        add(&mut pool, &never_expiring, 0);
+
+        let mut removed = VecDeque::new();
+        pool.remove_expired(10, &mut removed);
+        assert!(removed.is_empty());
+
+        pool.remove_expired(11, &mut removed);
+        assert_eq!(removed.len(), 1);
+        assert_eq!(removed[0].hash(), expiring.hash());
+        assert!(!pool.exists(expiring.hash()));
+        assert!(pool.exists(never_expiring.hash()));
+        assert_eq!(pool.size(), 1);
+    }
+
+    #[test]
+    fn entries_track_ancestors_and_descendants() {
+        let a = spending(&confirmed(1), 0);
+        let b = spending(&a.hash(), 0);
+        let c = spending(&b.hash(), 0);
+        let n_size = a.get_serialize_size();
+
+        let mut pool = TxMemPoolInner::new(DEFAULT_MAX_MEMPOOL_SIZE * 1000000);
+        add(&mut pool, &a, 1);
+        add(&mut pool, &b, 10);
+        add(&mut pool, &c, 100);
+        assert_eq!(pool.mapTx[&a.hash()].get_count_with_descendants(), 3);
+        assert_eq!(
+            pool.mapTx[&a.hash()].get_size_with_descendants(),
+            3 * n_size
+        );
+        assert_eq!(pool.mapTx[&a.hash()].get_fees_with_descendants(), 111);
+        assert_eq!(pool.mapTx[&b.hash()].get_fees_with_descendants(), 110);
+        assert_eq!(pool.mapTx[&c.hash()].get_count_with_ancestors(), 3);
+        assert_eq!(pool.mapTx[&c.hash()].get_size_with_ancestors(), 3 * n_size);
+        assert_eq!(pool.mapTx[&c.hash()].get_fees_with_ancestors(), 111);
+        assert_indexes_in_sync(&pool);
+
+        // Mining a confirms it, its descendants stay with fewer ancestors
+        let mut conflicts = VecDeque::new();
+        pool.remove_for_block(&vec![a.clone()], 1, &mut conflicts, true);
+        assert!(conflicts.is_empty());
+        assert_eq!(pool.size(), 2);
+        assert_eq!(pool.mapTx[&b.hash()].get_count_with_ancestors(), 1);
+        assert_eq!(pool.mapTx[&c.hash()].get_count_with_ancestors(), 2);
+        assert_eq!(pool.mapTx[&c.hash()].get_fees_with_ancestors(), 110);
+        assert_indexes_in_sync(&pool);
+
+        // Recursive removal takes the grandchildren too
+        let mut pool = TxMemPoolInner::new(DEFAULT_MAX_MEMPOOL_SIZE * 1000000);
+        add(&mut pool, &a, 1);
+        add(&mut pool, &b, 10);
+        add(&mut pool, &c, 100);
+        let mut removed = VecDeque::new();
+        pool.remove(&b, &mut removed, true);
+        assert_eq!(removed.len(), 2);
+        assert_eq!(pool.size(), 1);
+        assert!(pool.mapNextTx.len() == 1 && pool.map_sapling_nullifier.is_empty());
+        assert_eq!(pool.mapTx[&a.hash()].get_count_with_descendants(), 1);
+        assert_eq!(pool.mapTx[&a.hash()].get_fees_with_descendants(), 1);
+        assert_indexes_in_sync(&pool);
+
+        // Also when the removed transaction itself isn't in the mempool any more
+        add(&mut pool, &c, 100);
+        pool.remove(&b, &mut removed, true);
+        assert!(!pool.exists(c.hash()));
+    }
+
+    #[test]
+    fn package_limits_bound_unconfirmed_chains() {
+        let a = spending(&confirmed(1), 0);
+        let b = spending(&a.hash(), 0);
+        let c = spending(&b.hash(), 0);
+        let n_size = a.get_serialize_size();
+
+        let mut pool = TxMemPoolInner::new(DEFAULT_MAX_MEMPOOL_SIZE * 1000000);
+        add(&mut pool, &a, 0);
+        add(&mut pool, &b, 0);
+        let entry = TxMemPoolEntry::new(Arc::new(c.clone()), 0, 0, 0.0, 1, false, false);
+        let check = |limits: PackageLimits| {
+            let mut set_ancestors = HashSet::new();
+            pool.calculate_mem_pool_ancestors(&entry, &mut set_ancestors, Some(&limits))
+        };
+
+        assert!(check(PackageLimits::default()));
+        let limits = PackageLimits {
+            n_ancestor_count: 3,
+            n_ancestor_size: 3 * n_size,
+            n_descendant_count: 3,
+            n_descendant_size: 3 * n_size,
+        };
+        assert!(check(limits));
+        assert!(!check(PackageLimits {
+            n_ancestor_count: 2,
+            ..limits
+        }));
+        assert!(!check(PackageLimits {
+            n_ancestor_size: 3 * n_size - 1,
+            ..limits
+        }));
+        assert!(!check(PackageLimits {
+            n_descendant_count: 2,
+            ..limits
+        }));
+        assert!(!check(PackageLimits {
+            n_descendant_size: 3 * n_size - 1,
+            ..limits
+        }));
+
+        let mut set_ancestors = HashSet::new();
+        assert!(pool.calculate_mem_pool_ancestors(&entry, &mut set_ancestors, None));
+        assert_eq!(set_ancestors.len(), 2);
+    }
+
+    #[test]
+    fn entries_cache_fee_and_size() {
+        let tx = spending(&confirmed(1), 0);
+        let n_size = tx.get_serialize_size();
+        let entry = TxMemPoolEntry::new(Arc::new(tx.clone()), 3000, 7, 0.0, 1, true, false);
+        assert_eq!(entry.get_fee(), 3000);
+        assert_eq!(entry.get_tx_size(), n_size);
+        assert_eq!(entry.get_fee_rate(), FeeRate::from_fee(3000, n_size));
+        assert_eq!(entry.get_time(), 7);
+
+        let mut pool = TxMemPoolInner::new(DEFAULT_MAX_MEMPOOL_SIZE * 1000000);
+        pool.add_unchecked(tx.hash(), entry);
+        assert_eq!(pool.size(), 1);
+        assert_eq!(pool.get_total_tx_size(), n_size);
+        assert!(pool.dynamic_memory_usage() > recursive_dynamic_usage(&tx));
+    }
+
+    #[test]
+    fn packages_are_ordered_by_ancestor_fee_rate() {
+        let parent = spending(&confirmed(1), 0);
+        let child = spending(&parent.hash(), 0);
+        let unrelated = spending(&confirmed(2), 0);
+
+        let mut pool = TxMemPoolInner::new(DEFAULT_MAX_MEMPOOL_SIZE * 1000000);
+        add(&mut pool, &parent, 100);
+        add(&mut pool, &child, 10000);
+        add(&mut pool, &unrelated, 2000);
+        assert!(!pool.mapTx[&child.hash()].was_clear_at_entry());
+
+        // The child pays for its parent
+        let order: Vec<FrHash> = pool.iter_by_ancestor_score().map(|tx| tx.hash()).collect();
+        assert_eq!(order, vec![parent.hash(), child.hash(), unrelated.hash()]);
+
+        let mut pool = TxMemPoolInner::new(DEFAULT_MAX_MEMPOOL_SIZE * 1000000);
+        add(&mut pool, &parent, 100);
+        add(&mut pool, &child, 200);
+        add(&mut pool, &unrelated, 2000);
+        let order: Vec<FrHash> = pool.iter_by_ancestor_score().map(|tx| tx.hash()).collect();
+        assert_eq!(order, vec![unrelated.hash(), parent.hash(), child.hash()]);
+
+        // Once the parent is taken, the child only counts with its own fee
+        let mut pool = TxMemPoolInner::new(DEFAULT_MAX_MEMPOOL_SIZE * 1000000);
+        add(&mut pool, &parent, 10000);
+        add(&mut pool, &child, 100);
+        add(&mut pool, &unrelated, 2000);
+        let order: Vec<FrHash> = pool.iter_by_ancestor_score().map(|tx| tx.hash()).collect();
+        assert_eq!(order, vec![parent.hash(), unrelated.hash(), child.hash()]);
+
+        // A long chain paid for by its last transaction
+        let mut chain = vec![spending(&confirmed(3), 0)];
+        for _ in 0..10 {
+            let tx = spending(&chain.last().unwrap().hash(), 0);
+            chain.push(tx);
+        }
+        let mut pool = TxMemPoolInner::new(DEFAULT_MAX_MEMPOOL_SIZE * 1000000);
+        for tx in chain.iter().take(10) {
+            add(&mut pool, tx, 0);
+        }
+        add(&mut pool, &chain[10], 11 * 2000);
+        add(&mut pool, &unrelated, 1000);
+        let order: Vec<FrHash> = pool.iter_by_ancestor_score().map(|tx| tx.hash()).collect();
+        let mut expected: Vec<FrHash> = chain.iter().map(|tx| tx.hash()).collect();
+        expected.push(unrelated.hash());
+        assert_eq!(order, expected);
+    }
+
+    #[test]
+    fn trim_to_size_evicts_lowest_fee_rate_packages() {
+        let parent = spending(&confirmed(1), 0);
+        let child = spending(&parent.hash(), 0);
+        let unrelated = spending(&confirmed(2), 0);
+
+        let mut pool = TxMemPoolInner::new(0);
+        add(&mut pool, &parent, 100);
+        add(&mut pool, &child, 5000);
+        add(&mut pool, &unrelated, 1000);
+
+        // The parent is worth keeping for its child
+        let mut removed = VecDeque::new();
+        let n_usage = pool.dynamic_memory_usage();
+        pool.trim_to_size(n_usage - 1, &mut removed);
+        assert_eq!(removed.len(), 1);
+        assert_eq!(removed[0].hash(), unrelated.hash());
+        assert_eq!(pool.size(), 2);
+
+        // A package goes as a whole
+        add(&mut pool, &unrelated, 10000);
+        removed.clear();
+        let n_usage = pool.dynamic_memory_usage();
+        pool.trim_to_size(n_usage - 1, &mut removed);
+        assert_eq!(removed.len(), 2);
+        assert!(pool.exists(unrelated.hash()));
+        assert_indexes_in_sync(&pool);
+
+        pool.trim_to_size(0, &mut removed);
+        assert_eq!(pool.size(), 0);
+        assert_eq!(pool.get_total_tx_size(), 0);
+        assert_eq!(pool.dynamic_memory_usage(), 0);
+    }
+
+    #[test]
+    fn replacements_pay_more_than_what_they_evict() {
+        let original = spending(&confirmed(1), 0);
+        let child = spending(&original.hash(), 0);
+        let mut replacement = MutableTransaction::from(&spending(&confirmed(1), 0));
+        replacement.vout[0].n_value -= 1;
+        let replacement = Transaction::from(replacement);
+
+        let mut pool = TxMemPoolInner::new(DEFAULT_MAX_MEMPOOL_SIZE * 1000000);
+        add(&mut pool, &original, 1000);
+        add(&mut pool, &child, 1000);
+        let set_conflicts = pool.get_conflicts(&replacement);
+        assert_eq!(set_conflicts.len(), 1);
+        assert!(set_conflicts.contains(&original.hash()));
+        assert!(pool.get_conflicts(&original).is_empty());
+
+        let check = |pool: &TxMemPoolInner, n_fee| {
+            let entry =
+                TxMemPoolEntry::new(Arc::new(replacement.clone()), n_fee, 0, 0.0, 1, true, false);
+            pool.check_replacement(&entry, &set_conflicts, &HashSet::new())
+        };
+        let n_relay_fee = pool
+            .get_incremental_relay_fee()
+            .get_fee(replacement.get_serialize_size());
+        assert!(n_relay_fee > 0);
+        // Replacement is opt-in
+        assert!(!check(&pool, 2000 + n_relay_fee));
+        pool.set_replacement_enabled(true);
+        assert!(check(&pool, 2000 + n_relay_fee));
+        // A lower fee rate than the original
+        assert!(!check(&pool, 900));
+        // Less than the original and its child pay together
+        assert!(!check(&pool, 1999));
+        // Not enough on top of that to pay for relaying the replacement
+        assert!(!check(&pool, 2000));
+        assert!(!check(&pool, 2000 + n_relay_fee - 1));
+
+        let mut removed = VecDeque::new();
+        pool.remove_conflicts(&replacement, &mut removed);
+        assert_eq!(removed.len(), 2);
+        assert_eq!(pool.size(), 0);
+    }
+
+    #[test]
+    fn trimmed_replacements_restore_what_they_replaced() {
+        let original = spending(&confirmed(1), 0);
+        let child = spending(&original.hash(), 0);
+        let unrelated = spending(&confirmed(2), 0);
+        // Much larger than the original and its child, so it doesn't fit in their place
+        let mut replacement = MutableTransaction::from(&spending(&confirmed(1), 0));
+        for _ in 0..100 {
+            replacement.vout.push(replacement.vout[0].clone());
+        }
+        let replacement = Transaction::from(replacement);
+
+        let mut pool = TxMemPoolInner::new(DEFAULT_MAX_MEMPOOL_SIZE * 1000000);
+        add(&mut pool, &original, 1000);
+        add(&mut pool, &child, 1000);
+        add(&mut pool, &unrelated, 100000);
+        pool.set_size_limit(pool.dynamic_memory_usage());
+
+        let entry =
+            TxMemPoolEntry::new(Arc::new(replacement.clone()), 5000, 0, 0.0, 1, true, false);
+        let set_conflicts = pool.get_conflicts(&replacement);
+        let mut replaced = VecDeque::new();
+        assert!(!pool.add_replacing(
+            replacement.hash(),
+            entry,
+            &HashSet::new(),
+            &set_conflicts,
+            &mut replaced
+        ));
+        assert!(replaced.is_empty());
+        assert!(!pool.exists(replacement.hash()));
+        assert_eq!(pool.size(), 3);
+        assert_eq!(pool.mapTx[&original.hash()].get_count_with_descendants(), 2);
+        assert_eq!(pool.mapTx[&child.hash()].get_fees_with_ancestors(), 2000);
+        assert_indexes_in_sync(&pool);
+
+        // With room for it, the replacement evicts the original and its child
+        pool.set_size_limit(DEFAULT_MAX_MEMPOOL_SIZE * 1000000);
+        let entry =
+            TxMemPoolEntry::new(Arc::new(replacement.clone()), 5000, 0, 0.0, 1, true, false);
+        assert!(pool.add_replacing(
+            replacement.hash(),
+            entry,
+            &HashSet::new(),
+            &set_conflicts,
+            &mut replaced
+        ));
+        assert_eq!(replaced.len(), 2);
+        assert_eq!(pool.size(), 2);
+        assert!(pool.exists(replacement.hash()) && pool.exists(unrelated.hash()));
+    }
+
+    #[test]
+    fn nullifiers_conflict() {
+        let original = transaction();
+        let mut replacement = MutableTransaction::from(&transaction());
+        replacement.vin.clear();
+        let replacement = Transaction::from(replacement);
+
+        let mut pool = TxMemPoolInner::new(DEFAULT_MAX_MEMPOOL_SIZE * 1000000);
+        add(&mut pool, &original, 1000);
+        assert!(pool.get_conflicts(&replacement).contains(&original.hash()));
+
+        let mut removed = VecDeque::new();
+        pool.remove_conflicts(&replacement, &mut removed);
+        assert_eq!(removed.len(), 1);
+        assert!(pool.map_sapling_nullifier.is_empty());
+    }
+
+    #[test]
+    fn reorgs_remove_invalidated_entries() {
+        let mut view = CoinViewCache::new();
+        let txout = TxOut {
+            n_value: 2000,
+            script_pub_key: Script::new(),
+        };
+        let cb = Transaction::new_coinbase(10, vec![txout]);
+        update_coins(&cb, &mut view, 10);
+        let spends_coinbase = spending(&cb.hash(), 0);
+        let mut time_locked = MutableTransaction::from(&spending(&confirmed(1), 0));
+        time_locked.lock_time = 109;
+        let time_locked = Transaction::from(time_locked);
+        let shielded = transaction();
+
+        let mut pool = TxMemPoolInner::new(DEFAULT_MAX_MEMPOOL_SIZE * 1000000);
+        let entry = TxMemPoolEntry::new(
+            Arc::new(spends_coinbase.clone()),
+            0,
+            0,
+            0.0,
+            110,
+            true,
+            true,
+        );
+        pool.add_unchecked(spends_coinbase.hash(), entry);
+        add(&mut pool, &time_locked, 0);
+        add(&mut pool, &shielded, 0);
+
+        // The coinbase is mature and the lock time passed in the next block
+        let mut chain_active = Chain {
+            v_chain: branch(None, 1, 110),
+        };
+        let mut removed = VecDeque::new();
+        pool.remove_for_reorg(&mut view, &chain_active, &mut removed);
+        assert!(removed.is_empty());
+
+        // Neither is the case after disconnecting the tip
+        chain_active.v_chain.pop();
+        pool.remove_for_reorg(&mut view, &chain_active, &mut removed);
+        assert_eq!(removed.len(), 2);
+        assert_eq!(pool.size(), 1);
+
+        // Spends from a disconnected anchor
+        pool.remove_with_anchor(FrHash(Fr::one()), &mut removed);
+        assert_eq!(pool.size(), 1);
+        let anchor = FrHash(shielded.v_shielded_spend[0].anchor);
+        pool.remove_with_anchor(anchor, &mut removed);
+        assert_eq!(pool.size(), 0);
+    }
+
+    #[test]
+    fn shared_pool_is_usable_from_many_threads() {
+        let pool = Arc::new(TxMemPool::new(DEFAULT_MAX_MEMPOOL_SIZE * 1000000));
+        let handles: Vec<_> = (0..4u8)
+            .map(|i| {
+                let pool = pool.clone();
+                thread::spawn(move || {
+                    let tx = spending(&confirmed(i), 0);
+                    add(&mut pool.lock(), &tx, 1000 * (i as CAmount + 1));
+                    tx.hash()
+                })
+            })
+            .collect();
+        let hashes: Vec<FrHash> = handles.into_iter().map(|h| h.join().unwrap()).collect();
+
+        assert_eq!(pool.size(), 4);
+        for hash in hashes.iter() {
+            assert!(pool.exists(*hash));
+            assert_eq!(pool.get(hash).unwrap().hash(), *hash);
+        }
+        // The highest fee first
+        let order: Vec<FrHash> = pool
+            .get_ancestor_score_order()
+            .iter()
+            .map(|tx| tx.hash())
+            .collect();
+        assert_eq!(order, hashes.into_iter().rev().collect::<Vec<_>>());
+    }
+}
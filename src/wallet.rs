@@ -2,9 +2,11 @@ use ethereum_types::U256;
 use ff::PrimeField;
 use pairing::bls12_381::{Bls12, Fr, FrRepr};
 use rand::Rng;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::fs::File;
 use std::io::prelude::*;
+use zcash_primitives::note_encryption::{try_sapling_note_decryption, try_sapling_output_recovery};
+use zcash_primitives::JUBJUB;
 
 use crate::block_chain::{Block, BlockIndex, Chain};
 use crate::coins::{CoinViewCache, CoinsView};
@@ -16,35 +18,38 @@ use crate::key::key_management::{
 use crate::key::key_store::KeyStore;
 use crate::main_impl::read_block_from_disk;
 use crate::my::constants::WITNESS_CACHE_SIZE;
-use crate::sendmany::SaplingOutPoint;
-use crate::transaction::NoteDataMap;
+use crate::sendmany::{SaplingNoteData, SaplingNoteEntry, SaplingOutPoint, SaplingOutgoingNote};
+use crate::transaction::{NoteDataMap, OutgoingNoteMap};
 use crate::transaction::{Transaction, WalletTransaction};
 
 type SaplingIncomingViewingKeyMap = HashMap<SaplingPaymentAddress, SaplingIncomingViewingKey>;
-//type TxSpendMap =
+//typedef std::multimap<COutPoint, uint256> TxSpends;
+//typedef std::multimap<uint256, uint256> TxNullifiers;
+//Maps each spent outpoint or nullifier to the hashes of the wallet transactions spending it.
+type TxSpendMap<T> = HashMap<T, Vec<FrHash>>;
 
-pub struct Wallet<'a> {
+pub struct Wallet {
     pub map_wallet: HashMap<FrHash, WalletTransaction>,
-    //pub map_tx_sapling_nullifier:
+    map_tx_spends: TxSpendMap<SaplingOutPoint>,
+    map_tx_sapling_nullifiers: TxSpendMap<U256>,
+    map_sapling_nullifiers_to_notes: HashMap<U256, SaplingOutPoint>,
     nWitnessCacheSize: usize,
     n_time_first_key: i64,
 
-    pub chain_active: &'a Chain,
-    pub pcoins_tip: &'a mut CoinViewCache,
-
     key_store: KeyStore,
     seed: [u8; 32],
 }
 
-impl<'a> Wallet<'a> {
-    pub fn new(pcoins_tip: &'a mut CoinViewCache, chain_active: &'a Chain) -> Self {
+impl Wallet {
+    pub fn new() -> Self {
         Wallet {
             nWitnessCacheSize: 0,
             map_wallet: HashMap::new(),
+            map_tx_spends: TxSpendMap::new(),
+            map_tx_sapling_nullifiers: TxSpendMap::new(),
+            map_sapling_nullifiers_to_notes: HashMap::new(),
             n_time_first_key: 0,
 
-            chain_active,
-            pcoins_tip,
             key_store: KeyStore::new(),
             seed: [0u8; 32],
         }
@@ -82,6 +87,8 @@ impl<'a> Wallet<'a> {
     //TODO, omit something for GUI
     pub fn scan_for_wallet_transactions(
         &mut self,
+        chain_active: &Chain,
+        pcoins_tip: &mut CoinViewCache,
         pindex_start: Option<BlockIndex>,
         f_update: bool,
     ) {
@@ -106,8 +113,8 @@ impl<'a> Wallet<'a> {
             let block = {
                 block.and_then(|b| {
                     for tx in b.vtx.iter() {
-                        if self.add_to_wallet_if_invloving_me(tx, &b, f_update) {
-                            my_tx_hashes.push(tx.hash);
+                        if self.add_to_wallet_if_invloving_me(tx, Some(&b), f_update) {
+                            my_tx_hashes.push(tx.hash());
                             ret += 1;
                         }
                     }
@@ -118,10 +125,9 @@ impl<'a> Wallet<'a> {
             let mut sapling_tree = {
                 let t_pindex = pindex.clone();
                 t_pindex.and_then(|p| {
-                    p.pprev.and_then(|pp| {
-                        self.pcoins_tip
-                            .get_sapling_anchor_at(pp.hash_final_sapling_root)
-                    })
+                    chain_active
+                        .get(p.nHeight - 1)
+                        .and_then(|pp| pcoins_tip.get_sapling_anchor_at(pp.hash_final_sapling_root))
                 })
             };
 
@@ -135,27 +141,116 @@ impl<'a> Wallet<'a> {
                 );
             }
 
-            pindex = pindex.and_then(|i| self.chain_active.next(i));
+            pindex = pindex.and_then(|i| chain_active.next(&i).cloned());
         }
     }
 
+    //std::pair<mapSaplingNoteData_t, SaplingIncomingViewingKeyMap> CWallet::FindMySaplingNotes(const CTransaction &tx) const
+    //Trial-decrypts every shielded output with each incoming viewing key. The nullifier is not
+    //cached here, it needs the note position in the tree which is only known once mined.
     pub fn find_my_sapling_notes(
         &self,
         tx: &Transaction,
     ) -> (NoteDataMap, SaplingIncomingViewingKeyMap) {
-        let hash = tx.hash;
+        let hash = tx.hash();
 
-        let note_data = NoteDataMap::new();
-        let viewing_keys_to_add = SaplingIncomingViewingKeyMap::new();
+        let mut note_data = NoteDataMap::new();
+        let mut viewing_keys_to_add = SaplingIncomingViewingKeyMap::new();
+
+        for (i, output) in tx.v_shielded_output.iter().enumerate() {
+            let epk = match output.ephemeral_key.as_prime_order(&JUBJUB) {
+                Some(epk) => epk,
+                None => continue,
+            };
 
-        for output in tx.v_shielded_output.iter() {
             let map_full_viewing_keys = self.key_store.get_map_full_viewing_keys();
-            for (ivk, val) in map_full_viewing_keys.iter() {}
+            for (ivk, _) in map_full_viewing_keys.iter() {
+                let (_, address, _) = match try_sapling_note_decryption(
+                    &ivk.0,
+                    &epk,
+                    &output.cmu,
+                    &output.enc_ciphertext,
+                ) {
+                    Some(result) => result,
+                    None => continue,
+                };
+
+                if self.key_store.get_incoming_viewing_key(&address).is_none() {
+                    viewing_keys_to_add.insert(address, *ivk);
+                }
+
+                let op = SaplingOutPoint { hash, n: i };
+                note_data.insert(op, SaplingNoteData::new(*ivk));
+                break;
+            }
         }
 
         (note_data, viewing_keys_to_add)
     }
 
+    //Recovers the outputs we sent by decrypting out_ciphertext with each of our outgoing
+    //viewing keys. This also finds payments made by another wallet sharing our seed.
+    pub fn find_my_sapling_outgoing_notes(&self, tx: &Transaction) -> OutgoingNoteMap {
+        let hash = tx.hash();
+        let mut outgoing = OutgoingNoteMap::new();
+
+        for (i, output) in tx.v_shielded_output.iter().enumerate() {
+            let epk = match output.ephemeral_key.as_prime_order(&JUBJUB) {
+                Some(epk) => epk,
+                None => continue,
+            };
+
+            let map_full_viewing_keys = self.key_store.get_map_full_viewing_keys();
+            for (_, fvk) in map_full_viewing_keys.iter() {
+                let (note, address, memo) = match try_sapling_output_recovery(
+                    &fvk.ovk,
+                    &output.cv,
+                    &output.cmu,
+                    &epk,
+                    &output.enc_ciphertext,
+                    &output.out_ciphertext,
+                ) {
+                    Some(result) => result,
+                    None => continue,
+                };
+
+                let op = SaplingOutPoint { hash, n: i };
+                outgoing.insert(
+                    op,
+                    SaplingOutgoingNote {
+                        address,
+                        note,
+                        memo,
+                    },
+                );
+                break;
+            }
+        }
+
+        outgoing
+    }
+
+    // support z_viewtransaction and sent history: who we paid, how much and with what memo.
+    pub fn get_sent_notes(&self, chain_active: &Chain, min_depth: i32) -> Vec<SaplingNoteEntry> {
+        let mut entries = Vec::new();
+        for (_, wtx) in self.map_wallet.iter() {
+            let depth = wtx.get_depth_in_main_chain(chain_active);
+            if depth < min_depth {
+                continue;
+            }
+            for (op, sent) in wtx.mapSaplingOutgoing.iter() {
+                entries.push(SaplingNoteEntry {
+                    op: *op,
+                    address: sent.address.clone(),
+                    note: sent.note.clone(),
+                    memo: sent.memo.clone(),
+                    confirmations: depth,
+                });
+            }
+        }
+        entries
+    }
+
     fn add_sapling_incoming_view_key(
         &mut self,
         ivk: SaplingIncomingViewingKey,
@@ -164,33 +259,67 @@ impl<'a> Wallet<'a> {
         self.key_store.add_incoming_viewing_key(ivk, addr)
     }
 
-    //TODO
-    fn add_to_transparent_spends(&mut self, outpoint: &SaplingOutPoint, wtxid: FrHash) {}
+    //void CWallet::AddToTransparentSpends(const COutPoint& outpoint, const uint256& wtxid)
+    fn add_to_transparent_spends(&mut self, outpoint: &SaplingOutPoint, wtxid: FrHash) {
+        self.map_tx_spends
+            .entry(*outpoint)
+            .or_insert_with(Vec::new)
+            .push(wtxid);
+    }
 
-    //TODO
-    fn add_to_sapling_spends(&mut self, nullifier: U256, wtxid: &FrHash) {}
+    //void CWallet::AddToSaplingSpends(const uint256& nullifier, const uint256& wtxid)
+    fn add_to_sapling_spends(&mut self, nullifier: U256, wtxid: &FrHash) {
+        self.map_tx_sapling_nullifiers
+            .entry(nullifier)
+            .or_insert_with(Vec::new)
+            .push(*wtxid);
+    }
 
     fn add_to_spends(&mut self, wtxid: FrHash) {
         assert!(self.map_wallet.contains_key(&wtxid));
 
-        let this_tx = self.map_wallet.get(&wtxid).unwrap();
-        if this_tx.tx.is_coin_base() {
+        let this_tx = &self.map_wallet.get(&wtxid).unwrap().tx;
+        if this_tx.is_coin_base() {
             return;
         }
 
-        //TODO(can be deal later), uncomment it then compile error
-        /*
-        for txin in this_tx.tx.vin.iter() {
-            self.add_to_transparent_spends(&txin.prevout, wtxid);
+        let prevouts: Vec<SaplingOutPoint> = this_tx.vin.iter().map(|txin| txin.prevout).collect();
+        let nullifiers: Vec<U256> = this_tx
+            .v_shielded_spend
+            .iter()
+            .map(|spend| U256::from(spend.nullifier))
+            .collect();
+
+        for prevout in prevouts.iter() {
+            self.add_to_transparent_spends(prevout, wtxid);
+        }
+
+        for nullifier in nullifiers {
+            self.add_to_sapling_spends(nullifier, &wtxid);
         }
+    }
 
-        for spend in this_tx.tx.v_shielded_spend.iter() {
-            self.add_to_sapling_spends(U256::from(spend.nullifier), &wtxid);
-        }*/
+    //bool CWallet::IsSpent(const uint256& hash, unsigned int n) const
+    //TODO take the depth of the spending transaction into account
+    pub fn is_spent(&self, outpoint: &SaplingOutPoint) -> bool {
+        self.is_spent_by_wallet_tx(self.map_tx_spends.get(outpoint))
+    }
+
+    //bool CWallet::IsSaplingSpent(const uint256& nullifier) const
+    pub fn is_sapling_spent(&self, nullifier: &U256) -> bool {
+        self.is_spent_by_wallet_tx(self.map_tx_sapling_nullifiers.get(nullifier))
+    }
+
+    fn is_spent_by_wallet_tx(&self, wtxids: Option<&Vec<FrHash>>) -> bool {
+        wtxids.map_or(false, |wtxids| {
+            wtxids
+                .iter()
+                .any(|wtxid| self.map_wallet.contains_key(wtxid))
+        })
     }
 
     fn add_to_wallet(&mut self, wtx_in: WalletTransaction, f_from_load_wallet: bool) -> bool {
-        let hash = wtx_in.tx.hash;
+        let hash = wtx_in.tx.hash();
         if f_from_load_wallet {
             //No DB yet
         } else {
@@ -198,14 +327,18 @@ impl<'a> Wallet<'a> {
             if !self.map_wallet.contains_key(&hash) {
                 self.map_wallet.insert(hash, wtx_in);
                 f_inserted_new = true;
+            } else {
+                // Merge newly found notes without dropping the witnesses of known ones.
+                let wtx = self.map_wallet.get_mut(&hash).unwrap();
+                for (op, nd) in wtx_in.mapSaplingData {
+                    wtx.mapSaplingData.entry(op).or_insert(nd);
+                }
+                wtx.mapSaplingOutgoing.extend(wtx_in.mapSaplingOutgoing);
             }
-            let mut wtx = self.map_wallet.get_mut(&hash).unwrap();
-
             //TODO, uncomment it then compile error
             //wtx.bind_wallet(&self);
 
-            //TODO, uncomment it then compile error
-            //self.update_sapling_nullifier_note_map_with_tx(wtx);
+            self.update_sapling_nullifier_note_map_with_tx(&hash);
 
             if f_inserted_new {
                 self.add_to_spends(hash);
@@ -219,18 +352,22 @@ impl<'a> Wallet<'a> {
         false
     }
 
-    //TODO
+    //bool CWallet::IsFromMe(const CTransaction& tx) const
+    //TODO check the debit of transparent inputs
     fn is_from_me(&self, tx: &Transaction) -> bool {
-        false
+        tx.v_shielded_spend.iter().any(|spend| {
+            self.map_sapling_nullifiers_to_notes
+                .contains_key(&U256::from(spend.nullifier))
+        })
     }
 
     pub fn add_to_wallet_if_invloving_me(
         &mut self,
         tx: &Transaction,
-        block: &Block,
+        pblock: Option<&Block>,
         f_update: bool,
     ) -> bool {
-        let f_existed = self.map_wallet.contains_key(&tx.hash);
+        let f_existed = self.map_wallet.contains_key(&tx.hash());
         if f_existed && !f_update {
             return false;
         }
@@ -241,17 +378,30 @@ impl<'a> Wallet<'a> {
                 return false;
             }
         }
+        let sapling_outgoing = self.find_my_sapling_outgoing_notes(tx);
 
-        if f_existed || self.is_mine(tx) || self.is_from_me(tx) || sapling_note_data.len() > 0 {
+        if f_existed
+            || self.is_mine(tx)
+            || self.is_from_me(tx)
+            || sapling_note_data.len() > 0
+            || sapling_outgoing.len() > 0
+        {
             let mut wtx = WalletTransaction::new((*tx).clone());
             if sapling_note_data.len() > 0 {
                 wtx.mapSaplingData = sapling_note_data;
             }
+            wtx.mapSaplingOutgoing = sapling_outgoing;
             return self.add_to_wallet(wtx, false);
         }
         true
     }
 
+    //void CWallet::SyncTransaction(const CTransaction& tx, const CBlock* pblock)
+    //Called for transactions entering the mempool (pblock is None) and for connected blocks.
+    pub fn sync_transaction(&mut self, tx: &Transaction, pblock: Option<&Block>) {
+        self.add_to_wallet_if_invloving_me(tx, pblock, true);
+    }
+
     pub fn get_sapling_note_witnesses(
         &self,
         notes: Vec<&SaplingOutPoint>,
@@ -264,7 +414,7 @@ impl<'a> Wallet<'a> {
                 self.map_wallet.get(&note.hash).and_then(|tx| {
                     tx.mapSaplingData.get(&note).and_then(|data| {
                         data.witnesses.front().and_then(|witness| {
-                            let r = witness.root().unwrap();
+                            let r = witness.root();
 
                             match rt.clone() {
                                 None => {
@@ -296,48 +446,60 @@ impl<'a> Wallet<'a> {
         } else {
             self.decrement_note_witnesses(pindex);
         }
+        // Record where our transactions are mined so their depth can be computed.
+        for tx in pblock.vtx.iter() {
+            if let Some(wtx) = self.map_wallet.get_mut(&tx.hash()) {
+                wtx.block_height = if added { Some(pindex.nHeight) } else { None };
+            }
+        }
         self.update_sapling_nullifier_note_map_for_block(pblock);
     }
 
     fn update_sapling_nullifier_note_map_for_block(&mut self, pblock: &Block) {
         for tx in pblock.vtx.iter() {
-            let hash = &tx.hash;
+            let hash = &tx.hash();
             let tx_is_ours = self.map_wallet.contains_key(hash);
             if tx_is_ours {
-                let wtx = self.map_wallet.get_mut(hash);
-                //self.update_sapling_nullifier_note_map_with_tx(&mut wtx.unwrap());
+                self.update_sapling_nullifier_note_map_with_tx(hash);
             }
         }
     }
-    //            uint64_t position = nd.witnesses.front().position();
-    //            SaplingFullViewingKey fvk = mapSaplingFullViewingKeys.at(nd.ivk);
-    //            OutputDescription output = wtx.vShieldedOutput[op.n];
-    //            auto optPlaintext = SaplingNotePlaintext::decrypt(output.encCiphertext, nd.ivk, output.ephemeralKey, output.cm);
-    //            if (!optPlaintext) {
-    //                // An item in mapSaplingNoteData must have already been successfully decrypted,
-    //                // otherwise the item would not exist in the first place.
-    //                assert(false);
-    //            }
-    //            auto optNote = optPlaintext.get().note(nd.ivk);
-    //            if (!optNote) {
-    //                assert(false);
-    //            }
-    //            auto optNullifier = optNote.get().nullifier(fvk, position);
-    //            if (!optNullifier) {
-    //                // This should not happen.  If it does, maybe the position has been corrupted or miscalculated?
-    //                assert(false);
-    //            }
-    //            uint256 nullifier = optNullifier.get();
-    //            mapSaplingNullifiersToNotes[nullifier] = op;
-    //            item.second.nullifier = nullifier;
+    fn update_sapling_nullifier_note_map_with_tx(&mut self, wtxid: &FrHash) {
+        let wtx = match self.map_wallet.get_mut(wtxid) {
+            Some(wtx) => wtx,
+            None => return,
+        };
 
-    //TODO
-    fn update_sapling_nullifier_note_map_with_tx(&mut self, wtx: &mut WalletTransaction) {
-        for (op, nd) in wtx.mapSaplingData.iter() {
+        for (op, nd) in wtx.mapSaplingData.iter_mut() {
             if nd.witnesses.is_empty() {
-
+                // If there are no witnesses, erase the nullifier and associated mapping.
+                if let Some(nullifier) = nd.nullifier.take() {
+                    self.map_sapling_nullifiers_to_notes.remove(&nullifier);
+                }
             } else {
-
+                let position = nd.witnesses.front().unwrap().position() as u64;
+                let fvk = self
+                    .key_store
+                    .get_full_viewing_key(&nd.ivk)
+                    .expect("a note's viewing key should be in the key store");
+                let output = &wtx.tx.v_shielded_output[op.n];
+                let epk = output
+                    .ephemeral_key
+                    .as_prime_order(&JUBJUB)
+                    .expect("a decrypted output should have a valid ephemeral key");
+                // An item in mapSaplingData must have already been successfully decrypted,
+                // otherwise the item would not exist in the first place.
+                let (note, _, _) = try_sapling_note_decryption(
+                    &nd.ivk.0,
+                    &epk,
+                    &output.cmu,
+                    &output.enc_ciphertext,
+                )
+                .expect("a note in the wallet should decrypt");
+
+                let nullifier = U256::from(&note.nf(&fvk.vk, position, &JUBJUB)[..]);
+                self.map_sapling_nullifiers_to_notes.insert(nullifier, *op);
+                nd.nullifier = Some(nullifier);
             }
         }
     }
@@ -397,7 +559,32 @@ impl<'a> Wallet<'a> {
     //    }
     //}
 
-    pub fn decrement_note_witnesses(&mut self, pindex: &BlockIndex) {}
+    //void CWallet::DecrementNoteWitnesses(const CBlockIndex* pindex)
+    //Notes created in the disconnected block no longer exist on the chain, so they are
+    //dropped together with their nullifiers. They are found again if the block is reconnected.
+    pub fn decrement_note_witnesses(&mut self, pindex: &BlockIndex) {
+        for (_, wtx) in self.map_wallet.iter_mut() {
+            if wtx.block_height != Some(pindex.nHeight) {
+                continue;
+            }
+            for (_, nd) in wtx.mapSaplingData.drain() {
+                if let Some(nullifier) = nd.nullifier {
+                    self.map_sapling_nullifiers_to_notes.remove(&nullifier);
+                }
+            }
+        }
+        for (_, wtx) in self.map_wallet.iter_mut() {
+            decrement_witnesses(
+                &mut wtx.mapSaplingData,
+                pindex.nHeight,
+                self.nWitnessCacheSize,
+            );
+        }
+        //TODO: If nWitnessCacheSize is zero, we need to regenerate the caches
+        if self.nWitnessCacheSize > 0 {
+            self.nWitnessCacheSize -= 1;
+        }
+    }
 
     //void CWallet::IncrementNoteWitnesses(const CBlockIndex* pindex,
     //                                     const CBlock* pblockIn,
@@ -422,7 +609,7 @@ impl<'a> Wallet<'a> {
         }
 
         for tx in pblockIn.vtx.iter() {
-            let hash = &tx.hash;
+            let hash = &tx.hash();
             let tx_is_ours = self.map_wallet.contains_key(hash);
             for (i, item) in tx.v_shielded_output.iter().enumerate() {
                 //let repr = item.cmu.into_repr().as_ref();
@@ -431,7 +618,9 @@ impl<'a> Wallet<'a> {
                 let note_commitement = FrHash(cm);
                 let note_commitement_1 = note_commitement.clone();
                 let note_commitement_2 = note_commitement.clone();
-                saplingTree.append(note_commitement);
+                saplingTree
+                    .append(note_commitement)
+                    .expect("sapling commitment tree should not be full");
 
                 for (_, wtx) in self.map_wallet.iter_mut() {
                     let cm = note_commitement_1.clone();
@@ -444,7 +633,7 @@ impl<'a> Wallet<'a> {
                 }
 
                 if tx_is_ours {
-                    let t_hash = tx.hash.clone();
+                    let t_hash = tx.hash();
                     let out_point = SaplingOutPoint {
                         hash: t_hash,
                         n: i as usize,
@@ -457,7 +646,7 @@ impl<'a> Wallet<'a> {
                         self.nWitnessCacheSize,
                         note_commitement_2,
                         out_point,
-                        saplingTree.witness().unwrap(),
+                        saplingTree.witness(),
                     );
                 }
             }
@@ -500,13 +689,74 @@ impl<'a> Wallet<'a> {
 
     //GetFilteredNotes(
     //    std::vector<SaplingNoteEntry>& saplingEntries,
-    //    std::string address,
+    //    std::set<PaymentAddress>& filterAddresses,
     //    int minDepth,
+    //    int maxDepth,
     //    bool ignoreSpent,
     //    bool requireSpendingKey)
-    // support z_listunspent
-    //TODO hu yuan
-    pub fn get_filtered_notes() {}
+    // support z_listunspent and sendmany
+    // An empty filterAddresses matches notes of every address in the wallet.
+    pub fn get_filtered_notes(
+        &self,
+        chain_active: &Chain,
+        filter_addresses: &HashSet<SaplingPaymentAddress>,
+        min_depth: i32,
+        max_depth: i32,
+        ignore_spent: bool,
+        require_spending_key: bool,
+    ) -> Vec<SaplingNoteEntry> {
+        let mut sapling_entries = Vec::new();
+
+        for (_, wtx) in self.map_wallet.iter() {
+            // Filter the transactions before checking for notes
+            let depth = wtx.get_depth_in_main_chain(chain_active);
+            if depth < min_depth || depth > max_depth {
+                continue;
+            }
+
+            for (op, nd) in wtx.mapSaplingData.iter() {
+                let output = &wtx.tx.v_shielded_output[op.n];
+                let epk = output
+                    .ephemeral_key
+                    .as_prime_order(&JUBJUB)
+                    .expect("a decrypted output should have a valid ephemeral key");
+                let (note, pa, memo) = try_sapling_note_decryption(
+                    &nd.ivk.0,
+                    &epk,
+                    &output.cmu,
+                    &output.enc_ciphertext,
+                )
+                .expect("a note in the wallet should decrypt");
+
+                // skip notes which belong to a different payment address in the wallet
+                if !(filter_addresses.is_empty() || filter_addresses.contains(&pa)) {
+                    continue;
+                }
+
+                if ignore_spent {
+                    if let Some(nullifier) = nd.nullifier {
+                        if self.is_sapling_spent(&nullifier) {
+                            continue;
+                        }
+                    }
+                }
+
+                // skip notes which cannot be spent
+                if require_spending_key && self.key_store.get_extended_spending_key(&pa).is_none() {
+                    continue;
+                }
+
+                sapling_entries.push(SaplingNoteEntry {
+                    op: *op,
+                    address: pa,
+                    note,
+                    memo,
+                    confirmations: depth,
+                });
+            }
+        }
+        sapling_entries
+    }
 
     //bool CWallet::CreateTransaction(const vector<CRecipient>& vecSend, CWalletTx& wtxNew, CReserveKey& reservekey, CAmount& nFeeRet,
     //support sendmany
@@ -548,7 +798,11 @@ fn append_note_commitement(
             //nd.witnesses.front().
             //    and_then(|witness| witness.append(note_commitement));
             let cm = note_commitement.clone();
-            nd.witnesses.front().unwrap().append(cm);
+            nd.witnesses
+                .front_mut()
+                .unwrap()
+                .append(cm)
+                .expect("witness tree should not be full");
         }
     }
 }
@@ -573,6 +827,24 @@ fn witness_note_if_mine(
     }
 }
 
+fn decrement_witnesses(noteDataMap: &mut NoteDataMap, indexHeight: i32, nWitnessCacheSize: usize) {
+    for (_, nd) in noteDataMap.iter_mut() {
+        // Only decrement witnesses that are not above the current height
+        if nd.witnessHeight <= indexHeight {
+            assert!(nWitnessCacheSize >= nd.witnesses.len());
+            // Witnesses being decremented should always be either -1 (never incremented or
+            // decremented) or equal to the height of the block being removed.
+            assert!(nd.witnessHeight == -1 || nd.witnessHeight == indexHeight);
+            if nd.witnesses.len() > 0 {
+                nd.witnesses.pop_front();
+            }
+            // indexHeight is the height of the block being removed, so the new witness
+            // cache height is one below it.
+            nd.witnessHeight = indexHeight - 1;
+        }
+    }
+}
+
 fn update_witness_heights(
     noteDataMap: &mut NoteDataMap,
     indexHeight: i32,
@@ -596,18 +868,25 @@ pub fn show() {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::block_chain::BlockHeader;
+    use crate::key::key_management::{SaplingFullViewingKey, SaplingOutgoingViewingKey};
+    use crate::my::constants::MAX_REORG_LENGTH;
+    use crate::transaction::tests::spend_description;
+    use ff::Field;
+    use rand::OsRng;
+    use sapling_crypto::jubjub::edwards;
+    use sapling_crypto::primitives::Diversifier;
+    use zcash_primitives::note_encryption::{Memo, SaplingNoteEncryption};
+
     #[test]
     fn test_save_load_wallet() {
-        let chain_active = Chain::new();
-        let mut pcoins_tip = CoinViewCache::new();
-        let mut wallet = Wallet::new(&mut pcoins_tip, &chain_active);
+        let mut wallet = Wallet::new();
         wallet.set_seed([1u8; 32]);
         let addresses1 = wallet.key_store.get_sapling_payment_addresses();
         assert_eq!(addresses1.len(), 1);
 
         assert!(wallet.save_to_file("wallet.txt").is_ok());
-        let mut pcoins_tip2 = CoinViewCache::new();
-        let mut wallet2 = Wallet::new(&mut pcoins_tip2, &chain_active);
+        let mut wallet2 = Wallet::new();
         assert!(wallet2.load_from_file("wallet.txt").is_ok());
         // Test address is the same from loaded wallet.
         let addresses2 = wallet2.key_store.get_sapling_payment_addresses();
@@ -617,4 +896,376 @@ mod tests {
         }
     }
 
+    // Encrypts a note to `to`, with dummy value commitment and proof.
+    fn output_to(to: &SaplingPaymentAddress, value: u64) -> SaplingOutputDescription {
+        let ovk = SaplingExtendedSpendingKey::master(&[9u8; 32]).expsk.ovk;
+        output_from(ovk, to, value, Memo::default())
+    }
+
+    fn output_from(
+        ovk: SaplingOutgoingViewingKey,
+        to: &SaplingPaymentAddress,
+        value: u64,
+        memo: Memo,
+    ) -> SaplingOutputDescription {
+        let mut rng = OsRng::new().unwrap();
+        let note = to.create_note(value, rng.gen(), &JUBJUB).unwrap();
+        let encryptor = SaplingNoteEncryption::new(ovk, note.clone(), to.clone(), memo);
+        let cmu = note.cm(&JUBJUB);
+        let cv = edwards::Point::zero();
+
+        SaplingOutputDescription {
+            cv,
+            cmu,
+            ephemeral_key: encryptor.epk().clone().into(),
+            enc_ciphertext: encryptor.encrypt_note_plaintext(),
+            out_ciphertext: encryptor.encrypt_outgoing_plaintext(&cv, &cmu),
+            zkproof: [0u8; 192],
+        }
+    }
+
+    #[test]
+    fn find_my_sapling_notes_by_trial_decryption() {
+        let mut wallet = Wallet::new();
+        let address = wallet.set_seed([1u8; 32]);
+
+        let other =
+            SaplingExtendedFullViewingKey::from(&SaplingExtendedSpendingKey::master(&[2u8; 32]))
+                .default_address()
+                .unwrap()
+                .1;
+
+        let mut corrupted = output_to(&address, 7);
+        corrupted.cmu = Fr::one();
+        let tx = Transaction::new(
+            vec![],
+            vec![],
+            vec![],
+            vec![output_to(&other, 5), output_to(&address, 6), corrupted],
+            -18,
+            [0u8; 64],
+        );
+
+        let (note_data, addresses) = wallet.find_my_sapling_notes(&tx);
+        assert_eq!(note_data.len(), 1);
+        assert!(addresses.is_empty());
+        let op = SaplingOutPoint {
+            hash: tx.hash(),
+            n: 1,
+        };
+        assert!(note_data[&op].witnesses.is_empty());
+        assert!(note_data[&op].nullifier.is_none());
+
+        // Payments to another diversified address of the same key are detected too.
+        let fvk = wallet
+            .key_store
+            .get_full_viewing_key(&note_data[&op].ivk)
+            .unwrap();
+        let diversified = (1u8..)
+            .filter_map(|b| fvk.vk.into_payment_address(Diversifier([b; 11]), &JUBJUB))
+            .next()
+            .unwrap();
+        let tx2 = Transaction::new(
+            vec![],
+            vec![],
+            vec![],
+            vec![output_to(&diversified, 1)],
+            -1,
+            [0u8; 64],
+        );
+        let (note_data, addresses) = wallet.find_my_sapling_notes(&tx2);
+        assert_eq!(note_data.len(), 1);
+        assert!(addresses.contains_key(&diversified));
+
+        assert!(wallet.add_to_wallet_if_invloving_me(&tx, None, false));
+        assert!(wallet.map_wallet.contains_key(&tx.hash()));
+    }
+
+    #[test]
+    fn revealed_nullifier_marks_note_spent() {
+        let mut wallet = Wallet::new();
+        let address = wallet.set_seed([1u8; 32]);
+
+        let tx = Transaction::new(
+            vec![],
+            vec![],
+            vec![],
+            vec![output_to(&address, 6)],
+            -6,
+            [0u8; 64],
+        );
+        wallet.sync_transaction(&tx, None);
+        let op = SaplingOutPoint {
+            hash: tx.hash(),
+            n: 0,
+        };
+        // Without a witness the note position, and so its nullifier, is unknown.
+        assert!(wallet.map_wallet[&tx.hash()].mapSaplingData[&op]
+            .nullifier
+            .is_none());
+
+        let block = Block {
+            header: BlockHeader::new(),
+            vtx: vec![tx.clone()],
+        };
+        let mut tree = SaplingMerkleTree::new();
+        wallet.chain_tip(&BlockIndex::new(U256::from(1), 1), &block, &mut tree, true);
+        let nullifier = wallet.map_wallet[&tx.hash()].mapSaplingData[&op]
+            .nullifier
+            .unwrap();
+        assert_eq!(wallet.map_sapling_nullifiers_to_notes[&nullifier], op);
+        assert!(!wallet.is_sapling_spent(&nullifier));
+
+        // A mempool transaction revealing the nullifier is ours and spends the note.
+        let mut spend = spend_description();
+        nullifier.to_big_endian(&mut spend.nullifier);
+        let spend_tx = Transaction::new(vec![], vec![], vec![spend], vec![], 6, [0u8; 64]);
+        wallet.sync_transaction(&spend_tx, None);
+        assert!(wallet.map_wallet.contains_key(&spend_tx.hash()));
+        assert!(wallet.is_sapling_spent(&nullifier));
+
+        // Unrelated spends are ignored.
+        let other_tx = Transaction::new(
+            vec![],
+            vec![],
+            vec![spend_description()],
+            vec![],
+            0,
+            [0u8; 64],
+        );
+        wallet.sync_transaction(&other_tx, None);
+        assert!(!wallet.map_wallet.contains_key(&other_tx.hash()));
+    }
+
+    #[test]
+    fn get_filtered_notes_by_depth_address_and_spent() {
+        let mut chain_active = Chain::new();
+        for height in 0..3 {
+            chain_active
+                .v_chain
+                .push(BlockIndex::new(U256::from(height + 1), height));
+        }
+        let mut wallet = Wallet::new();
+        let address = wallet.set_seed([1u8; 32]);
+
+        // A watch-only address, the wallet has no spending key for it.
+        let watch_xsk = SaplingExtendedSpendingKey::master(&[2u8; 32]);
+        let (_, watch_address) = SaplingExtendedFullViewingKey::from(&watch_xsk)
+            .default_address()
+            .unwrap();
+        let watch_fvk =
+            SaplingFullViewingKey::from_expanded_spending_key(&watch_xsk.expsk, &JUBJUB);
+        wallet
+            .key_store
+            .add_full_viewing_key(watch_fvk, watch_address.clone());
+
+        let mined = Transaction::new(
+            vec![],
+            vec![],
+            vec![],
+            vec![output_to(&address, 6), output_to(&watch_address, 4)],
+            -10,
+            [0u8; 64],
+        );
+        let unconfirmed = Transaction::new(
+            vec![],
+            vec![],
+            vec![],
+            vec![output_to(&address, 3)],
+            -3,
+            [0u8; 64],
+        );
+        wallet.sync_transaction(&mined, None);
+        wallet.sync_transaction(&unconfirmed, None);
+        let block = Block {
+            header: BlockHeader::new(),
+            vtx: vec![mined.clone()],
+        };
+        let mut tree = SaplingMerkleTree::new();
+        wallet.chain_tip(&BlockIndex::new(U256::from(2), 1), &block, &mut tree, true);
+
+        let values = |entries: Vec<SaplingNoteEntry>| {
+            let mut values: Vec<u64> = entries.iter().map(|e| e.note.value).collect();
+            values.sort();
+            values
+        };
+        let all = HashSet::new();
+        let max = i32::max_value();
+
+        let entries = wallet.get_filtered_notes(&chain_active, &all, 1, max, true, false);
+        assert!(entries.iter().all(|e| e.confirmations == 2));
+        assert_eq!(values(entries), vec![4, 6]);
+        assert_eq!(
+            values(wallet.get_filtered_notes(&chain_active, &all, 0, max, true, false)),
+            vec![3, 4, 6]
+        );
+        assert!(wallet
+            .get_filtered_notes(&chain_active, &all, 1, 1, true, false)
+            .is_empty());
+        assert_eq!(
+            values(wallet.get_filtered_notes(&chain_active, &all, 1, max, true, true)),
+            vec![6]
+        );
+
+        let mut watch_only = HashSet::new();
+        watch_only.insert(watch_address);
+        let entries = wallet.get_filtered_notes(&chain_active, &watch_only, 1, max, true, false);
+        assert_eq!(entries.len(), 1);
+        assert_eq!(
+            entries[0].op,
+            SaplingOutPoint {
+                hash: mined.hash(),
+                n: 1
+            }
+        );
+
+        // Spent notes are only listed when asked for.
+        let op = SaplingOutPoint {
+            hash: mined.hash(),
+            n: 0,
+        };
+        let nullifier = wallet.map_wallet[&mined.hash()].mapSaplingData[&op]
+            .nullifier
+            .unwrap();
+        let mut spend = spend_description();
+        nullifier.to_big_endian(&mut spend.nullifier);
+        let spend_tx = Transaction::new(vec![], vec![], vec![spend], vec![], 6, [0u8; 64]);
+        wallet.sync_transaction(&spend_tx, None);
+        assert_eq!(
+            values(wallet.get_filtered_notes(&chain_active, &all, 1, max, true, false)),
+            vec![4]
+        );
+        assert_eq!(
+            values(wallet.get_filtered_notes(&chain_active, &all, 1, max, false, false)),
+            vec![4, 6]
+        );
+    }
+
+    #[test]
+    fn outgoing_notes_are_recovered_with_our_ovk() {
+        let mut wallet = Wallet::new();
+        wallet.set_seed([1u8; 32]);
+
+        // The same seed used on another device sent these outputs.
+        let our_ovk = SaplingExtendedSpendingKey::master(&[1u8; 32]).expsk.ovk;
+        let recipient =
+            SaplingExtendedFullViewingKey::from(&SaplingExtendedSpendingKey::master(&[2u8; 32]))
+                .default_address()
+                .unwrap()
+                .1;
+        let memo = Memo::from_bytes(b"rent").unwrap();
+        let tx = Transaction::new(
+            vec![],
+            vec![],
+            vec![],
+            vec![
+                output_to(&recipient, 5),
+                output_from(our_ovk, &recipient, 8, memo.clone()),
+            ],
+            -13,
+            [0u8; 64],
+        );
+
+        let block = Block {
+            header: BlockHeader::new(),
+            vtx: vec![tx.clone()],
+        };
+        wallet.sync_transaction(&tx, Some(&block));
+        assert!(wallet.map_wallet.contains_key(&tx.hash()));
+
+        let sent = wallet.get_sent_notes(&Chain::new(), 0);
+        assert_eq!(sent.len(), 1);
+        assert_eq!(
+            sent[0].op,
+            SaplingOutPoint {
+                hash: tx.hash(),
+                n: 1
+            }
+        );
+        assert!(sent[0].address == recipient);
+        assert_eq!(sent[0].note.value, 8);
+        assert!(sent[0].memo == memo);
+
+        // None of the outputs were sent to us.
+        assert!(wallet
+            .get_filtered_notes(
+                &Chain::new(),
+                &HashSet::new(),
+                0,
+                i32::max_value(),
+                false,
+                false
+            )
+            .is_empty());
+    }
+
+    fn witness_roots(wallet: &Wallet) -> HashMap<SaplingOutPoint, FrHash> {
+        let mut roots = HashMap::new();
+        for wtx in wallet.map_wallet.values() {
+            for (op, nd) in wtx.mapSaplingData.iter() {
+                if let Some(witness) = nd.witnesses.front() {
+                    roots.insert(*op, witness.root());
+                }
+            }
+        }
+        roots
+    }
+
+    #[test]
+    fn disconnected_blocks_restore_witnesses() {
+        let mut wallet = Wallet::new();
+        let address = wallet.set_seed([1u8; 32]);
+        let other =
+            SaplingExtendedFullViewingKey::from(&SaplingExtendedSpendingKey::master(&[2u8; 32]))
+                .default_address()
+                .unwrap()
+                .1;
+
+        let mut tree = SaplingMerkleTree::new();
+        let mut blocks = Vec::new();
+        // The roots of every cached witness after each connected block
+        let mut roots = Vec::new();
+        for height in 0..MAX_REORG_LENGTH as i32 {
+            let to = if height % 40 == 0 { &address } else { &other };
+            let tx = Transaction::new(
+                vec![],
+                vec![],
+                vec![],
+                vec![output_to(to, 1)],
+                -1,
+                [0u8; 64],
+            );
+            let block = Block {
+                header: BlockHeader::new(),
+                vtx: vec![tx],
+            };
+            wallet.sync_transaction(&block.vtx[0], Some(&block));
+            let pindex = BlockIndex::new(U256::from(height + 1), height);
+            wallet.chain_tip(&pindex, &block, &mut tree, true);
+            roots.push(witness_roots(&wallet));
+            blocks.push(block);
+        }
+        assert_eq!(roots.last().unwrap().len(), 3);
+        assert_eq!(wallet.map_sapling_nullifiers_to_notes.len(), 3);
+
+        for height in (0..MAX_REORG_LENGTH as i32).rev() {
+            assert_eq!(witness_roots(&wallet), roots[height as usize]);
+            let pindex = BlockIndex::new(U256::from(height + 1), height);
+            wallet.chain_tip(&pindex, &blocks[height as usize], &mut tree, false);
+
+            // The note of the disconnected block is gone with its nullifier.
+            let hash = blocks[height as usize].vtx[0].hash();
+            assert!(wallet.map_wallet[&hash].mapSaplingData.is_empty());
+            let n_notes = roots[height as usize].len() - (height % 40 == 0) as usize;
+            assert_eq!(wallet.map_sapling_nullifiers_to_notes.len(), n_notes);
+        }
+
+        // Every note was created in a disconnected block, so none of them is left.
+        for wtx in wallet.map_wallet.values() {
+            assert!(wtx.block_height.is_none());
+            assert!(wtx.mapSaplingData.is_empty());
+        }
+        assert!(wallet.map_sapling_nullifiers_to_notes.is_empty());
+        assert_eq!(wallet.nWitnessCacheSize, 0);
+    }
 }
@@ -0,0 +1,184 @@
+//! A pool of worker threads running verification jobs, modeled on zcashd's CCheckQueue.
+//!
+//! The master thread adds jobs and then waits for all of them. As soon as one job fails the
+//! remaining ones are dropped without being run.
+
+use std::collections::VecDeque;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread::{self, JoinHandle};
+
+pub type Job = Box<dyn FnOnce() -> bool + Send>;
+
+lazy_static! {
+    // Shared by everything that verifies blocks, the lock keeps batches from interleaving.
+    pub static ref VERIFY_QUEUE: Mutex<WorkQueue> = Mutex::new(WorkQueue::new(
+        thread::available_parallelism()
+            .map(|n| n.get())
+            .unwrap_or(1)
+    ));
+}
+
+struct QueueState {
+    jobs: VecDeque<Job>,
+    // Jobs added since the last wait that have not finished yet
+    n_todo: usize,
+    // Whether all jobs finished so far succeeded
+    f_all_ok: bool,
+    f_quit: bool,
+}
+
+struct Shared {
+    state: Mutex<QueueState>,
+    // Signalled when jobs are added or the queue shuts down
+    cond_worker: Condvar,
+    // Signalled when the last outstanding job is done
+    cond_master: Condvar,
+}
+
+pub struct WorkQueue {
+    shared: Arc<Shared>,
+    workers: Vec<JoinHandle<()>>,
+}
+
+impl WorkQueue {
+    pub fn new(n_threads: usize) -> Self {
+        let shared = Arc::new(Shared {
+            state: Mutex::new(QueueState {
+                jobs: VecDeque::new(),
+                n_todo: 0,
+                f_all_ok: true,
+                f_quit: false,
+            }),
+            cond_worker: Condvar::new(),
+            cond_master: Condvar::new(),
+        });
+        let workers = (0..n_threads.max(1))
+            .map(|i| {
+                let shared = shared.clone();
+                thread::Builder::new()
+                    .name(format!("verify-{}", i))
+                    .spawn(move || worker_loop(&shared))
+                    .expect("should be able to spawn a verification thread")
+            })
+            .collect();
+        WorkQueue { shared, workers }
+    }
+
+    pub fn n_threads(&self) -> usize {
+        self.workers.len()
+    }
+
+    pub fn add(&self, jobs: Vec<Job>) {
+        if jobs.is_empty() {
+            return;
+        }
+        let mut state = self.shared.state.lock().unwrap();
+        state.n_todo += jobs.len();
+        state.jobs.extend(jobs);
+        self.shared.cond_worker.notify_all();
+    }
+
+    //Blocks until every added job is done and returns whether all of them succeeded. The
+    //queue is ready for the next batch afterwards.
+    pub fn wait(&self) -> bool {
+        let mut state = self.shared.state.lock().unwrap();
+        while state.n_todo > 0 {
+            state = self.shared.cond_master.wait(state).unwrap();
+        }
+        let f_all_ok = state.f_all_ok;
+        state.f_all_ok = true;
+        f_all_ok
+    }
+}
+
+impl Drop for WorkQueue {
+    fn drop(&mut self) {
+        self.shared.state.lock().unwrap().f_quit = true;
+        self.shared.cond_worker.notify_all();
+        for worker in self.workers.drain(..) {
+            let _ = worker.join();
+        }
+    }
+}
+
+fn worker_loop(shared: &Shared) {
+    let mut state = shared.state.lock().unwrap();
+    loop {
+        let job = match state.jobs.pop_front() {
+            Some(job) => job,
+            None if state.f_quit => return,
+            None => {
+                state = shared.cond_worker.wait(state).unwrap();
+                continue;
+            }
+        };
+
+        // No need to run the job once the batch has failed
+        let f_ok = if state.f_all_ok {
+            drop(state);
+            // A panicking job counts as a failed one, so the master isn't left waiting for it
+            let f_ok = panic::catch_unwind(AssertUnwindSafe(job)).unwrap_or(false);
+            state = shared.state.lock().unwrap();
+            f_ok
+        } else {
+            false
+        };
+
+        state.f_all_ok &= f_ok;
+        state.n_todo -= 1;
+        if state.n_todo == 0 {
+            shared.cond_master.notify_all();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    #[test]
+    fn wait_reports_failed_jobs() {
+        let queue = WorkQueue::new(4);
+        let counter = Arc::new(AtomicUsize::new(0));
+        let jobs = |n: usize, f_ok: bool| -> Vec<Job> {
+            (0..n)
+                .map(|_| {
+                    let counter = counter.clone();
+                    Box::new(move || {
+                        counter.fetch_add(1, Ordering::SeqCst);
+                        f_ok
+                    }) as Job
+                })
+                .collect()
+        };
+
+        queue.add(jobs(100, true));
+        assert!(queue.wait());
+        assert_eq!(counter.load(Ordering::SeqCst), 100);
+
+        queue.add(jobs(10, true));
+        queue.add(jobs(1, false));
+        assert!(!queue.wait());
+
+        // A failed batch doesn't affect the next one
+        assert!(queue.wait());
+        queue.add(jobs(5, true));
+        assert!(queue.wait());
+    }
+
+    #[test]
+    fn panicking_job_fails_the_batch() {
+        let queue = WorkQueue::new(1);
+        queue.add(vec![
+            Box::new(|| true) as Job,
+            Box::new(|| panic!("job panicked")) as Job,
+        ]);
+        assert!(!queue.wait());
+
+        // The only worker survives the panic and runs the next batch
+        queue.add((0..4).map(|_| Box::new(|| true) as Job).collect());
+        assert!(queue.wait());
+    }
+}
@@ -16,14 +16,20 @@ const SPEND_PARAM_HASH : &str = "8270785a1a0d0bc77196f000ee6d221c9c9894f55307bd9
 const OUTPUT_PARAM_PATH: &str = "res/sapling-output.params";
 const OUTPUT_PARAM_HASH : &str = "657e3d38dbb5cb5e7dd2970e8b03d69b4787dd907285b5a7f0790dcc8072f60bf593b32cc2d1c030e00ff5ae64bf84c5c3beb84ddc841d48264b4a171744d028";
 
+pub type SaplingVerifyingKey = BellmanVerifyingKey<Bls12>;
 pub type SaplingPreparedVerifyingKey = PreparedVerifyingKey<Bls12>;
 pub type SaplingParam = Parameters<Bls12>;
 
 lazy_static! {
-    pub static ref SPEND_VK: SaplingPreparedVerifyingKey =
+    // The batch verifier needs the keys before preparation
+    pub static ref SPEND_VERIFYING_KEY: SaplingVerifyingKey =
         { load_sapling_spend_verifying_key().unwrap() };
-    pub static ref OUTPUT_VK: SaplingPreparedVerifyingKey =
+    pub static ref OUTPUT_VERIFYING_KEY: SaplingVerifyingKey =
         { load_sapling_output_verifying_key().unwrap() };
+    pub static ref SPEND_VK: SaplingPreparedVerifyingKey =
+        { prepare_verifying_key(&SPEND_VERIFYING_KEY) };
+    pub static ref OUTPUT_VK: SaplingPreparedVerifyingKey =
+        { prepare_verifying_key(&OUTPUT_VERIFYING_KEY) };
     pub static ref SPEND_PARAM: SaplingParam =
         { load_single_parameters(Path::new(SPEND_PARAM_PATH), SPEND_PARAM_HASH).0 };
     pub static ref OUTPUT_PARAM: SaplingParam =
@@ -116,16 +122,16 @@ impl From<VerifyingKey> for BellmanVerifyingKey<Bls12> {
     }
 }
 
-pub fn load_sapling_spend_verifying_key() -> Result<SaplingPreparedVerifyingKey, String> {
+pub fn load_sapling_spend_verifying_key() -> Result<SaplingVerifyingKey, String> {
     let spend_vk_json = include_bytes!("../res/sapling-spend-verifying-key.json");
     let spend_vk = serde_json::from_slice::<VerifyingKey>(&spend_vk_json[..]).unwrap();
-    Ok(prepare_verifying_key(&spend_vk.into()))
+    Ok(spend_vk.into())
 }
 
-pub fn load_sapling_output_verifying_key() -> Result<SaplingPreparedVerifyingKey, String> {
+pub fn load_sapling_output_verifying_key() -> Result<SaplingVerifyingKey, String> {
     let output_vk_json = include_bytes!("../res/sapling-output-verifying-key.json");
     let output_vk = serde_json::from_slice::<VerifyingKey>(&output_vk_json[..]).unwrap();
-    Ok(prepare_verifying_key(&output_vk.into()))
+    Ok(output_vk.into())
 }
 
 pub fn load_sapling_spend_param() {}